@@ -0,0 +1,482 @@
+//! Statistics computed from the review log, similar to Anki's Stats screen.
+//!
+//! Each function over the review log covers the last `days` days
+//! (including today) and returns one [`Series`] per group, whose points are
+//! ordered by day. Days are relative to today, so `0` is today and `-1` is
+//! yesterday. Days without any matching reviews are omitted.
+//!
+//! ```rust,no_run
+//! use ankidb::{Database, analytics::{self, GroupBy}};
+//!
+//! let db = Database::open(&"/path/to/collection.anki2")?;
+//! for series in analytics::reviews_per_day(&db, 30, GroupBy::Deck)? {
+//!     let total: u32 = series.points.iter().map(|p| p.total()).sum();
+//!     println!("{:?}: {total} reviews", series.group);
+//! }
+//! # Ok::<(), rusqlite::Error>(())
+//! ```
+
+use crate::{
+    Database,
+    model::{DeckId, NotetypeId},
+};
+use rusqlite::{Result, Row, params};
+use std::collections::BTreeMap;
+
+/// The interval, in days, at which a card is considered mature.
+pub const MATURE_IVL: i64 = 21;
+
+/// How to split up statistics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GroupBy {
+    Collection,
+    /// By each card's home deck, so that reviews in filtered decks count
+    /// towards the deck the card came from.
+    Deck,
+    Notetype,
+}
+
+#[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Group {
+    Collection,
+    Deck(DeckId),
+    Notetype(NotetypeId),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Series<T> {
+    pub group: Group,
+    pub points: Vec<T>,
+}
+
+/// Reviews on a day, split by `Revlog::Type`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Reviews {
+    pub day: i32,
+    pub learning: u32,
+    pub review: u32,
+    pub relearning: u32,
+    pub filtered: u32,
+}
+
+impl Reviews {
+    #[must_use]
+    pub const fn total(&self) -> u32 {
+        self.learning + self.review + self.relearning + self.filtered
+    }
+}
+
+/// Pass/fail counts for review cards on a day, split into young and mature
+/// by the interval they had before being answered (`Revlog::Lastivl`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Retention {
+    pub day: i32,
+    pub young_passed: u32,
+    pub young_failed: u32,
+    pub mature_passed: u32,
+    pub mature_failed: u32,
+}
+
+fn ratio(passed: u32, failed: u32) -> Option<f64> {
+    let total = passed + failed;
+    (total > 0).then(|| f64::from(passed) / f64::from(total))
+}
+
+impl Retention {
+    /// The fraction of young cards that were passed, if any were reviewed.
+    #[must_use]
+    pub fn young(&self) -> Option<f64> {
+        ratio(self.young_passed, self.young_failed)
+    }
+
+    /// The fraction of mature cards that were passed, if any were reviewed.
+    #[must_use]
+    pub fn mature(&self) -> Option<f64> {
+        ratio(self.mature_passed, self.mature_failed)
+    }
+
+    /// The fraction of all cards that were passed, if any were reviewed.
+    #[must_use]
+    pub fn total(&self) -> Option<f64> {
+        ratio(
+            self.young_passed + self.mature_passed,
+            self.young_failed + self.mature_failed,
+        )
+    }
+}
+
+/// Time spent answering cards on a day, from `Revlog::Time`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AnswerTime {
+    pub day: i32,
+    pub reviews: u32,
+    pub total_millis: u64,
+}
+
+impl AnswerTime {
+    /// The average time per answer, in seconds.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn average_secs(&self) -> Option<f64> {
+        (self.reviews > 0).then(|| self.total_millis as f64 / 1000.0 / f64::from(self.reviews))
+    }
+}
+
+/// The number of cards studied for the first time on a day.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NewCards {
+    pub day: i32,
+    pub count: u32,
+}
+
+fn group(group_by: GroupBy, id: i64) -> Group {
+    match group_by {
+        GroupBy::Collection => Group::Collection,
+        GroupBy::Deck => Group::Deck(id.into()),
+        GroupBy::Notetype => Group::Notetype(id.into()),
+    }
+}
+
+/// Cards due on a day, split by `Cards::Queue`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Due {
+    pub day: i32,
+    /// Cards in the intraday (1) and interday (3) learning queues.
+    pub learning: u32,
+    pub review: u32,
+}
+
+impl Due {
+    #[must_use]
+    pub const fn total(&self) -> u32 {
+        self.learning + self.review
+    }
+}
+
+const fn group_column(group_by: GroupBy) -> &'static str {
+    match group_by {
+        GroupBy::Collection => "0",
+        GroupBy::Deck => "CASE WHEN c.odid != 0 THEN c.odid ELSE c.did END",
+        GroupBy::Notetype => "n.mid",
+    }
+}
+
+const fn group_join(group_by: GroupBy) -> &'static str {
+    match group_by {
+        GroupBy::Collection => "",
+        GroupBy::Deck => "JOIN cards c ON c.id = r.cid",
+        GroupBy::Notetype => "JOIN cards c ON c.id = r.cid JOIN notes n ON n.id = c.nid",
+    }
+}
+
+/// Runs a query whose first two columns are the group and the number of days
+/// ago, collecting the rows into series. `$group` and `$join` in the SQL
+/// are replaced with the grouping, and the query is bound with the end of
+/// today (in milliseconds) and the cutoff to start from.
+fn series<T>(
+    db: &Database,
+    sql: &str,
+    days: u32,
+    group_by: GroupBy,
+    point: impl Fn(i32, &Row) -> Result<T>,
+) -> Result<Vec<Series<T>>> {
+    let timing = db.timing()?;
+    let next_day_at = timing.next_day_at * 1000;
+    let cutoff = (timing.next_day_at - i64::from(days) * 86_400) * 1000;
+
+    let sql = sql
+        .replace("$group", group_column(group_by))
+        .replace("$join", group_join(group_by));
+    let mut stmt = db.prepare_raw(&sql)?;
+    let mut rows = stmt.query(params![next_day_at, cutoff])?;
+
+    let mut out: Vec<Series<T>> = Vec::new();
+    while let Some(row) = rows.next()? {
+        let group = group(group_by, row.get(0)?);
+        let days_ago: i32 = row.get(1)?;
+        let point = point(-days_ago, row)?;
+        match out.last_mut() {
+            Some(series) if series.group == group => series.points.push(point),
+            _ => out.push(Series {
+                group,
+                points: vec![point],
+            }),
+        }
+    }
+    Ok(out)
+}
+
+/// Counts reviews per day. Manual rescheduling isn't counted.
+///
+/// # Errors
+///
+/// This can fail if the database becomes unavailable.
+pub fn reviews_per_day(
+    db: &Database,
+    days: u32,
+    group_by: GroupBy,
+) -> Result<Vec<Series<Reviews>>> {
+    series(
+        db,
+        "SELECT $group, (?1 - r.id - 1) / 86400000 AS days_ago,
+                SUM(r.type = 0), SUM(r.type = 1), SUM(r.type = 2), SUM(r.type = 3)
+         FROM revlog r $join
+         WHERE r.id >= ?2 AND r.type < 4 AND r.ease != 0
+         GROUP BY 1, 2
+         ORDER BY 1, 2 DESC",
+        days,
+        group_by,
+        |day, row| {
+            Ok(Reviews {
+                day,
+                learning: row.get(2)?,
+                review: row.get(3)?,
+                relearning: row.get(4)?,
+                filtered: row.get(5)?,
+            })
+        },
+    )
+}
+
+/// Computes the true retention of review cards per day, where any answer
+/// other than Again is a pass.
+///
+/// # Errors
+///
+/// This can fail if the database becomes unavailable.
+pub fn retention(db: &Database, days: u32, group_by: GroupBy) -> Result<Vec<Series<Retention>>> {
+    series(
+        db,
+        &format!(
+            "SELECT $group, (?1 - r.id - 1) / 86400000 AS days_ago,
+                    SUM(r.lastivl < {MATURE_IVL} AND r.ease > 1),
+                    SUM(r.lastivl < {MATURE_IVL} AND r.ease = 1),
+                    SUM(r.lastivl >= {MATURE_IVL} AND r.ease > 1),
+                    SUM(r.lastivl >= {MATURE_IVL} AND r.ease = 1)
+             FROM revlog r $join
+             WHERE r.id >= ?2 AND r.type = 1
+             GROUP BY 1, 2
+             ORDER BY 1, 2 DESC"
+        ),
+        days,
+        group_by,
+        |day, row| {
+            Ok(Retention {
+                day,
+                young_passed: row.get(2)?,
+                young_failed: row.get(3)?,
+                mature_passed: row.get(4)?,
+                mature_failed: row.get(5)?,
+            })
+        },
+    )
+}
+
+/// Sums the time spent answering cards per day.
+///
+/// # Errors
+///
+/// This can fail if the database becomes unavailable.
+pub fn answer_time(db: &Database, days: u32, group_by: GroupBy) -> Result<Vec<Series<AnswerTime>>> {
+    series(
+        db,
+        "SELECT $group, (?1 - r.id - 1) / 86400000 AS days_ago, COUNT(*), SUM(r.time)
+         FROM revlog r $join
+         WHERE r.id >= ?2 AND r.type < 4 AND r.ease != 0
+         GROUP BY 1, 2
+         ORDER BY 1, 2 DESC",
+        days,
+        group_by,
+        |day, row| {
+            Ok(AnswerTime {
+                day,
+                reviews: row.get(2)?,
+                total_millis: row.get(3)?,
+            })
+        },
+    )
+}
+
+/// Counts the cards that were studied for the first time per day.
+///
+/// # Errors
+///
+/// This can fail if the database becomes unavailable.
+pub fn new_cards_per_day(
+    db: &Database,
+    days: u32,
+    group_by: GroupBy,
+) -> Result<Vec<Series<NewCards>>> {
+    series(
+        db,
+        "SELECT $group, (?1 - r.id - 1) / 86400000 AS days_ago, COUNT(*)
+         FROM (
+             SELECT cid, MIN(id) AS id FROM revlog
+             WHERE type = 0 AND ease != 0
+             GROUP BY cid
+         ) r $join
+         WHERE r.id >= ?2
+         GROUP BY 1, 2
+         ORDER BY 1, 2 DESC",
+        days,
+        group_by,
+        |day, row| {
+            Ok(NewCards {
+                day,
+                count: row.get(2)?,
+            })
+        },
+    )
+}
+
+/// Joins for grouping a query over `cards c`.
+const fn card_group_join(group_by: GroupBy) -> &'static str {
+    match group_by {
+        GroupBy::Collection | GroupBy::Deck => "",
+        GroupBy::Notetype => "JOIN notes n ON n.id = c.nid",
+    }
+}
+
+/// Forecasts how many cards will be due on each of the next `days` days,
+/// like the Future Due graph.
+///
+/// Overdue cards are included on the (negative) day they were due, so
+/// they can be treated as a backlog or added to today as needed. Cards in
+/// filtered decks are counted by when they'd be due in their home deck.
+/// Like Anki, only new and suspended cards are left out; buried cards are
+/// counted as the learning or review cards they are.
+///
+/// ```rust,no_run
+/// use ankidb::{Database, analytics::{self, GroupBy}};
+///
+/// let db = Database::open(&"/path/to/collection.anki2")?;
+/// for series in analytics::future_due(&db, 7, GroupBy::Deck)? {
+///     let upcoming = series.points.iter().filter(|p| p.day >= 0);
+///     println!("{:?}: {}", series.group, upcoming.map(|p| p.total()).sum::<u32>());
+/// }
+/// # Ok::<(), rusqlite::Error>(())
+/// ```
+///
+/// # Errors
+///
+/// This can fail if the database becomes unavailable.
+pub fn future_due(db: &Database, days: u32, group_by: GroupBy) -> Result<Vec<Series<Due>>> {
+    let timing = db.timing()?;
+    let days_elapsed = i64::from(timing.days_elapsed);
+
+    let sql = format!(
+        "SELECT {},
+                CASE WHEN c.queue < 0 THEN c.type ELSE c.queue END,
+                CASE WHEN c.odid != 0 AND c.odue != 0 THEN c.odue ELSE c.due END
+         FROM cards c {}
+         WHERE c.queue IN (1, 2, 3) OR (c.queue IN (-2, -3) AND c.type != 0)",
+        group_column(group_by),
+        card_group_join(group_by),
+    );
+    let mut stmt = db.prepare_raw(&sql)?;
+    let mut rows = stmt.query([])?;
+
+    let mut buckets: BTreeMap<(Group, i32), Due> = BTreeMap::new();
+    while let Some(row) = rows.next()? {
+        // The queue, or for buried cards the one they'll go back to
+        let queue: i64 = row.get(1)?;
+        let due: i64 = row.get(2)?;
+
+        // Intraday learning cards are due at a timestamp, the rest on a
+        // day. Buried learning cards can be either, so go by the number.
+        let day = if queue != 2 && due > 1_000_000_000 {
+            timing.relative_day(due)
+        } else {
+            i32::try_from(due - days_elapsed).unwrap_or(i32::MAX)
+        };
+        if i64::from(day) >= i64::from(days) {
+            continue;
+        }
+
+        let point = buckets
+            .entry((group(group_by, row.get(0)?), day))
+            .or_insert_with(|| Due {
+                day,
+                ..Due::default()
+            });
+        if queue == 2 {
+            point.review += 1;
+        } else {
+            point.learning += 1;
+        }
+    }
+
+    let mut out: Vec<Series<Due>> = Vec::new();
+    for ((group, _), point) in buckets {
+        match out.last_mut() {
+            Some(series) if series.group == group => series.points.push(point),
+            _ => out.push(Series {
+                group,
+                points: vec![point],
+            }),
+        }
+    }
+    Ok(out)
+}
+
+/// Estimates how many reviews per day it takes to keep up with the current
+/// review cards, as the sum of `1 / Cards::Ivl`. Anki shows this as the
+/// daily load under the Future Due graph.
+///
+/// # Errors
+///
+/// This can fail if the database becomes unavailable.
+pub fn daily_load(db: &Database, group_by: GroupBy) -> Result<Vec<(Group, f64)>> {
+    let sql = format!(
+        "SELECT {}, SUM(1.0 / MAX(c.ivl, 1))
+         FROM cards c {}
+         WHERE c.type IN (2, 3) AND c.queue >= 0
+         GROUP BY 1
+         ORDER BY 1",
+        group_column(group_by),
+        card_group_join(group_by),
+    );
+    let mut stmt = db.prepare_raw(&sql)?;
+    let res = stmt.query_map([], |row| Ok((group(group_by, row.get(0)?), row.get(1)?)))?;
+    res.collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{model::Ease, testing::Fixture};
+
+    #[test]
+    fn buried_cards_are_due_but_suspended_and_new_ones_are_not() -> Result<()> {
+        let db = Fixture::new()
+            .with_note("Default", "Basic", &["猫", "cat"], &[])
+            .with_reviews(&[(3, Ease::Good)])
+            .with_note("Default", "Basic", &["犬", "dog"], &[])
+            .with_reviews(&[(3, Ease::Good)])
+            .with_note("Default", "Basic", &["本", "book"], &[])
+            .build()?;
+        // Bury cat, suspend dog and bury the new book card
+        let mut set_queue = db.prepare_raw(
+            "UPDATE cards SET queue = ? WHERE nid = (SELECT id FROM notes WHERE sfld = ?)",
+        )?;
+        set_queue.execute(params![-3, "猫"])?;
+        set_queue.execute(params![-1, "犬"])?;
+        set_queue.execute(params![-2, "本"])?;
+
+        let series = future_due(&db, 30, GroupBy::Collection)?;
+        let points: Vec<_> = series.iter().flat_map(|s| &s.points).collect();
+        assert_eq!(points.len(), 1);
+        assert_eq!(
+            (points[0].day, points[0].learning, points[0].review),
+            (-2, 0, 1)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,469 @@
+//! Finding and repairing inconsistencies, like Anki's Check Database.
+//!
+//! This follows the parts of
+//! <https://github.com/ankitects/anki/tree/30ae9f7c5408420c8f347073a9e5e62756a6d7cb/rslib/src/dbcheck.rs>
+//! that scripts editing the database directly tend to break.
+
+use crate::{
+    Database,
+    model::{CardId, GraveKind, NoteId, NotetypeId, NotetypeKind, parse_fields, parse_tags},
+    proto::Message,
+    text::{field_checksum, strip_html_preserving_media_filenames},
+    timing::{now_millis, now_secs},
+};
+use rusqlite::{Result, params, types::Value};
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/// Due numbers past this are treated as corrupt, as Anki does. They usually
+/// come from a timestamp being written where a position or day was meant.
+const MAX_DUE: i64 = 1_000_000;
+
+/// The problems found by [`Database::check`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DatabaseCheck {
+    /// Cards whose note doesn't exist. Repairing deletes them.
+    pub cards_without_notes: Vec<CardId>,
+    /// Notes whose notetype doesn't exist. Repairing deletes them and their
+    /// cards.
+    pub notes_without_notetypes: Vec<NoteId>,
+    /// Notes with more or fewer fields than their notetype. Repairing adds
+    /// empty fields, or joins the extra fields onto the last one with `; `.
+    pub notes_with_wrong_field_count: Vec<NoteId>,
+    /// Notes whose `sfld` or `csum` don't match their fields. Repairing
+    /// recomputes them.
+    pub notes_with_bad_checksums: Vec<NoteId>,
+    /// Cards of normal notetypes whose ordinal has no template. Repairing
+    /// deletes them.
+    pub cards_with_invalid_ordinals: Vec<CardId>,
+    /// Cards whose `due` isn't an integer, or is far too large for a new
+    /// card's position or a review card's day. Repairing moves new cards to
+    /// the end of the new queue, and makes review cards due today.
+    pub cards_with_invalid_due: Vec<CardId>,
+    /// Cards whose deck, or original deck, doesn't exist. Repairing moves
+    /// them to the Default deck, or clears the original deck.
+    pub cards_in_missing_decks: Vec<CardId>,
+    /// Tags that notes use but which aren't in the `tags` table. Repairing
+    /// adds them, along with their parents.
+    pub missing_tags: Vec<String>,
+    /// Whether the problems were repaired.
+    pub repaired: bool,
+}
+
+impl DatabaseCheck {
+    /// Whether no problems were found.
+    #[must_use]
+    pub fn is_ok(&self) -> bool {
+        self.cards_without_notes.is_empty()
+            && self.notes_without_notetypes.is_empty()
+            && self.notes_with_wrong_field_count.is_empty()
+            && self.notes_with_bad_checksums.is_empty()
+            && self.cards_with_invalid_ordinals.is_empty()
+            && self.cards_with_invalid_due.is_empty()
+            && self.cards_in_missing_decks.is_empty()
+            && self.missing_tags.is_empty()
+    }
+}
+
+struct NotetypeInfo {
+    kind: NotetypeKind,
+    fields: usize,
+    templates: i64,
+    sort_field: usize,
+}
+
+/// A note whose fields, `sfld` or `csum` need rewriting.
+struct NoteFix {
+    id: NoteId,
+    fields: String,
+    sort_field: String,
+    checksum: u32,
+}
+
+/// A card whose deck or due needs changing.
+#[derive(Default)]
+struct CardFix {
+    move_to_default: bool,
+    clear_original_deck: bool,
+    due: Option<DueFix>,
+}
+
+enum DueFix {
+    Round,
+    EndOfNewQueue,
+    Today,
+}
+
+/// Pads or joins fields to match the notetype, as Anki does.
+fn fix_field_count(fields: &mut Vec<String>, count: usize) {
+    while fields.len() < count {
+        fields.push(String::new());
+    }
+    while fields.len() > count.max(1) {
+        let last = fields.pop().unwrap_or_default();
+        if let Some(previous) = fields.last_mut() {
+            previous.push_str("; ");
+            previous.push_str(&last);
+        }
+    }
+}
+
+impl Database {
+    /// Checks the collection for the inconsistencies that Anki's Check
+    /// Database looks for, and repairs them if `repair` is set.
+    ///
+    /// Repairs that delete cards or notes record them in the `graves`
+    /// table, so that the next sync deletes them elsewhere too. Changed
+    /// notes and cards are marked as modified.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::Database;
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let check = db.check(false)?;
+    /// if !check.is_ok() {
+    ///     println!("{check:#?}");
+    ///     db.check(true)?;
+    /// }
+    /// # Ok::<(), rusqlite::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the database becomes unavailable. Nothing is
+    /// repaired if it fails.
+    pub fn check(&self, repair: bool) -> Result<DatabaseCheck> {
+        self.transact(|db| {
+            let mut check = DatabaseCheck::default();
+            let notetypes = db.notetype_info()?;
+            let notes = db.check_notes(&notetypes, &mut check)?;
+            let cards = db.check_cards(&notetypes, &mut check)?;
+
+            if repair && !check.is_ok() {
+                db.repair(&check, &notes, &cards)?;
+                check.repaired = true;
+            }
+            Ok(check)
+        })
+    }
+
+    fn notetype_info(&self) -> Result<HashMap<NotetypeId, NotetypeInfo>> {
+        let mut notetypes = HashMap::new();
+        let mut stmt = self.prepare_raw(
+            "SELECT id, config,
+                    (SELECT COUNT(*) FROM fields WHERE ntid = notetypes.id),
+                    (SELECT COUNT(*) FROM templates WHERE ntid = notetypes.id)
+             FROM notetypes",
+        )?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let config: Message = row.get(1)?;
+            notetypes.insert(
+                row.get(0)?,
+                NotetypeInfo {
+                    kind: config.varint(1).into(),
+                    fields: row.get(2)?,
+                    templates: row.get(3)?,
+                    sort_field: usize::try_from(config.uint32(2)).unwrap_or_default(),
+                },
+            );
+        }
+        Ok(notetypes)
+    }
+
+    /// Checks notes' notetypes, fields, checksums and tags, returning the
+    /// notes that need rewriting.
+    fn check_notes(
+        &self,
+        notetypes: &HashMap<NotetypeId, NotetypeInfo>,
+        check: &mut DatabaseCheck,
+    ) -> Result<Vec<NoteFix>> {
+        let mut fixes = Vec::new();
+        let mut tags = BTreeSet::new();
+        // Compared in SQL, so that the sort field gets the column's numeric
+        // affinity just as it did when it was stored
+        let mut matches =
+            self.prepare_raw("SELECT sfld = ? AND csum = ? FROM notes WHERE id = ?")?;
+
+        let mut stmt = self.prepare_raw("SELECT id, mid, flds, tags FROM notes ORDER BY id")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let id: NoteId = row.get(0)?;
+            let Some(notetype) = notetypes.get(&row.get(1)?) else {
+                check.notes_without_notetypes.push(id);
+                continue;
+            };
+            tags.extend(parse_tags(row.get_ref(3)?.as_str()?).map(str::to_string));
+
+            let original = row.get_ref(2)?.as_str()?;
+            let mut fields = parse_fields(original)
+                .map(str::to_string)
+                .collect::<Vec<_>>();
+            if fields.len() != notetype.fields {
+                check.notes_with_wrong_field_count.push(id);
+                fix_field_count(&mut fields, notetype.fields);
+            }
+
+            let sort_field = strip_html_preserving_media_filenames(
+                fields.get(notetype.sort_field).map_or("", String::as_str),
+            );
+            let checksum = field_checksum(&fields[0]);
+            let fields = fields.join("\x1f");
+            let valid: bool =
+                matches.query_row(params![sort_field, checksum, id], |row| row.get(0))?;
+            if !valid {
+                check.notes_with_bad_checksums.push(id);
+            }
+            if !valid || fields != original {
+                fixes.push(NoteFix {
+                    id,
+                    fields,
+                    sort_field,
+                    checksum,
+                });
+            }
+        }
+
+        let mut registered = self.prepare_raw("SELECT 1 FROM tags WHERE tag = ?")?;
+        let mut seen = HashSet::new();
+        for tag in tags {
+            // The tags table is case-insensitive, so only report one spelling
+            if seen.insert(tag.to_lowercase()) && !registered.exists(params![tag])? {
+                check.missing_tags.push(tag);
+            }
+        }
+
+        Ok(fixes)
+    }
+
+    /// Checks cards' notes, ordinals, decks and due numbers, returning the
+    /// cards that need changing.
+    fn check_cards(
+        &self,
+        notetypes: &HashMap<NotetypeId, NotetypeInfo>,
+        check: &mut DatabaseCheck,
+    ) -> Result<Vec<(CardId, CardFix)>> {
+        let decks = self
+            .prepare_raw("SELECT id FROM decks")?
+            .query_map([], |row| row.get::<_, i64>(0))?
+            .collect::<Result<HashSet<_>>>()?;
+
+        let mut fixes = Vec::new();
+        let mut stmt = self.prepare_raw(
+            "SELECT cards.id, notes.mid, cards.did, cards.odid, cards.ord, cards.type, cards.queue,
+                    cards.due
+             FROM cards LEFT JOIN notes ON notes.id = cards.nid
+             ORDER BY cards.id",
+        )?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let id: CardId = row.get(0)?;
+            let Some(notetype) = row.get::<_, Option<NotetypeId>>(1)? else {
+                check.cards_without_notes.push(id);
+                continue;
+            };
+            // Deleted along with their notes
+            let Some(notetype) = notetypes.get(&notetype) else {
+                continue;
+            };
+            let ord: i64 = row.get(4)?;
+            if notetype.kind == NotetypeKind::Normal && ord >= notetype.templates {
+                check.cards_with_invalid_ordinals.push(id);
+                continue;
+            }
+
+            let mut fix = CardFix::default();
+            let (deck, original_deck): (i64, i64) = (row.get(2)?, row.get(3)?);
+            fix.move_to_default = !decks.contains(&deck);
+            fix.clear_original_deck = original_deck != 0 && !decks.contains(&original_deck);
+            if fix.move_to_default || fix.clear_original_deck {
+                check.cards_in_missing_decks.push(id);
+            }
+
+            let (kind, queue): (i64, i64) = (row.get(5)?, row.get(6)?);
+            fix.due = match row.get::<_, Value>(7)? {
+                Value::Integer(_) if original_deck != 0 => None,
+                Value::Integer(due) if kind == 0 && due > MAX_DUE => Some(DueFix::EndOfNewQueue),
+                Value::Integer(due) if kind == 2 && queue != 1 && due > MAX_DUE => {
+                    Some(DueFix::Today)
+                }
+                Value::Integer(_) => None,
+                _ => Some(DueFix::Round),
+            };
+            if fix.due.is_some() {
+                check.cards_with_invalid_due.push(id);
+            }
+
+            if fix.move_to_default || fix.clear_original_deck || fix.due.is_some() {
+                fixes.push((id, fix));
+            }
+        }
+        Ok(fixes)
+    }
+
+    fn repair(
+        &self,
+        check: &DatabaseCheck,
+        notes: &[NoteFix],
+        cards: &[(CardId, CardFix)],
+    ) -> Result<()> {
+        let now = now_secs();
+
+        for &note in &check.notes_without_notetypes {
+            let cards = self
+                .prepare_cached_raw("SELECT id FROM cards WHERE nid = ?")?
+                .query_map(params![note], |row| row.get::<_, CardId>(0))?
+                .collect::<Result<Vec<_>>>()?;
+            for card in cards {
+                self.delete_card(card)?;
+            }
+            self.prepare_cached_raw("DELETE FROM notes WHERE id = ?")?
+                .execute(params![note])?;
+            self.add_grave(note.into(), GraveKind::Note)?;
+        }
+        for &card in check
+            .cards_without_notes
+            .iter()
+            .chain(&check.cards_with_invalid_ordinals)
+        {
+            self.delete_card(card)?;
+        }
+
+        let mut update = self.prepare_raw(
+            "UPDATE notes SET flds = ?, sfld = ?, csum = ?, mod = ?, usn = -1 WHERE id = ?",
+        )?;
+        for note in notes {
+            update.execute(params![
+                note.fields,
+                note.sort_field,
+                note.checksum,
+                now,
+                note.id
+            ])?;
+        }
+
+        let today = i64::from(self.timing()?.days_elapsed);
+        let mut next_position: i64 = self
+            .prepare_raw("SELECT COALESCE(MAX(due), 0) + 1 FROM cards WHERE type = 0 AND due <= ?")?
+            .query_row(params![MAX_DUE], |row| row.get(0))?;
+        for (card, fix) in cards {
+            if fix.move_to_default {
+                self.prepare_cached_raw("UPDATE cards SET did = 1 WHERE id = ?")?
+                    .execute(params![card])?;
+            }
+            if fix.clear_original_deck {
+                self.prepare_cached_raw("UPDATE cards SET odid = 0, odue = 0 WHERE id = ?")?
+                    .execute(params![card])?;
+            }
+            match fix.due {
+                Some(DueFix::Round) => {
+                    self.prepare_cached_raw(
+                        "UPDATE cards SET due = CAST(ROUND(due) AS INTEGER) WHERE id = ?",
+                    )?
+                    .execute(params![card])?;
+                }
+                Some(DueFix::EndOfNewQueue) => {
+                    self.prepare_cached_raw("UPDATE cards SET due = ? WHERE id = ?")?
+                        .execute(params![next_position, card])?;
+                    next_position += 1;
+                }
+                Some(DueFix::Today) => {
+                    self.prepare_cached_raw("UPDATE cards SET due = ? WHERE id = ?")?
+                        .execute(params![today, card])?;
+                }
+                None => {}
+            }
+            self.prepare_cached_raw("UPDATE cards SET mod = ?, usn = -1 WHERE id = ?")?
+                .execute(params![now, card])?;
+        }
+
+        let mut register =
+            self.prepare_raw("INSERT OR IGNORE INTO tags VALUES (?, -1, 0, NULL)")?;
+        for tag in &check.missing_tags {
+            for (end, _) in tag.match_indices("::").chain([(tag.len(), "")]) {
+                register.execute(params![&tag[..end]])?;
+            }
+        }
+
+        self.prepare_raw("UPDATE col SET mod = ?")?
+            .execute(params![now_millis()])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Fixture;
+
+    #[test]
+    fn repairs_field_counts_due_numbers_and_orphaned_cards() -> Result<()> {
+        let db = Fixture::new()
+            .with_note("Default", "Basic", &["猫", "cat"], &[])
+            .with_note("Default", "Basic", &["犬", "dog"], &[])
+            .with_note("Default", "Basic", &["本", "book"], &[])
+            .build()?;
+        let ids = db
+            .prepare_raw(
+                "SELECT n.id, c.id FROM notes n JOIN cards c ON c.nid = n.id ORDER BY n.id",
+            )?
+            .query_map([], |row| {
+                Ok((row.get::<_, NoteId>(0)?, row.get::<_, CardId>(1)?))
+            })?
+            .collect::<Result<Vec<_>>>()?;
+        let [(cat, _), (dog, _), (_, book)] = ids[..] else {
+            panic!("expected three notes");
+        };
+        let orphan = CardId::from(i64::from(book) + 1000);
+
+        let mut set_fields = db.prepare_raw("UPDATE notes SET flds = ? WHERE id = ?")?;
+        set_fields.execute(params!["猫", cat])?;
+        set_fields.execute(params!["犬\x1fdog\x1fextra", dog])?;
+        db.prepare_raw("UPDATE cards SET due = 1700000000 WHERE id = ?")?
+            .execute(params![book])?;
+        db.prepare_raw(
+            "INSERT INTO cards
+             SELECT ?, 424242, did, ord, mod, usn, type, queue, due, ivl, factor, reps, lapses,
+                    left, odue, odid, flags, data
+             FROM cards WHERE id = ?",
+        )?
+        .execute(params![orphan, book])?;
+
+        let expected = DatabaseCheck {
+            cards_without_notes: vec![orphan],
+            notes_with_wrong_field_count: vec![cat, dog],
+            cards_with_invalid_due: vec![book],
+            ..DatabaseCheck::default()
+        };
+        assert_eq!(db.check(false)?, expected);
+        assert_eq!(
+            db.check(true)?,
+            DatabaseCheck {
+                repaired: true,
+                ..expected
+            }
+        );
+        assert!(db.check(false)?.is_ok());
+
+        let fields = |id: NoteId| -> Result<String> {
+            db.prepare_raw("SELECT flds FROM notes WHERE id = ?")?
+                .query_row(params![id], |row| row.get(0))
+        };
+        assert_eq!(fields(cat)?, "猫\x1f");
+        assert_eq!(fields(dog)?, "犬\x1fdog; extra");
+        let (due, last): (i64, i64) = db
+            .prepare_raw(
+                "SELECT due, (SELECT MAX(due) FROM cards WHERE id != ?1) FROM cards WHERE id = ?1",
+            )?
+            .query_row(params![book], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        assert_eq!(due, last + 1);
+        let graves: i64 = db
+            .prepare_raw("SELECT COUNT(*) FROM graves WHERE oid = ? AND type = 0")?
+            .query_row(params![orphan], |row| row.get(0))?;
+        assert_eq!(graves, 1);
+        let orphans: i64 = db
+            .prepare_raw("SELECT COUNT(*) FROM cards WHERE id = ?")?
+            .query_row(params![orphan], |row| row.get(0))?;
+        assert_eq!(orphans, 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,264 @@
+//! Parsing and rendering of Anki's cloze deletions, like
+//! `{{c1::answer::hint}}`.
+//!
+//! Clozes may be nested (`{{c1::outer {{c2::inner}}}}`) and may belong to
+//! several cards at once (`{{c1,2::shared}}`).
+//!
+//! ```rust
+//! use ankidb::cloze;
+//!
+//! let text = "{{c1::Canberra::city}} is the capital of {{c2::Australia}}";
+//! assert_eq!(cloze::card_ords([text]).into_iter().collect::<Vec<_>>(), [0, 1]);
+//! assert_eq!(
+//!     cloze::render_question(text, 0),
+//!     r#"<span class="cloze" data-cloze="Canberra" data-ordinal="1">[city]</span> is the capital of <span class="cloze-inactive" data-ordinal="2">Australia</span>"#,
+//! );
+//! ```
+
+use crate::text::encode_attribute;
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+/// A parsed piece of cloze text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node<'a> {
+    Text(&'a str),
+    Cloze(Cloze<'a>),
+}
+
+/// A single cloze deletion.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cloze<'a> {
+    /// The 1-based cloze numbers this deletion belongs to, e.g. `[1]` for `{{c1::...}}`.
+    pub ordinals: Vec<u16>,
+    pub nodes: Vec<Node<'a>>,
+    pub hint: Option<&'a str>,
+}
+
+enum Token<'a> {
+    Open(&'a str, Vec<u16>),
+    Close(&'a str),
+    Text(&'a str),
+}
+
+/// Tries to read a `{{c1::` or `{{c1,2::` opener at the start of `s`,
+/// returning its length and ordinals.
+fn parse_open(s: &str) -> Option<(usize, Vec<u16>)> {
+    let rest = s.strip_prefix("{{c")?;
+    let end = rest.find("::")?;
+    let ordinals = rest[..end]
+        .split(',')
+        .map(|n| {
+            if n.is_empty() || !n.bytes().all(|b| b.is_ascii_digit()) {
+                None
+            } else {
+                n.parse().ok()
+            }
+        })
+        .collect::<Option<Vec<u16>>>()?;
+    Some((3 + end + 2, ordinals))
+}
+
+fn tokenize(text: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    let mut pos = 0;
+    while pos < text.len() {
+        let rest = &text[pos..];
+        let token = if rest.starts_with("}}") {
+            Some((2, Token::Close(&rest[..2])))
+        } else if rest.starts_with("{{c") {
+            parse_open(rest).map(|(len, ords)| (len, Token::Open(&rest[..len], ords)))
+        } else {
+            None
+        };
+
+        if let Some((len, token)) = token {
+            if start < pos {
+                tokens.push(Token::Text(&text[start..pos]));
+            }
+            tokens.push(token);
+            pos += len;
+            start = pos;
+        } else {
+            pos += rest.chars().next().map_or(1, char::len_utf8);
+        }
+    }
+    if start < text.len() {
+        tokens.push(Token::Text(&text[start..]));
+    }
+    tokens
+}
+
+/// Parses text into a tree of text and cloze nodes. Unbalanced markers are
+/// treated as plain text, as Anki does.
+#[must_use]
+pub fn parse(text: &str) -> Vec<Node<'_>> {
+    let mut stack: Vec<(&str, Cloze)> = Vec::new();
+    let mut root = Vec::new();
+
+    for token in tokenize(text) {
+        match token {
+            Token::Open(raw, ordinals) => stack.push((
+                raw,
+                Cloze {
+                    ordinals,
+                    nodes: Vec::new(),
+                    hint: None,
+                },
+            )),
+            Token::Close(raw) => {
+                if let Some((_, mut cloze)) = stack.pop() {
+                    if let Some(Node::Text(last)) = cloze.nodes.last_mut() {
+                        if let Some((text, hint)) = last.split_once("::") {
+                            *last = text;
+                            cloze.hint = (!hint.is_empty()).then_some(hint);
+                        }
+                    }
+                    let parent = stack.last_mut().map_or(&mut root, |(_, c)| &mut c.nodes);
+                    parent.push(Node::Cloze(cloze));
+                } else {
+                    root.push(Node::Text(raw));
+                }
+            }
+            Token::Text(text) => {
+                let parent = stack.last_mut().map_or(&mut root, |(_, c)| &mut c.nodes);
+                parent.push(Node::Text(text));
+            }
+        }
+    }
+
+    // Any clozes still open were never closed, so flatten them back to text
+    while let Some((raw, cloze)) = stack.pop() {
+        let parent = stack.last_mut().map_or(&mut root, |(_, c)| &mut c.nodes);
+        parent.push(Node::Text(raw));
+        parent.extend(cloze.nodes);
+    }
+
+    root
+}
+
+impl Cloze<'_> {
+    /// The text of this cloze as revealed on the answer side, with any
+    /// nested clozes also revealed.
+    #[must_use]
+    pub fn text(&self) -> String {
+        let mut out = String::new();
+        for node in &self.nodes {
+            match node {
+                Node::Text(t) => out.push_str(t),
+                Node::Cloze(c) => out.push_str(&c.text()),
+            }
+        }
+        out
+    }
+
+    fn ordinals_attr(&self) -> String {
+        self.ordinals
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+fn collect_numbers(nodes: &[Node], out: &mut BTreeSet<u16>) {
+    for node in nodes {
+        if let Node::Cloze(cloze) = node {
+            out.extend(cloze.ordinals.iter().copied());
+            collect_numbers(&cloze.nodes, out);
+        }
+    }
+}
+
+/// Gets the 1-based cloze numbers used in some text, e.g. `{1, 2}` for
+/// `{{c1::a}} {{c2::b}}`.
+#[must_use]
+pub fn cloze_numbers(text: &str) -> BTreeSet<u16> {
+    let mut out = BTreeSet::new();
+    collect_numbers(&parse(text), &mut out);
+    out
+}
+
+/// Gets the card ordinals (`Cards::Ord`) that a cloze note with the given
+/// fields should have. `{{c1::...}}` produces ordinal 0, and so on.
+///
+/// Like Anki, a note without any clozes still produces its first card.
+pub fn card_ords<'a>(fields: impl IntoIterator<Item = &'a str>) -> BTreeSet<u16> {
+    let mut out = fields
+        .into_iter()
+        .flat_map(cloze_numbers)
+        .map(|n| n.saturating_sub(1))
+        .collect::<BTreeSet<_>>();
+    if out.is_empty() {
+        out.insert(0);
+    }
+    out
+}
+
+fn render_nodes(nodes: &[Node], number: u16, question: bool, out: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Text(t) => out.push_str(t),
+            Node::Cloze(cloze) if cloze.ordinals.contains(&number) => {
+                if question {
+                    let _ = write!(
+                        out,
+                        r#"<span class="cloze" data-cloze="{}" data-ordinal="{}">[{}]</span>"#,
+                        encode_attribute(&cloze.text()),
+                        cloze.ordinals_attr(),
+                        cloze.hint.unwrap_or("..."),
+                    );
+                } else {
+                    let _ = write!(
+                        out,
+                        r#"<span class="cloze" data-ordinal="{}">"#,
+                        cloze.ordinals_attr()
+                    );
+                    render_nodes(&cloze.nodes, number, question, out);
+                    out.push_str("</span>");
+                }
+            }
+            Node::Cloze(cloze) => {
+                let _ = write!(
+                    out,
+                    r#"<span class="cloze-inactive" data-ordinal="{}">"#,
+                    cloze.ordinals_attr()
+                );
+                render_nodes(&cloze.nodes, number, question, out);
+                out.push_str("</span>");
+            }
+        }
+    }
+}
+
+/// Renders the question side of the card with the given ordinal
+/// (`Cards::Ord`), hiding that card's clozes behind their hints.
+#[must_use]
+pub fn render_question(text: &str, ord: u16) -> String {
+    let mut out = String::new();
+    render_nodes(&parse(text), ord.saturating_add(1), true, &mut out);
+    out
+}
+
+/// Renders the answer side of the card with the given ordinal
+/// (`Cards::Ord`), highlighting that card's clozes.
+#[must_use]
+pub fn render_answer(text: &str, ord: u16) -> String {
+    let mut out = String::new();
+    render_nodes(&parse(text), ord.saturating_add(1), false, &mut out);
+    out
+}
+
+/// Replaces every cloze with its answer text, without any markup.
+#[must_use]
+pub fn strip(text: &str) -> String {
+    let mut out = String::new();
+    for node in parse(text) {
+        match node {
+            Node::Text(t) => out.push_str(t),
+            Node::Cloze(c) => out.push_str(&c.text()),
+        }
+    }
+    out
+}
@@ -1,8 +1,14 @@
-use crate::model::{DeckId, NotetypeId};
-use rusqlite::{params, Connection, Result};
+use crate::furigana;
+use crate::model::{
+    CardId, DeckConfigId, DeckId, GraveKind, NotetypeId, NotetypeKind, parse_fields,
+};
+use crate::proto::Message;
+use crate::stock;
+use crate::timing::{SchedTiming, now_millis, now_secs};
+use rusqlite::{Connection, OpenFlags, Result, functions::FunctionFlags, params};
 use sea_query::SqliteQueryBuilder;
 use sea_query_rusqlite::{RusqliteBinder, RusqliteValues};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use unicase::UniCase;
 
 pub struct Database {
@@ -25,12 +31,69 @@ impl Database {
     /// configuration settings on the database handle fails, etc.
     pub fn open<P: AsRef<Path>>(path: &P) -> Result<Self> {
         // Connection::open, but without the CREATE flag
-        let db = Connection::open_with_flags(
+        Self::connect(
             path,
-            rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE
-                | rusqlite::OpenFlags::SQLITE_OPEN_URI
-                | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            OpenFlags::SQLITE_OPEN_READ_WRITE
+                | OpenFlags::SQLITE_OPEN_URI
+                | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )
+    }
+
+    /// Creates a new, empty collection, laid out the way the current version
+    /// of Anki creates them: the Default deck and options preset, and the
+    /// Basic, Basic (and reversed card) and Cloze notetypes.
+    ///
+    /// ```rust,no_run
+    /// use ankidb::Database;
+    ///
+    /// let db = Database::create(&"/path/to/collection.anki2")?;
+    /// let basic = db.id_for_notetype("Basic")?;
+    /// # Ok::<(), rusqlite::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the file can't be created, or if it already holds a
+    /// database with any of the collection's tables.
+    pub fn create<P: AsRef<Path>>(path: &P) -> Result<Self> {
+        let db = Self::connect(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_WRITE
+                | OpenFlags::SQLITE_OPEN_CREATE
+                | OpenFlags::SQLITE_OPEN_URI
+                | OpenFlags::SQLITE_OPEN_NO_MUTEX,
         )?;
+        db.transact(|db| db.initialize(now_secs()))?;
+        Ok(db)
+    }
+
+    /// Creates a new collection in memory, like [`Self::create`]. It has no
+    /// media folder, and is gone once the `Database` is dropped.
+    ///
+    /// # Errors
+    ///
+    /// This can fail if `SQLite` can't allocate the database.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn in_memory() -> Result<Self> {
+        Self::in_memory_at(now_secs())
+    }
+
+    /// Creates a new collection in memory as if it were created at `now`,
+    /// in seconds, so that its timestamps and stock notetype ids are fixed.
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn in_memory_at(now: i64) -> Result<Self> {
+        let db = Self::connect(
+            &":memory:",
+            OpenFlags::SQLITE_OPEN_READ_WRITE
+                | OpenFlags::SQLITE_OPEN_CREATE
+                | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        db.transact(|db| db.initialize(now))?;
+        Ok(db)
+    }
+
+    fn connect<P: AsRef<Path>>(path: &P, flags: OpenFlags) -> Result<Self> {
+        let db = Connection::open_with_flags(path, flags)?;
 
         // This is the same config that Anki uses, though without exclusive locking
         // since this library is meant to coexist with other tools.
@@ -48,9 +111,106 @@ impl Database {
 
         db.create_collation("unicase", |s1, s2| UniCase::new(s1).cmp(&UniCase::new(s2)))?;
 
+        // Used by FieldMatcher::Kana and FieldMatcher::Kanji
+        for (name, f) in [
+            ("kana", furigana::kana as fn(&str) -> String),
+            ("kanji", furigana::kanji),
+        ] {
+            db.create_scalar_function(
+                name,
+                1,
+                FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+                move |ctx| Ok(f(&ctx.get::<String>(0)?)),
+            )?;
+        }
+
+        // Used by field searches in the search module
+        db.create_scalar_function(
+            "field_at_index",
+            2,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+            |ctx| {
+                let fields = ctx.get::<String>(0)?;
+                let index = ctx.get::<usize>(1)?;
+                Ok(parse_fields(&fields)
+                    .nth(index)
+                    .unwrap_or_default()
+                    .to_string())
+            },
+        )?;
+
         Ok(Self { connection: db })
     }
 
+    /// Writes the schema and the stock objects into an empty database, as
+    /// if it were created at `now`, in seconds.
+    fn initialize(&self, now: i64) -> Result<()> {
+        self.create_missing_tables()?;
+
+        let now_ms = now * 1000;
+        // Days start at 4am UTC until Anki first opens the collection, which
+        // updates the offset to the local timezone
+        let crt = SchedTiming::compute(now, Some(0), Some(0), 4, now).day_start();
+        self.connection.execute(
+            "INSERT INTO col VALUES (1, ?, ?, ?, 18, 0, 0, 0, '', '', '', '', '')",
+            params![crt, now_ms, now_ms],
+        )?;
+
+        self.connection.execute(
+            "INSERT INTO deck_config VALUES (1, 'Default', ?, 0, ?)",
+            params![now, stock::deck_config().encode()],
+        )?;
+        self.connection.execute(
+            "INSERT INTO decks VALUES (1, 'Default', ?, 0, ?, ?)",
+            params![
+                now,
+                Message::new().encode(),
+                stock::normal_deck_kind(DeckConfigId::from(1)).encode()
+            ],
+        )?;
+
+        let mut basic = None;
+        for (offset, notetype) in (0..).zip(stock::notetypes()) {
+            let id = now_ms + offset;
+            basic.get_or_insert(id);
+            self.connection.execute(
+                "INSERT INTO notetypes VALUES (?, ?, ?, 0, ?)",
+                params![id, notetype.name, now, notetype.config.encode()],
+            )?;
+            for (ord, (name, config)) in notetype.fields.iter().enumerate() {
+                self.connection.execute(
+                    "INSERT INTO fields VALUES (?, ?, ?, ?)",
+                    params![id, ord, name, config.encode()],
+                )?;
+            }
+            for (ord, (name, config)) in notetype.templates.iter().enumerate() {
+                self.connection.execute(
+                    "INSERT INTO templates VALUES (?, ?, ?, ?, 0, ?)",
+                    params![id, ord, name, now, config.encode()],
+                )?;
+            }
+        }
+
+        let config = [
+            ("activeDecks", "[1]".to_string()),
+            ("creationOffset", "0".to_string()),
+            ("curDeck", "1".to_string()),
+            ("curModel", basic.unwrap_or_default().to_string()),
+            ("nextPos", "1".to_string()),
+            ("sched2021", "true".to_string()),
+            ("schedVer", "2".to_string()),
+            ("sortType", "\"noteFld\"".to_string()),
+        ];
+        for (key, val) in config {
+            self.connection.execute(
+                "INSERT INTO config VALUES (?, 0, ?, ?)",
+                params![key, now, val.into_bytes()],
+            )?;
+        }
+
+        Ok(())
+    }
+
     /// Prepares a seaquery statement to run against the db.
     ///
     /// ```rust,no_run
@@ -143,6 +303,53 @@ impl Database {
         self.connection.prepare_cached(sql)
     }
 
+    /// Creates the tables and indexes of the current schema that the
+    /// database doesn't have yet.
+    pub(crate) fn create_missing_tables(&self) -> Result<()> {
+        self.connection.execute_batch(include_str!("schema.sql"))
+    }
+
+    /// Runs `f` in a transaction, which is committed if it succeeds and
+    /// rolled back otherwise.
+    pub(crate) fn transact<T>(&self, f: impl FnOnce(&Self) -> Result<T>) -> Result<T> {
+        let tx = self.connection.unchecked_transaction()?;
+        let out = f(self)?;
+        tx.commit()?;
+        Ok(out)
+    }
+
+    /// The path of the database file, or `None` for in-memory databases.
+    pub(crate) fn path(&self) -> Option<PathBuf> {
+        self.connection
+            .path()
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from)
+    }
+
+    /// An id for a new row in `table`, from the current time like Anki's
+    /// ids, but after any existing one.
+    pub(crate) fn next_id(&self, table: &str) -> Result<i64> {
+        let max: Option<i64> = self
+            .prepare_raw(&format!("SELECT MAX(id) FROM {table}"))?
+            .query_row([], |row| row.get(0))?;
+        Ok(now_millis().max(max.map_or(0, |max| max + 1)))
+    }
+
+    /// Records that an object was deleted, so that the next sync deletes it
+    /// from `AnkiWeb` and other devices too.
+    pub(crate) fn add_grave(&self, oid: i64, kind: GraveKind) -> Result<()> {
+        self.prepare_cached_raw("INSERT OR IGNORE INTO graves VALUES (?, ?, -1)")?
+            .execute(params![oid, kind as i64])?;
+        Ok(())
+    }
+
+    /// Deletes a card, recording it in the `graves` table.
+    pub(crate) fn delete_card(&self, card: CardId) -> Result<()> {
+        self.prepare_cached_raw("DELETE FROM cards WHERE id = ?")?
+            .execute(params![card])?;
+        self.add_grave(card.into(), GraveKind::Card)
+    }
+
     /// Gets the id of a deck by its name.
     ///
     /// ```rust,no_run
@@ -220,4 +427,26 @@ impl Database {
         let res = stmt.query_map(params![id], |row| row.get(0))?;
         res.collect()
     }
+
+    /// Gets the kind of a notetype, which determines whether its cards are
+    /// generated from templates or from cloze deletions.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::Database;
+    /// use ankidb::model::NotetypeKind;
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let id = db.id_for_notetype("Cloze")?;
+    /// assert_eq!(db.notetype_kind(id)?, NotetypeKind::Cloze);
+    /// # Ok::<(), rusqlite::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the provided id does not match a notetype, if its
+    /// config can't be decoded, or if the database becomes unavailable.
+    pub fn notetype_kind(&self, id: NotetypeId) -> Result<NotetypeKind> {
+        let mut stmt = self.prepare_raw("SELECT config FROM notetypes WHERE id=?")?;
+        let config: Message = stmt.query_row(params![id], |row| row.get(0))?;
+        Ok(config.varint(1).into())
+    }
 }
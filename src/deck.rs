@@ -0,0 +1,521 @@
+//! Decks, their options, and the counts shown beside them in the deck list,
+//! and creating, renaming and deleting them.
+//!
+//! Anki stores decks' settings as protobuf blobs; the field numbers used here
+//! come from
+//! <https://github.com/ankitects/anki/blob/30ae9f7c5408420c8f347073a9e5e62756a6d7cb/proto/anki/decks.proto>
+//! and
+//! <https://github.com/ankitects/anki/blob/30ae9f7c5408420c8f347073a9e5e62756a6d7cb/proto/anki/deck_config.proto>.
+
+use crate::{
+    Database,
+    model::{DeckConfigId, DeckId},
+    proto::{DecodeError, Message},
+    search,
+    timing::now_secs,
+};
+use rusqlite::{Result, params};
+use std::collections::HashMap;
+
+mod edit;
+mod filtered;
+
+pub use filtered::{FilteredDeck, FilteredOrder, FilteredSearch};
+
+#[derive(Debug)]
+pub enum Error {
+    Database(rusqlite::Error),
+    /// The change isn't allowed, such as giving a deck a name that's
+    /// already used.
+    Invalid(&'static str),
+    /// One of a filtered deck's searches couldn't be used.
+    Search(search::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Database(e) => write!(f, "{e}"),
+            Self::Invalid(reason) => write!(f, "invalid deck change: {reason}"),
+            Self::Search(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Database(e) => Some(e),
+            Self::Invalid(_) => None,
+            Self::Search(e) => Some(e),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Self::Database(e)
+    }
+}
+
+impl From<search::Error> for Error {
+    fn from(e: search::Error) -> Self {
+        match e {
+            search::Error::Database(e) => Self::Database(e),
+            e @ search::Error::Invalid(_) => Self::Search(e),
+        }
+    }
+}
+
+/// The options shared by decks that use the same preset, from
+/// `DeckConfig::Config`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeckOptions {
+    pub id: DeckConfigId,
+    pub name: String,
+    pub new_per_day: u32,
+    pub reviews_per_day: u32,
+    /// The FSRS parameters from the newest FSRS version that has any,
+    /// or empty if they've never been optimized.
+    pub fsrs_params: Vec<f32>,
+    pub desired_retention: f32,
+    /// Learning steps, in minutes.
+    pub learn_steps: Vec<f32>,
+    /// Relearning steps, in minutes.
+    pub relearn_steps: Vec<f32>,
+    pub graduating_interval_good: u32,
+    pub graduating_interval_easy: u32,
+    pub initial_ease: f32,
+    pub easy_multiplier: f32,
+    pub hard_multiplier: f32,
+    pub lapse_multiplier: f32,
+    pub interval_multiplier: f32,
+    pub maximum_review_interval: u32,
+    pub minimum_lapse_interval: u32,
+    pub leech_action: LeechAction,
+    pub leech_threshold: u32,
+    /// The longest an answer can take, in seconds, for the review log.
+    pub cap_answer_time: u32,
+    /// Which new cards are picked for the day's queue.
+    pub new_card_gather_priority: NewCardGatherPriority,
+    /// The order the picked new cards are shown in.
+    pub new_card_sort_order: NewCardSortOrder,
+}
+
+/// What happens to a card when it becomes a leech.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum LeechAction {
+    #[default]
+    Suspend,
+    TagOnly,
+}
+
+impl From<u64> for LeechAction {
+    fn from(value: u64) -> Self {
+        match value {
+            1 => Self::TagOnly,
+            _ => Self::Suspend,
+        }
+    }
+}
+
+/// Which new cards are gathered first when building the new-card queue.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum NewCardGatherPriority {
+    /// Each deck in turn, in the deck list's order, by position.
+    #[default]
+    Deck,
+    PositionLowestFirst,
+    PositionHighestFirst,
+    RandomNotes,
+    RandomCards,
+    /// Each deck in turn, with its notes in random order.
+    DeckThenRandomNotes,
+}
+
+impl From<u64> for NewCardGatherPriority {
+    fn from(value: u64) -> Self {
+        match value {
+            1 => Self::PositionLowestFirst,
+            2 => Self::PositionHighestFirst,
+            3 => Self::RandomNotes,
+            4 => Self::RandomCards,
+            5 => Self::DeckThenRandomNotes,
+            _ => Self::Deck,
+        }
+    }
+}
+
+/// How gathered new cards are sorted before they're shown.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum NewCardSortOrder {
+    /// By card type, then in the order they were gathered.
+    #[default]
+    Template,
+    /// In the order they were gathered.
+    NoSort,
+    TemplateThenRandom,
+    RandomNoteThenTemplate,
+    RandomCard,
+}
+
+impl From<u64> for NewCardSortOrder {
+    fn from(value: u64) -> Self {
+        match value {
+            1 => Self::NoSort,
+            2 => Self::TemplateThenRandom,
+            3 => Self::RandomNoteThenTemplate,
+            4 => Self::RandomCard,
+            _ => Self::Template,
+        }
+    }
+}
+
+impl DeckOptions {
+    pub(crate) fn decode(
+        id: DeckConfigId,
+        name: String,
+        config: &Message,
+    ) -> Result<Self, DecodeError> {
+        let mut fsrs_params = Vec::new();
+        for field in [6, 5, 3] {
+            fsrs_params = config.floats(field)?;
+            if !fsrs_params.is_empty() {
+                break;
+            }
+        }
+
+        Ok(Self {
+            id,
+            name,
+            new_per_day: config.uint32(9),
+            reviews_per_day: config.uint32(10),
+            fsrs_params,
+            desired_retention: config.float(37),
+            learn_steps: config.floats(1)?,
+            relearn_steps: config.floats(2)?,
+            graduating_interval_good: config.uint32(18),
+            graduating_interval_easy: config.uint32(19),
+            initial_ease: config.float(11),
+            easy_multiplier: config.float(12),
+            hard_multiplier: config.float(13),
+            lapse_multiplier: config.float(14),
+            interval_multiplier: config.float(15),
+            maximum_review_interval: config.uint32(16),
+            minimum_lapse_interval: config.uint32(17),
+            leech_action: config.varint(21).into(),
+            leech_threshold: config.uint32(22),
+            cap_answer_time: config.uint32(24),
+            new_card_gather_priority: config.varint(34).into(),
+            new_card_sort_order: config.varint(32).into(),
+        })
+    }
+}
+
+/// The number of cards a deck has ready to study today, as shown in the
+/// deck list.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeckCounts {
+    pub new: u32,
+    pub learn: u32,
+    pub review: u32,
+}
+
+/// What's been studied in a deck today, from `Decks::Common`.
+#[derive(Debug, Clone, Copy, Default)]
+struct Studied {
+    new: i64,
+    review: i64,
+}
+
+impl Studied {
+    fn decode(common: &Message, today: u32) -> Self {
+        if common.uint32(3) == today {
+            Self {
+                new: common.int32(4).into(),
+                review: common.int32(5).into(),
+            }
+        } else {
+            Self::default()
+        }
+    }
+}
+
+/// How many more new and review cards a deck may show today.
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    new: u32,
+    review: u32,
+}
+
+impl Limits {
+    const UNLIMITED: Self = Self {
+        new: u32::MAX,
+        review: u32::MAX,
+    };
+
+    fn cap(self, other: Self) -> Self {
+        Self {
+            new: self.new.min(other.new),
+            review: self.review.min(other.review),
+        }
+    }
+}
+
+/// Reads a normal deck's own limit override, preferring one set for today
+/// only.
+fn limit_override(
+    normal: &Message,
+    deck_field: u32,
+    today_field: u32,
+    today: u32,
+) -> Result<Option<u32>, DecodeError> {
+    if normal.has(today_field) {
+        let limit = normal.message(today_field)?;
+        if limit.uint32(2) == today {
+            return Ok(Some(limit.uint32(1)));
+        }
+    }
+    Ok(normal.has(deck_field).then(|| normal.uint32(deck_field)))
+}
+
+/// Cards in a single deck (not including its children) that could be
+/// studied today.
+#[derive(Debug, Clone, Copy, Default)]
+struct Available {
+    new: u32,
+    intraday: u32,
+    interday: u32,
+    review: u32,
+}
+
+impl std::ops::AddAssign for Available {
+    fn add_assign(&mut self, other: Self) {
+        self.new += other.new;
+        self.intraday += other.intraday;
+        self.interday += other.interday;
+        self.review += other.review;
+    }
+}
+
+struct Tree {
+    names: HashMap<DeckId, String>,
+    ids: HashMap<String, DeckId>,
+    children: HashMap<DeckId, Vec<DeckId>>,
+    limits: HashMap<DeckId, Limits>,
+    available: HashMap<DeckId, Available>,
+    ignore_review_limit: bool,
+}
+
+impl Tree {
+    /// Counts a deck and its children, with each deck's cards limited by
+    /// its own limits, which already include those of its parents. Interday
+    /// learning cards come out of the review limit first, and new cards are
+    /// limited by what's left of it, like the v3 scheduler.
+    fn count(&self, id: DeckId) -> Available {
+        let mut total = self.available.get(&id).copied().unwrap_or_default();
+        for child in self.children.get(&id).into_iter().flatten() {
+            total += self.count(*child);
+        }
+
+        let limits = self.limits[&id];
+        total.interday = total.interday.min(limits.review);
+        total.review = total.review.min(limits.review - total.interday);
+        total.new = total.new.min(limits.new);
+        if !self.ignore_review_limit {
+            total.new = total.new.min(limits.review - total.interday - total.review);
+        }
+        total
+    }
+
+    fn parent(&self, id: DeckId) -> Option<DeckId> {
+        let (parent, _) = self.names[&id].rsplit_once('\x1f')?;
+        self.ids.get(parent).copied()
+    }
+}
+
+impl Database {
+    /// Gets the ids of a deck and all of its descendants.
+    pub(crate) fn deck_and_children(&self, id: DeckId) -> Result<Vec<DeckId>> {
+        let mut stmt = self.prepare_cached_raw(
+            "SELECT d.id FROM decks d, decks parent
+             WHERE parent.id = ?
+               AND (d.id = parent.id
+                    OR substr(d.name, 1, length(parent.name) + 1) = parent.name || char(31))",
+        )?;
+        let ids = stmt
+            .query_map(params![id], |row| row.get(0))?
+            .collect::<Result<Vec<_>>>()?;
+        if ids.is_empty() {
+            return Err(rusqlite::Error::QueryReturnedNoRows);
+        }
+        Ok(ids)
+    }
+
+    /// Gets the options preset used by a deck.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::Database;
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let id = db.id_for_deck("General")?;
+    /// let options = db.deck_options(id)?;
+    /// println!("{} new cards per day", options.new_per_day);
+    /// # Ok::<(), rusqlite::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the provided id does not match a deck, if it's a
+    /// filtered deck, or if the database becomes unavailable.
+    pub fn deck_options(&self, id: DeckId) -> Result<DeckOptions> {
+        let mut stmt = self.prepare_raw("SELECT kind FROM decks WHERE id=?")?;
+        let kind: Message = stmt.query_row(params![id], |row| row.get(0))?;
+        if !kind.has(1) {
+            return Err(rusqlite::Error::QueryReturnedNoRows);
+        }
+        let config_id = DeckConfigId::from(kind.message(1)?.int64(1));
+
+        let mut stmt = self.prepare_raw("SELECT name, config FROM deck_config WHERE id=?")?;
+        let (name, config): (String, Message) =
+            stmt.query_row(params![config_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(DeckOptions::decode(config_id, name, &config)?)
+    }
+
+    /// Gets the number of new, learning, and review cards a deck has ready
+    /// to study, as shown beside it in the deck list.
+    ///
+    /// This follows the v3 scheduler: each deck's cards are limited by its
+    /// own daily limits (less what's already been studied today) and those
+    /// of its parents, and learning cards due within the learn-ahead limit
+    /// are included.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::Database;
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let id = db.id_for_deck("General")?;
+    /// let counts = db.deck_counts(id)?;
+    /// println!("{} {} {}", counts.new, counts.learn, counts.review);
+    /// # Ok::<(), rusqlite::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the provided id does not match a deck, or if the
+    /// database becomes unavailable.
+    pub fn deck_counts(&self, id: DeckId) -> Result<DeckCounts> {
+        let now = now_secs();
+        let today = self.timing_at(now)?.days_elapsed;
+        let learn_ahead = self.config_i64("collapseTime")?.unwrap_or(1200);
+        let ignore_review_limit =
+            self.config_json("newCardsIgnoreReviewLimit")?.as_deref() == Some("true");
+
+        let mut options = HashMap::new();
+        let mut stmt = self.prepare_raw("SELECT id, config FROM deck_config")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let config: Message = row.get(1)?;
+            let config = DeckOptions::decode(row.get(0)?, String::new(), &config)?;
+            options.insert(config.id, config);
+        }
+
+        let mut names: HashMap<DeckId, String> = HashMap::new();
+        let mut own_limits = HashMap::new();
+        let mut stmt = self.prepare_raw("SELECT id, name, common, kind FROM decks")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let deck: DeckId = row.get(0)?;
+            let common: Message = row.get(2)?;
+            let kind: Message = row.get(3)?;
+
+            // Filtered decks have no limits of their own
+            let limits = if kind.has(1) {
+                let normal = kind.message(1)?;
+                let options = options.get(&DeckConfigId::from(normal.int64(1)));
+                let studied = Studied::decode(&common, today);
+                let remaining = |limit: u32, studied: i64| {
+                    u32::try_from((i64::from(limit) - studied).max(0)).unwrap_or_default()
+                };
+                Limits {
+                    new: remaining(
+                        limit_override(&normal, 7, 9, today)?
+                            .or_else(|| options.map(|o| o.new_per_day))
+                            .unwrap_or_default(),
+                        studied.new,
+                    ),
+                    review: remaining(
+                        limit_override(&normal, 6, 8, today)?
+                            .or_else(|| options.map(|o| o.reviews_per_day))
+                            .unwrap_or_default(),
+                        studied.review,
+                    ),
+                }
+            } else {
+                Limits::UNLIMITED
+            };
+
+            names.insert(deck, row.get(1)?);
+            own_limits.insert(deck, limits);
+        }
+
+        if !names.contains_key(&id) {
+            return Err(rusqlite::Error::QueryReturnedNoRows);
+        }
+
+        let mut available = HashMap::new();
+        let mut stmt = self.prepare_raw(
+            "SELECT did,
+                 SUM(queue = 0),
+                 SUM(queue IN (1, 4) AND due < ?1),
+                 SUM(queue = 3 AND due <= ?2),
+                 SUM(queue = 2 AND due <= ?2)
+             FROM cards
+             GROUP BY did",
+        )?;
+        let mut rows = stmt.query(params![now + learn_ahead, today])?;
+        while let Some(row) = rows.next()? {
+            available.insert(
+                row.get(0)?,
+                Available {
+                    new: row.get(1)?,
+                    intraday: row.get(2)?,
+                    interday: row.get(3)?,
+                    review: row.get(4)?,
+                },
+            );
+        }
+
+        let mut tree = Tree {
+            ids: names.iter().map(|(id, name)| (name.clone(), *id)).collect(),
+            names,
+            children: HashMap::new(),
+            limits: HashMap::new(),
+            available,
+            ignore_review_limit,
+        };
+
+        // Parents are always named before their children, so sorting by name
+        // lets each deck's limits be capped by its already-capped parent
+        let mut decks = tree.names.keys().copied().collect::<Vec<_>>();
+        decks.sort_by(|a, b| tree.names[a].cmp(&tree.names[b]));
+        for deck in decks {
+            let mut limits = own_limits[&deck];
+            if let Some(parent) = tree.parent(deck) {
+                limits = limits.cap(tree.limits[&parent]);
+                tree.children.entry(parent).or_default().push(deck);
+            }
+            tree.limits.insert(deck, limits);
+        }
+
+        let counts = tree.count(id);
+        Ok(DeckCounts {
+            new: counts.new,
+            learn: counts.intraday + counts.interday,
+            review: counts.review,
+        })
+    }
+}
@@ -0,0 +1,278 @@
+//! Creating, renaming, moving and deleting decks.
+
+use super::Error;
+use crate::{
+    Database,
+    model::{CardId, DeckId, GraveKind, NoteId},
+    proto::Message,
+    stock,
+    timing::now_secs,
+};
+use rusqlite::{OptionalExtension, params};
+
+/// Turns a name with `::` between levels, as Anki shows it, into the form
+/// stored in `Decks::Name`. Like in Anki, each level is trimmed, and empty
+/// ones are named "blank".
+pub(super) fn native_name(name: &str) -> String {
+    name.split("::")
+        .map(|part| match part.replace('\x1f', "").trim() {
+            "" => "blank".to_string(),
+            part => part.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("\x1f")
+}
+
+impl Database {
+    /// A deck's name, and its `kind`, which has field 2 set for filtered
+    /// decks.
+    pub(super) fn deck_name_and_kind(&self, id: DeckId) -> rusqlite::Result<(String, Message)> {
+        self.prepare_cached_raw("SELECT name, kind FROM decks WHERE id = ?")?
+            .query_row(params![id], |row| Ok((row.get(0)?, row.get(1)?)))
+    }
+
+    /// Checks that a deck can be given a native name: no other deck has it,
+    /// and none of its parents are filtered decks, which can't have
+    /// children. `except` is the deck being renamed, if any.
+    pub(super) fn check_deck_name(&self, name: &str, except: Option<DeckId>) -> Result<(), Error> {
+        let existing: Option<DeckId> = self
+            .prepare_cached_raw("SELECT id FROM decks WHERE name = ?")?
+            .query_row(params![name], |row| row.get(0))
+            .optional()?;
+        if existing.is_some_and(|id| Some(id) != except) {
+            return Err(Error::Invalid("a deck with that name already exists"));
+        }
+        for (end, _) in name.match_indices('\x1f') {
+            let kind: Option<Message> = self
+                .prepare_cached_raw("SELECT kind FROM decks WHERE name = ?")?
+                .query_row(params![&name[..end]], |row| row.get(0))
+                .optional()?;
+            if kind.is_some_and(|kind| kind.has(2)) {
+                return Err(Error::Invalid("filtered decks can't have child decks"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the parents of a deck that don't exist yet, as normal decks
+    /// using the Default preset.
+    fn add_parent_decks(&self, name: &str) -> rusqlite::Result<()> {
+        for (end, _) in name.match_indices('\x1f') {
+            let id = self.next_id("decks")?;
+            self.prepare_cached_raw(
+                "INSERT INTO decks SELECT ?, ?, ?, -1, ?, ?
+                 WHERE NOT EXISTS (SELECT 1 FROM decks WHERE name = ?2)",
+            )?
+            .execute(params![
+                id,
+                &name[..end],
+                now_secs(),
+                Message::new(),
+                stock::normal_deck_kind(1.into())
+            ])?;
+        }
+        Ok(())
+    }
+
+    /// Adds a deck with a native name that's been checked with
+    /// [`Self::check_deck_name`], along with its missing parents.
+    pub(super) fn insert_deck(&self, name: &str, kind: &Message) -> rusqlite::Result<DeckId> {
+        self.add_parent_decks(name)?;
+        let id = DeckId::from(self.next_id("decks")?);
+        self.prepare_cached_raw("INSERT INTO decks VALUES (?, ?, ?, -1, ?, ?)")?
+            .execute(params![id, name, now_secs(), Message::new(), kind])?;
+        Ok(id)
+    }
+
+    /// Gives a deck a new native name, renaming its descendants to match.
+    fn move_deck(&self, id: DeckId, name: &str) -> Result<(), Error> {
+        let (old, _) = self.deck_name_and_kind(id)?;
+        if old == name {
+            return Ok(());
+        }
+        if name.starts_with(&format!("{old}\x1f")) {
+            return Err(Error::Invalid("a deck can't be moved into itself"));
+        }
+        self.check_deck_name(name, Some(id))?;
+        Ok(self.transact(|db| {
+            db.add_parent_decks(name)?;
+            db.prepare_raw(
+                "UPDATE decks SET name = ?1 || substr(name, length(?2) + 1), mtime_secs = ?3, usn = -1
+                 WHERE id = ?4 OR substr(name, 1, length(?2) + 1) = ?2 || char(31)",
+            )?
+            .execute(params![name, old, now_secs(), id])?;
+            Ok(())
+        })?)
+    }
+
+    /// Creates a normal deck using the Default preset, along with any of
+    /// its parents that don't exist yet. Names use `::` between levels, as
+    /// in Anki.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::Database;
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let id = db.add_deck("Japanese::Vocab")?;
+    /// # Ok::<(), ankidb::deck::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if a deck with the name exists, if a parent is a
+    /// filtered deck, or if the database becomes unavailable.
+    pub fn add_deck(&self, name: &str) -> Result<DeckId, Error> {
+        let name = native_name(name);
+        self.check_deck_name(&name, None)?;
+        Ok(self.transact(|db| db.insert_deck(&name, &stock::normal_deck_kind(1.into())))?)
+    }
+
+    /// Renames a deck, along with its descendants. Since names include the
+    /// parents, this can also move a deck elsewhere in the tree, and any
+    /// new parents are created.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::Database;
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let id = db.id_for_deck("Japanese\x1fVocab")?;
+    /// db.rename_deck(id, "Japanese::Words")?;
+    /// # Ok::<(), ankidb::deck::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the deck doesn't exist, if another deck has the
+    /// name, if it would move the deck into itself or under a filtered
+    /// deck, or if the database becomes unavailable.
+    pub fn rename_deck(&self, id: DeckId, name: &str) -> Result<(), Error> {
+        self.move_deck(id, &native_name(name))
+    }
+
+    /// Moves a deck and its descendants under another deck, or to the top
+    /// level for `None`, keeping its own name.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::Database;
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let vocab = db.id_for_deck("Vocab")?;
+    /// let japanese = db.id_for_deck("Japanese")?;
+    /// db.reparent_deck(vocab, Some(japanese))?;
+    /// # Ok::<(), ankidb::deck::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if either deck doesn't exist, if the new parent
+    /// already has a deck with the name, if the parent is the deck itself,
+    /// one of its descendants or a filtered deck, or if the database
+    /// becomes unavailable.
+    pub fn reparent_deck(&self, id: DeckId, parent: Option<DeckId>) -> Result<(), Error> {
+        let (name, _) = self.deck_name_and_kind(id)?;
+        let base = name.rsplit('\x1f').next().unwrap_or_default();
+        let name = match parent {
+            Some(parent) => format!("{}\x1f{base}", self.deck_name_and_kind(parent)?.0),
+            None => base.to_string(),
+        };
+        self.move_deck(id, &name)
+    }
+
+    /// Deletes a deck and its descendants, recording them in the `graves`
+    /// table. Cards in filtered decks go back to their home decks first.
+    /// The remaining cards are moved to `move_cards_to`, or, for `None`,
+    /// deleted along with notes left without cards, as Anki does. Returns
+    /// how many cards were moved or deleted.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::Database;
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let old = db.id_for_deck("Old")?;
+    /// let moved = db.delete_deck(old, Some(1.into()))?;
+    /// println!("{moved} cards moved to Default");
+    /// # Ok::<(), ankidb::deck::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the deck doesn't exist, if it's the Default deck or
+    /// one of its parents, if cards would be moved to a filtered deck or
+    /// one being deleted, or if the database becomes unavailable.
+    pub fn delete_deck(&self, id: DeckId, move_cards_to: Option<DeckId>) -> Result<usize, Error> {
+        let decks = self.deck_and_children(id)?;
+        if decks.contains(&DeckId::from(1)) {
+            return Err(Error::Invalid("the Default deck can't be deleted"));
+        }
+        if let Some(target) = move_cards_to {
+            if decks.contains(&target) {
+                return Err(Error::Invalid(
+                    "cards can't be moved to a deck being deleted",
+                ));
+            }
+            if self.deck_name_and_kind(target)?.1.has(2) {
+                return Err(Error::Invalid("cards can't be moved to a filtered deck"));
+            }
+        }
+
+        Ok(self.transact(|db| {
+            let mut count = 0;
+            for &deck in &decks {
+                if db.deck_name_and_kind(deck)?.1.has(2) {
+                    db.return_cards(deck)?;
+                }
+            }
+            for &deck in &decks {
+                count += match move_cards_to {
+                    Some(target) => db.move_deck_cards(deck, target)?,
+                    None => db.delete_deck_cards(deck)?,
+                };
+                db.prepare_cached_raw("DELETE FROM decks WHERE id = ?")?
+                    .execute(params![deck])?;
+                db.add_grave(deck.into(), GraveKind::Deck)?;
+            }
+            if db
+                .config_i64("curDeck")?
+                .is_some_and(|current| decks.contains(&DeckId::from(current)))
+            {
+                db.set_config_json("curDeck", "1")?;
+                db.set_config_json("activeDecks", "[1]")?;
+            }
+            Ok(count)
+        })?)
+    }
+
+    /// Moves the cards whose home is `deck` to `target`. Cards in filtered
+    /// decks stay there, with `target` as their new home.
+    fn move_deck_cards(&self, deck: DeckId, target: DeckId) -> rusqlite::Result<usize> {
+        self.prepare_cached_raw(
+            "UPDATE cards SET did = CASE WHEN odid = 0 THEN ?1 ELSE did END,
+                              odid = CASE WHEN odid = 0 THEN 0 ELSE ?1 END,
+                              mod = ?2, usn = -1
+             WHERE did = ?3 OR odid = ?3",
+        )?
+        .execute(params![target, now_secs(), deck])
+    }
+
+    /// Deletes the cards whose home is `deck`, and the notes they leave
+    /// without cards.
+    fn delete_deck_cards(&self, deck: DeckId) -> rusqlite::Result<usize> {
+        let cards = self
+            .prepare_cached_raw("SELECT id, nid FROM cards WHERE did = ?1 OR odid = ?1")?
+            .query_map(params![deck], |row| {
+                Ok((row.get::<_, CardId>(0)?, row.get::<_, NoteId>(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for &(card, _) in &cards {
+            self.delete_card(card)?;
+        }
+        for &(_, note) in &cards {
+            let deleted = self
+                .prepare_cached_raw(
+                    "DELETE FROM notes WHERE id = ?1
+                     AND NOT EXISTS (SELECT 1 FROM cards WHERE nid = ?1)",
+                )?
+                .execute(params![note])?;
+            if deleted > 0 {
+                self.add_grave(note.into(), GraveKind::Note)?;
+            }
+        }
+        Ok(cards.len())
+    }
+}
@@ -0,0 +1,435 @@
+//! Filtered decks, which borrow cards from their home decks for a while.
+
+use super::{Error, edit::native_name};
+use crate::{
+    Database,
+    model::{CardId, DeckId},
+    proto::{DecodeError, Message},
+    search,
+    timing::now_secs,
+};
+use rusqlite::{Result, params, params_from_iter};
+
+/// Moves cards in filtered decks back home, as Anki's Empty does. A
+/// condition on `?2` can be appended to pick which cards.
+const RETURN_CARDS: &str = "UPDATE cards SET did = odid, odid = 0,
+        due = CASE WHEN odue != 0 THEN odue ELSE due END, odue = 0,
+        queue = CASE
+            WHEN queue < 0 THEN queue
+            WHEN type IN (1, 3) THEN
+                CASE WHEN (CASE WHEN odue != 0 THEN odue ELSE due END) > 1000000000
+                    THEN 1 ELSE 3 END
+            ELSE type END,
+        mod = ?1, usn = -1
+    WHERE odid != 0";
+
+/// The order a search's cards are gathered in, from
+/// `Deck::Filtered::SearchTerm::Order`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FilteredOrder {
+    OldestReviewedFirst,
+    #[default]
+    Random,
+    IntervalsAscending,
+    IntervalsDescending,
+    Lapses,
+    Added,
+    Due,
+    ReverseAdded,
+    RelativeOverdueness,
+}
+
+impl FilteredOrder {
+    /// How Anki sorts a search's cards, on `cards c` joined with `notes n`.
+    fn sql(self, today: i64, now: i64) -> String {
+        match self {
+            Self::OldestReviewedFirst => "(SELECT MAX(id) FROM revlog WHERE cid = c.id)".into(),
+            Self::Random => "random()".into(),
+            Self::IntervalsAscending => "c.ivl".into(),
+            Self::IntervalsDescending => "c.ivl DESC".into(),
+            Self::Lapses => "c.lapses DESC".into(),
+            Self::Added => "n.id, c.ord".into(),
+            Self::ReverseAdded => "n.id DESC".into(),
+            // Learning cards are due at a time rather than on a day
+            Self::Due => format!(
+                "(CASE WHEN c.due > 1000000000 THEN c.due
+                  ELSE (c.due - {today}) * 86400 + {now} END), c.ord"
+            ),
+            Self::RelativeOverdueness => format!(
+                "(CASE WHEN c.queue = 2 AND c.due <= {today}
+                  THEN c.ivl / CAST({today} - c.due + 0.001 AS REAL)
+                  ELSE 100000 + c.due END)"
+            ),
+        }
+    }
+}
+
+impl From<u64> for FilteredOrder {
+    fn from(value: u64) -> Self {
+        match value {
+            0 => Self::OldestReviewedFirst,
+            2 => Self::IntervalsAscending,
+            3 => Self::IntervalsDescending,
+            4 => Self::Lapses,
+            5 => Self::Added,
+            6 => Self::Due,
+            7 => Self::ReverseAdded,
+            8 => Self::RelativeOverdueness,
+            _ => Self::Random,
+        }
+    }
+}
+
+/// One of the searches that gathers a filtered deck's cards, from
+/// `Deck::Filtered::SearchTerm`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FilteredSearch {
+    /// A search in Anki's search syntax, such as `deck:Japanese is:due`.
+    pub search: String,
+    /// The most cards to gather.
+    pub limit: u32,
+    pub order: FilteredOrder,
+}
+
+/// A filtered deck's settings, from `Decks::Kind`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FilteredDeck {
+    /// The searches that gather cards, of which Anki allows one or two.
+    pub search_terms: Vec<FilteredSearch>,
+    /// Whether answers count towards cards' normal scheduling, rather
+    /// than only previewing them.
+    pub reschedule: bool,
+    /// When cards are shown again after each answer while previewing, in
+    /// seconds. Good at 0 means the card leaves the deck.
+    pub preview_again_secs: u32,
+    pub preview_hard_secs: u32,
+    pub preview_good_secs: u32,
+}
+
+impl Default for FilteredDeck {
+    /// Anki's defaults, without any searches.
+    fn default() -> Self {
+        Self {
+            search_terms: Vec::new(),
+            reschedule: true,
+            preview_again_secs: 60,
+            preview_hard_secs: 600,
+            preview_good_secs: 0,
+        }
+    }
+}
+
+impl FilteredDeck {
+    pub(crate) fn decode(kind: &Message) -> Result<Self, DecodeError> {
+        let filtered = kind.message(2)?;
+        Ok(Self {
+            search_terms: filtered
+                .messages(2)?
+                .iter()
+                .map(|term| FilteredSearch {
+                    search: term.string(1),
+                    limit: term.uint32(2),
+                    order: term.varint(3).into(),
+                })
+                .collect(),
+            reschedule: filtered.varint(1) != 0,
+            preview_again_secs: filtered.uint32(7),
+            preview_hard_secs: filtered.uint32(5),
+            preview_good_secs: filtered.uint32(6),
+        })
+    }
+
+    /// The deck's `kind`, which holds the settings in field 2.
+    fn encode(&self) -> Message {
+        let terms = self
+            .search_terms
+            .iter()
+            .map(|term| {
+                Message::new()
+                    .set_string(1, &term.search)
+                    .set_uint32(2, term.limit)
+                    .set_varint(3, term.order as u64)
+                    .clone()
+            })
+            .collect::<Vec<_>>();
+        let filtered = Message::new()
+            .set_varint(1, self.reschedule.into())
+            .set_messages(2, &terms)
+            .set_uint32(5, self.preview_hard_secs)
+            .set_uint32(6, self.preview_good_secs)
+            .set_uint32(7, self.preview_again_secs)
+            .clone();
+        Message::new().set_message(2, &filtered).clone()
+    }
+}
+
+impl Database {
+    /// Gets the settings of a filtered deck.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::Database;
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let id = db.id_for_deck("Exam Cram")?;
+    /// for term in db.filtered_deck(id)?.search_terms {
+    ///     println!("{} ({} cards)", term.search, term.limit);
+    /// }
+    /// # Ok::<(), rusqlite::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the provided id does not match a deck, if it's a
+    /// normal deck, or if the database becomes unavailable.
+    pub fn filtered_deck(&self, id: DeckId) -> Result<FilteredDeck> {
+        let (_, kind) = self.deck_name_and_kind(id)?;
+        if !kind.has(2) {
+            return Err(rusqlite::Error::QueryReturnedNoRows);
+        }
+        Ok(FilteredDeck::decode(&kind)?)
+    }
+
+    /// Creates an empty filtered deck, along with any of its parents that
+    /// don't exist yet. Names use `::` between levels, as in Anki. Unlike
+    /// Anki, this doesn't gather cards; use [`Self::build_filtered_deck`]
+    /// for that.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::Database;
+    /// use ankidb::deck::{FilteredDeck, FilteredOrder, FilteredSearch};
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let deck = FilteredDeck {
+    ///     search_terms: vec![FilteredSearch {
+    ///         search: "deck:Japanese prop:due<=3".to_string(),
+    ///         limit: 200,
+    ///         order: FilteredOrder::Due,
+    ///     }],
+    ///     ..FilteredDeck::default()
+    /// };
+    /// let id = db.add_filtered_deck("Exam Cram", &deck)?;
+    /// # Ok::<(), ankidb::deck::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if a deck with the name exists, if a parent is a
+    /// filtered deck, if there isn't one or two searches, or if the
+    /// database becomes unavailable.
+    pub fn add_filtered_deck(&self, name: &str, deck: &FilteredDeck) -> Result<DeckId, Error> {
+        if !(1..=2).contains(&deck.search_terms.len()) {
+            return Err(Error::Invalid("filtered decks need one or two searches"));
+        }
+        let name = native_name(name);
+        self.check_deck_name(&name, None)?;
+        Ok(self.transact(|db| db.insert_deck(&name, &deck.encode()))?)
+    }
+
+    /// Fills a filtered deck with the cards its searches find, as Anki's
+    /// Rebuild does, first returning any cards it already has. Returns how
+    /// many cards were gathered.
+    ///
+    /// Each search adds up to its limit of cards, in its order, skipping
+    /// suspended and buried cards and cards already in a filtered deck.
+    /// Their home deck and due date are kept in `Cards::Odid` and
+    /// `Cards::Odue`, and, like in Anki, cards due on a day are given
+    /// positions that show them in the order they were gathered. Without
+    /// rescheduling, cards are put in the review queue to be previewed.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::Database;
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let id = db.id_for_deck("Exam Cram")?;
+    /// let gathered = db.build_filtered_deck(id)?;
+    /// println!("{gathered} cards to study");
+    /// # Ok::<(), ankidb::deck::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the deck doesn't exist or isn't a filtered deck, if
+    /// one of its searches can't be understood, or if the database becomes
+    /// unavailable. Nothing is changed if it fails.
+    pub fn build_filtered_deck(&self, id: DeckId) -> Result<usize, Error> {
+        let (_, kind) = self.deck_name_and_kind(id)?;
+        if !kind.has(2) {
+            return Err(Error::Invalid("not a filtered deck"));
+        }
+        let deck = FilteredDeck::decode(&kind).map_err(rusqlite::Error::from)?;
+        let now = now_secs();
+        let today = i64::from(self.timing_at(now)?.days_elapsed);
+        let searches = deck
+            .search_terms
+            .iter()
+            .map(|term| {
+                let (condition, params) = search::card_condition(self, &term.search)?;
+                let sql = format!(
+                    "SELECT c.id FROM cards c JOIN notes n ON n.id = c.nid
+                     WHERE ({condition}) AND c.queue >= 0 AND c.odid = 0
+                     ORDER BY {} LIMIT {}",
+                    term.order.sql(today, now),
+                    term.limit
+                );
+                Ok((sql, params))
+            })
+            .collect::<Result<Vec<_>, search::Error>>()?;
+
+        Ok(self.transact(|db| {
+            db.return_cards(id)?;
+            let mut position = -100_000;
+            let mut gathered = 0;
+            for (sql, params) in &searches {
+                let cards = db
+                    .prepare_raw(sql)?
+                    .query_map(params_from_iter(params), |row| row.get::<_, CardId>(0))?
+                    .collect::<Result<Vec<_>>>()?;
+                for card in cards {
+                    db.prepare_cached_raw(
+                        "UPDATE cards SET odid = did, did = ?, odue = due,
+                            queue = CASE WHEN ? THEN queue ELSE 2 END,
+                            due = CASE WHEN due > 0 THEN ? ELSE due END,
+                            mod = ?, usn = -1
+                         WHERE id = ?",
+                    )?
+                    .execute(params![
+                        id,
+                        deck.reschedule,
+                        position,
+                        now,
+                        card
+                    ])?;
+                    position += 1;
+                    gathered += 1;
+                }
+            }
+            Ok(gathered)
+        })?)
+    }
+
+    /// Returns a filtered deck's cards to their home decks, as Anki's Empty
+    /// does, restoring their due dates and queues. Returns how many cards
+    /// were returned.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::Database;
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let id = db.id_for_deck("Exam Cram")?;
+    /// db.empty_filtered_deck(id)?;
+    /// # Ok::<(), ankidb::deck::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the deck doesn't exist or isn't a filtered deck, or
+    /// if the database becomes unavailable.
+    pub fn empty_filtered_deck(&self, id: DeckId) -> Result<usize, Error> {
+        if !self.deck_name_and_kind(id)?.1.has(2) {
+            return Err(Error::Invalid("not a filtered deck"));
+        }
+        Ok(self.transact(|db| db.return_cards(id))?)
+    }
+
+    /// Moves a filtered deck's cards back to their home decks, restoring
+    /// their due dates and queues like Anki does. Returns how many cards
+    /// were moved.
+    pub(super) fn return_cards(&self, deck: DeckId) -> Result<usize> {
+        self.prepare_cached_raw(&format!("{RETURN_CARDS} AND did = ?2"))?
+            .execute(params![now_secs(), deck])
+    }
+
+    /// Moves every card that's in a filtered deck back to its home deck,
+    /// like [`Self::return_cards`].
+    pub(crate) fn return_all_cards(&self) -> Result<usize> {
+        self.prepare_cached_raw(RETURN_CARDS)?
+            .execute(params![now_secs()])
+    }
+
+    /// Moves a card in a filtered deck back to its home deck, like
+    /// [`Self::return_cards`]. Cards that aren't in a filtered deck are left
+    /// alone.
+    pub(crate) fn return_card(&self, id: CardId) -> Result<()> {
+        self.prepare_cached_raw(&format!("{RETURN_CARDS} AND id = ?2"))?
+            .execute(params![now_secs(), id])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{model::Ease, testing::Fixture};
+
+    /// Each card's deck, due and queue, and its home deck.
+    fn cards(db: &Database) -> Result<Vec<(DeckId, i64, i64, DeckId)>> {
+        db.prepare_raw("SELECT did, due, queue, odid FROM cards ORDER BY id")?
+            .query_map([], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })?
+            .collect()
+    }
+
+    /// A new card, a review card and a learning card in a Japanese deck,
+    /// and an empty filtered deck that gathers them.
+    fn cram(reschedule: bool) -> Result<(Database, DeckId), Error> {
+        let db = Fixture::new()
+            .with_deck("Japanese")
+            .with_note("Japanese", "Basic", &["猫", "cat"], &[])
+            .with_note("Japanese", "Basic", &["犬", "dog"], &[])
+            .with_reviews(&[(10, Ease::Good), (5, Ease::Good)])
+            .with_note("Japanese", "Basic", &["本", "book"], &[])
+            .build()?;
+        db.prepare_raw(
+            "UPDATE cards SET type = 1, queue = 1, due = ? WHERE id = (SELECT MAX(id) FROM cards)",
+        )?
+        .execute([now_secs() + 600])?;
+        let deck = FilteredDeck {
+            search_terms: vec![FilteredSearch {
+                search: "deck:Japanese".to_string(),
+                limit: 100,
+                order: FilteredOrder::Added,
+            }],
+            reschedule,
+            ..FilteredDeck::default()
+        };
+        let id = db.add_filtered_deck("Cram", &deck)?;
+        Ok((db, id))
+    }
+
+    #[test]
+    fn emptying_restores_gathered_cards() -> Result<(), Error> {
+        let (db, id) = cram(true)?;
+        let before = cards(&db)?;
+
+        assert_eq!(db.build_filtered_deck(id)?, 3);
+        let built = cards(&db)?;
+        assert!(built.iter().all(|&(did, ..)| did == id));
+        assert_eq!(
+            built
+                .iter()
+                .map(|&(_, _, queue, odid)| (queue, odid))
+                .collect::<Vec<_>>(),
+            before
+                .iter()
+                .map(|&(did, _, queue, _)| (queue, did))
+                .collect::<Vec<_>>(),
+        );
+
+        assert_eq!(db.empty_filtered_deck(id)?, 3);
+        assert_eq!(cards(&db)?, before);
+        Ok(())
+    }
+
+    #[test]
+    fn previewed_cards_are_put_in_the_review_queue() -> Result<(), Error> {
+        let (db, id) = cram(false)?;
+        let before = cards(&db)?;
+
+        db.build_filtered_deck(id)?;
+        assert!(cards(&db)?.iter().all(|&(_, _, queue, _)| queue == 2));
+
+        db.empty_filtered_deck(id)?;
+        assert_eq!(cards(&db)?, before);
+        Ok(())
+    }
+}
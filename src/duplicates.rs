@@ -0,0 +1,178 @@
+//! Finding notes that share a field's content.
+//!
+//! This crate doesn't add notes itself, apart from importing packages, which
+//! matches notes by guid as Anki does. Tools that write notes directly can
+//! use [`Database::first_field_duplicates`] beforehand, which is the check
+//! Anki's editor runs when adding a note.
+//!
+//! ```rust,no_run
+//! use ankidb::{Database, duplicates::Normalization};
+//!
+//! let db = Database::open(&"/path/to/collection.anki2")?;
+//! let id = db.id_for_notetype("Basic")?;
+//! for duplicate in db.find_duplicates(id, "Front", Normalization::default())? {
+//!     println!("{}: {:?}", duplicate.text, duplicate.notes);
+//! }
+//! # Ok::<(), rusqlite::Error>(())
+//! ```
+
+use crate::{
+    Database,
+    furigana::kanji,
+    model::{NoteId, NotetypeId, parse_fields},
+    text::{field_checksum, strip_html_preserving_media_filenames},
+};
+use rusqlite::{Result, params};
+use std::collections::HashMap;
+
+/// How field text is compared, on top of stripping HTML and surrounding
+/// whitespace as Anki's Find Duplicates does.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Normalization {
+    pub case_fold: bool,
+    /// Remove readings, so that `漢字[かんじ]` matches `漢字`.
+    pub strip_furigana: bool,
+}
+
+impl Normalization {
+    fn apply(self, field: &str) -> String {
+        let mut text = strip_html_preserving_media_filenames(field);
+        if self.strip_furigana {
+            text = kanji(&text);
+        }
+        if self.case_fold {
+            text = text.to_lowercase();
+        }
+        text.trim().to_string()
+    }
+}
+
+/// Notes whose field normalized to the same text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Duplicate {
+    pub text: String,
+    pub notes: Vec<NoteId>,
+}
+
+impl Database {
+    /// Groups a notetype's notes by the normalized text of one of their
+    /// fields, returning each group of two or more notes. Notes whose field
+    /// is empty are ignored.
+    ///
+    /// Groups are ordered by their oldest note, and notes within a group
+    /// by id. When comparing the first field without normalization, only
+    /// notes sharing a checksum in `notes.csum` are considered, which
+    /// relies on the checksums being up to date, as Anki keeps them.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::Database;
+    /// use ankidb::duplicates::Normalization;
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let id = db.id_for_notetype("Japanese")?;
+    /// let normalization = Normalization {
+    ///     case_fold: true,
+    ///     strip_furigana: true,
+    /// };
+    /// let duplicates = db.find_duplicates(id, "Expression", normalization)?;
+    /// println!("{} sets of duplicates", duplicates.len());
+    /// # Ok::<(), rusqlite::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the provided id does not match a notetype, if the
+    /// notetype has no field with that name, or if the database becomes
+    /// unavailable.
+    pub fn find_duplicates(
+        &self,
+        notetype: NotetypeId,
+        field: &str,
+        normalization: Normalization,
+    ) -> Result<Vec<Duplicate>> {
+        let index = self
+            .fields_for_notetype(notetype)?
+            .iter()
+            .position(|f| f == field)
+            .ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+
+        let mut stmt = if index == 0 && normalization == Normalization::default() {
+            self.prepare_raw(
+                "SELECT id, flds FROM notes
+                 WHERE mid = ?1
+                   AND csum IN (SELECT csum FROM notes WHERE mid = ?1
+                                GROUP BY csum HAVING COUNT(*) > 1)
+                 ORDER BY id",
+            )?
+        } else {
+            self.prepare_raw("SELECT id, flds FROM notes WHERE mid = ?1 ORDER BY id")?
+        };
+        let mut rows = stmt.query(params![notetype])?;
+
+        let mut duplicates: Vec<Duplicate> = Vec::new();
+        let mut groups: HashMap<String, usize> = HashMap::new();
+        while let Some(row) = rows.next()? {
+            let fields: String = row.get(1)?;
+            let text = normalization.apply(parse_fields(&fields).nth(index).unwrap_or_default());
+            if text.is_empty() {
+                continue;
+            }
+            let id = row.get(0)?;
+            if let Some(&group) = groups.get(&text) {
+                duplicates[group].notes.push(id);
+            } else {
+                groups.insert(text.clone(), duplicates.len());
+                duplicates.push(Duplicate {
+                    text,
+                    notes: vec![id],
+                });
+            }
+        }
+
+        duplicates.retain(|d| d.notes.len() > 1);
+        Ok(duplicates)
+    }
+
+    /// Finds existing notes of a notetype that a new note with this first
+    /// field would duplicate, using the same check as Anki's editor: the
+    /// first fields match once HTML is stripped. A first field that's empty
+    /// never has duplicates. Use this to check a note before adding it.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::Database;
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let id = db.id_for_notetype("Basic")?;
+    /// if !db.first_field_duplicates(id, "<b>hello</b>")?.is_empty() {
+    ///     println!("already have a note for hello");
+    /// }
+    /// # Ok::<(), rusqlite::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the database becomes unavailable.
+    pub fn first_field_duplicates(
+        &self,
+        notetype: NotetypeId,
+        first_field: &str,
+    ) -> Result<Vec<NoteId>> {
+        let stripped = strip_html_preserving_media_filenames(first_field);
+        if stripped.trim().is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut stmt =
+            self.prepare_cached_raw("SELECT id, flds FROM notes WHERE mid = ? AND csum = ?")?;
+        let mut rows = stmt.query(params![notetype, field_checksum(first_field)])?;
+        let mut ids = Vec::new();
+        while let Some(row) = rows.next()? {
+            let fields: String = row.get(1)?;
+            let existing = parse_fields(&fields).next().unwrap_or_default();
+            if strip_html_preserving_media_filenames(existing) == stripped {
+                ids.push(row.get(0)?);
+            }
+        }
+        Ok(ids)
+    }
+}
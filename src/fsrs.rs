@@ -0,0 +1,490 @@
+//! Memory states for the FSRS scheduler.
+//!
+//! When FSRS is enabled, Anki keeps each card's stability and difficulty in
+//! `Cards::Data`. The same state can be recomputed by replaying a card's
+//! review log with its deck's parameters, which is what Anki does whenever
+//! the parameters change. The formulas follow FSRS-6 as implemented by
+//! <https://github.com/open-spaced-repetition/fsrs-rs>; parameters from
+//! older versions are upgraded the same way fsrs-rs upgrades them.
+//!
+//! ```rust,no_run
+//! use ankidb::{Database, model::CardId};
+//!
+//! let db = Database::open(&"/path/to/collection.anki2")?;
+//! let id = CardId::from(1700000000000);
+//! let stored = db.card_data(id)?.memory;
+//! let computed = db.compute_memory_state(id)?;
+//! println!("stored {stored:?}, computed {computed:?}");
+//! # Ok::<(), rusqlite::Error>(())
+//! ```
+
+pub mod optimizer;
+
+use crate::{
+    Database,
+    model::{CardId, Ease},
+    timing::{SchedTiming, now_secs},
+};
+use rusqlite::{Result, Row, params};
+use serde_json::Value;
+
+/// The default FSRS-6 parameters, used until a preset has been optimized.
+pub const DEFAULT_PARAMETERS: [f32; 21] = [
+    0.212, 1.2931, 2.3065, 8.2956, 6.4133, 0.8334, 3.0194, 0.001, 1.8722, 0.1666, 0.796, 1.4835,
+    0.0614, 0.2629, 1.6483, 0.6014, 1.8729, 0.5425, 0.0912, 0.0658, 0.1542,
+];
+
+/// The decay used by FSRS-4.5 and FSRS-5, which had no parameter for it.
+const FSRS5_DECAY: f32 = 0.5;
+
+/// The retention assumed when converting an SM-2 interval into a memory
+/// state, for cards whose learning history is missing.
+const SM2_RETENTION: f32 = 0.9;
+
+const S_MIN: f32 = 0.001;
+const S_MAX: f32 = 36_500.0;
+
+/// How well a card is remembered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MemoryState {
+    /// The number of days after which recall drops to 90%.
+    pub stability: f32,
+    /// From 1 (easiest) to 10 (hardest).
+    pub difficulty: f32,
+}
+
+/// The JSON that Anki keeps in `Cards::Data`.
+///
+/// Everything is optional, since cards that have never been reviewed with
+/// FSRS have none of it, and older versions of Anki wrote less.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CardData {
+    pub memory: Option<MemoryState>,
+    pub desired_retention: Option<f32>,
+    pub decay: Option<f32>,
+    /// When the card was last reviewed, in seconds.
+    pub last_review: Option<i64>,
+    /// The card's position in the new queue before it was first studied.
+    pub original_position: Option<u32>,
+}
+
+impl CardData {
+    /// Parses `Cards::Data`, ignoring anything that isn't understood.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn parse(data: &str) -> Self {
+        let Ok(Value::Object(json)) = serde_json::from_str(data) else {
+            return Self::default();
+        };
+        let float = |key| json.get(key).and_then(Value::as_f64).map(|v| v as f32);
+
+        Self {
+            memory: float("s")
+                .zip(float("d"))
+                .map(|(stability, difficulty)| MemoryState {
+                    stability,
+                    difficulty,
+                }),
+            desired_retention: float("dr"),
+            decay: float("decay"),
+            last_review: json.get("lrt").and_then(Value::as_i64),
+            original_position: json
+                .get("pos")
+                .and_then(Value::as_u64)
+                .and_then(|v| u32::try_from(v).ok()),
+        }
+    }
+}
+
+/// A memory state recomputed from a card's review log.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ComputedMemory {
+    pub state: MemoryState,
+    /// The probability of recalling the card today.
+    pub retrievability: f32,
+    /// Days since the card was last reviewed.
+    pub elapsed_days: u32,
+}
+
+/// A review to replay, with the number of days since the previous one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Review {
+    pub ease: Ease,
+    pub delta_days: u32,
+}
+
+/// The probability of recalling a card with the given stability after
+/// `elapsed_days`, on a forgetting curve with the given decay.
+///
+/// ```
+/// let r = ankidb::fsrs::retrievability(10.0, 10.0, 0.5);
+/// assert!((r - 0.9).abs() < 1e-6);
+/// ```
+#[must_use]
+pub fn retrievability(stability: f32, elapsed_days: f32, decay: f32) -> f32 {
+    let factor = 0.9f32.powf(-1.0 / decay) - 1.0;
+    (elapsed_days / stability).mul_add(factor, 1.0).powf(-decay)
+}
+
+/// An FSRS model with a fixed set of parameters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fsrs {
+    w: [f32; 21],
+}
+
+/// A preset's FSRS parameters were neither empty nor 17, 19 or 21 long.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParameterCountError(pub usize);
+
+impl std::fmt::Display for ParameterCountError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "expected 17, 19 or 21 FSRS parameters, found {}", self.0)
+    }
+}
+
+impl std::error::Error for ParameterCountError {}
+
+impl From<ParameterCountError> for rusqlite::Error {
+    fn from(e: ParameterCountError) -> Self {
+        Self::FromSqlConversionFailure(0, rusqlite::types::Type::Blob, Box::new(e))
+    }
+}
+
+impl Default for Fsrs {
+    fn default() -> Self {
+        Self {
+            w: DEFAULT_PARAMETERS,
+        }
+    }
+}
+
+impl Fsrs {
+    /// Creates a model from FSRS-4.5 (17), FSRS-5 (19) or FSRS-6 (21)
+    /// parameters, or the defaults if `params` is empty. Returns `None` for
+    /// any other number of parameters.
+    #[must_use]
+    pub fn new(params: &[f32]) -> Option<Self> {
+        let mut w = DEFAULT_PARAMETERS;
+        match params.len() {
+            0 => {}
+            17 => {
+                w[..17].copy_from_slice(params);
+                w[4] = params[5].mul_add(2.0, params[4]);
+                w[5] = params[5].mul_add(3.0, 1.0).ln() / 3.0;
+                w[6] = params[6] + 0.5;
+                w[17..].copy_from_slice(&[0.0, 0.0, 0.0, FSRS5_DECAY]);
+            }
+            19 => {
+                w[..19].copy_from_slice(params);
+                w[19..].copy_from_slice(&[0.0, FSRS5_DECAY]);
+            }
+            21 => w.copy_from_slice(params),
+            _ => return None,
+        }
+        Some(Self { w })
+    }
+
+    /// The parameters in FSRS-6 form.
+    #[must_use]
+    pub const fn parameters(&self) -> &[f32; 21] {
+        &self.w
+    }
+
+    #[must_use]
+    pub const fn decay(&self) -> f32 {
+        self.w[20]
+    }
+
+    /// The probability of recalling a card after `elapsed_days`.
+    #[must_use]
+    pub fn retrievability(&self, state: MemoryState, elapsed_days: f32) -> f32 {
+        retrievability(state.stability, elapsed_days, self.decay())
+    }
+
+    /// The interval, in days, after which recall drops to `desired_retention`.
+    #[must_use]
+    pub fn interval(&self, state: MemoryState, desired_retention: f32) -> f32 {
+        let decay = self.decay();
+        let factor = 0.9f32.powf(-1.0 / decay) - 1.0;
+        state.stability / factor * (desired_retention.powf(-1.0 / decay) - 1.0)
+    }
+
+    fn initial_difficulty(&self, ease: Ease) -> f32 {
+        let rating = f32::from(ease as u8);
+        self.w[4] - (self.w[5] * (rating - 1.0)).exp() + 1.0
+    }
+
+    /// The memory state after a card's first review.
+    #[must_use]
+    pub fn initial_state(&self, ease: Ease) -> MemoryState {
+        MemoryState {
+            stability: self.w[ease as usize - 1].clamp(S_MIN, S_MAX),
+            difficulty: self.initial_difficulty(ease).clamp(1.0, 10.0),
+        }
+    }
+
+    /// The memory state after answering a card `delta_days` after its
+    /// previous review. Reviews on the same day use FSRS's short-term
+    /// stability formula.
+    #[must_use]
+    pub fn next_state(&self, state: MemoryState, ease: Ease, delta_days: u32) -> MemoryState {
+        let w = &self.w;
+        let rating = f32::from(ease as u8);
+        let (s, d) = (state.stability, state.difficulty);
+
+        #[allow(clippy::cast_precision_loss)]
+        let stability = if delta_days == 0 {
+            let mut increase = (w[17] * (rating - 3.0 + w[18])).exp() * s.powf(-w[19]);
+            if ease >= Ease::Good {
+                increase = increase.max(1.0);
+            }
+            s * increase
+        } else {
+            let r = self.retrievability(state, delta_days as f32);
+            if ease == Ease::Again {
+                let forgotten = w[11]
+                    * d.powf(-w[12])
+                    * ((s + 1.0).powf(w[13]) - 1.0)
+                    * ((1.0 - r) * w[14]).exp();
+                forgotten.min(s / (w[17] * w[18]).exp())
+            } else {
+                let hard_penalty = if ease == Ease::Hard { w[15] } else { 1.0 };
+                let easy_bonus = if ease == Ease::Easy { w[16] } else { 1.0 };
+                let increase = w[8].exp()
+                    * (11.0 - d)
+                    * s.powf(-w[9])
+                    * ((1.0 - r) * w[10]).exp_m1()
+                    * hard_penalty
+                    * easy_bonus;
+                s.mul_add(increase, s)
+            }
+        };
+
+        let delta = -w[6] * (rating - 3.0);
+        let damped = d + delta * (10.0 - d) / 9.0;
+        let difficulty = w[7].mul_add(self.initial_difficulty(Ease::Easy) - damped, damped);
+
+        MemoryState {
+            stability: stability.clamp(S_MIN, S_MAX),
+            difficulty: difficulty.clamp(1.0, 10.0),
+        }
+    }
+
+    /// Approximates the memory state of a card that was scheduled with
+    /// SM-2, from its ease factor (e.g. `2.5`) and interval in days.
+    #[must_use]
+    pub fn memory_state_from_sm2(
+        &self,
+        ease_factor: f32,
+        interval: f32,
+        sm2_retention: f32,
+    ) -> MemoryState {
+        let w = &self.w;
+        let decay = self.decay();
+        let factor = 0.9f32.powf(-1.0 / decay) - 1.0;
+        let stability = interval.max(S_MIN) * factor / (sm2_retention.powf(-1.0 / decay) - 1.0);
+        let difficulty = 11.0
+            - (ease_factor - 1.0)
+                / (w[8].exp() * stability.powf(-w[9]) * ((1.0 - sm2_retention) * w[10]).exp_m1());
+        MemoryState {
+            stability: stability.clamp(S_MIN, S_MAX),
+            difficulty: difficulty.clamp(1.0, 10.0),
+        }
+    }
+
+    /// Replays a series of reviews, starting from `starting_state` if given
+    /// or from the first review otherwise.
+    #[must_use]
+    pub fn memory_state(
+        &self,
+        reviews: &[Review],
+        starting_state: Option<MemoryState>,
+    ) -> Option<MemoryState> {
+        let mut reviews = reviews.iter();
+        let mut state = match starting_state {
+            Some(state) => state,
+            None => self.initial_state(reviews.next()?.ease),
+        };
+        for review in reviews {
+            state = self.next_state(state, review.ease, review.delta_days);
+        }
+        Some(state)
+    }
+}
+
+/// A row from the `revlog` table, with just what FSRS needs.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RevlogEntry {
+    pub id: i64,
+    pub ease: i64,
+    pub kind: i64,
+    pub lastivl: i64,
+    pub factor: i64,
+}
+
+impl RevlogEntry {
+    /// Reads an entry from `SELECT id, ease, type, lastivl, factor`.
+    pub fn from_row(row: &Row) -> Result<Self> {
+        Ok(Self {
+            id: row.get(0)?,
+            ease: row.get(1)?,
+            kind: row.get(2)?,
+            lastivl: row.get(3)?,
+            factor: row.get(4)?,
+        })
+    }
+}
+
+/// A card's review log, reduced to the reviews FSRS can learn from.
+#[derive(Debug, Clone)]
+pub(crate) struct History {
+    pub reviews: Vec<Review>,
+    /// The SM-2 ease factor and interval the card had before its first
+    /// review, if its learning steps aren't in the log.
+    pub sm2_start: Option<(f32, f32)>,
+    /// When the last review happened, in seconds.
+    pub last_review: i64,
+}
+
+impl History {
+    /// Picks out the reviews FSRS uses, like Anki does: manual changes,
+    /// reschedules, and cramming in filtered decks are skipped, and only
+    /// reviews since the card was last in learning count. `entries` must be
+    /// ordered by id.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn new(entries: &[RevlogEntry], timing: &SchedTiming) -> Option<Self> {
+        // Anything before the card was last reset is irrelevant
+        let reset = entries
+            .iter()
+            .rposition(|e| e.kind == 4 && e.ease == 0 && e.factor == 0)
+            .map_or(0, |i| i + 1);
+        let entries = entries[reset..]
+            .iter()
+            .filter(|e| (1..=4).contains(&e.ease) && e.kind < 4 && !(e.kind == 3 && e.factor == 0))
+            .collect::<Vec<_>>();
+
+        // Start from the last run of learning entries, in case the card was
+        // forgotten without a manual entry being written
+        let start = entries.iter().rposition(|e| e.kind == 0).map(|last| {
+            entries[..=last]
+                .iter()
+                .rposition(|e| e.kind != 0)
+                .map_or(0, |i| i + 1)
+        });
+        let (entries, sm2_start) = if let Some(start) = start {
+            (&entries[start..], None)
+        } else {
+            let first = entries.first()?;
+            (
+                &entries[..],
+                Some((first.factor as f32 / 1000.0, first.lastivl.max(1) as f32)),
+            )
+        };
+
+        let day = |e: &RevlogEntry| timing.relative_day(e.id / 1000);
+        let mut reviews = Vec::with_capacity(entries.len());
+        for (i, entry) in entries.iter().enumerate() {
+            let delta_days = match i.checked_sub(1) {
+                Some(prev) => day(entry) - day(entries[prev]),
+                None if sm2_start.is_some() => i32::try_from(entry.lastivl).unwrap_or_default(),
+                None => 0,
+            };
+            reviews.push(Review {
+                ease: Ease::try_from(entry.ease).ok()?,
+                delta_days: u32::try_from(delta_days).unwrap_or_default(),
+            });
+        }
+
+        Some(Self {
+            reviews,
+            sm2_start,
+            last_review: entries.last()?.id / 1000,
+        })
+    }
+
+    pub fn memory_state(&self, fsrs: &Fsrs) -> Option<MemoryState> {
+        let start = self
+            .sm2_start
+            .map(|(factor, ivl)| fsrs.memory_state_from_sm2(factor, ivl, SM2_RETENTION));
+        fsrs.memory_state(&self.reviews, start)
+    }
+}
+
+impl Database {
+    /// Gets the FSRS data that Anki stored for a card.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::{Database, model::CardId};
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let data = db.card_data(CardId::from(1700000000000))?;
+    /// if let Some(memory) = data.memory {
+    ///     println!("stability {}", memory.stability);
+    /// }
+    /// # Ok::<(), rusqlite::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the provided id does not match a card, or if the
+    /// database becomes unavailable.
+    pub fn card_data(&self, id: CardId) -> Result<CardData> {
+        let mut stmt = self.prepare_raw("SELECT data FROM cards WHERE id=?")?;
+        let data: String = stmt.query_row(params![id], |row| row.get(0))?;
+        Ok(CardData::parse(&data))
+    }
+
+    /// Recomputes a card's memory state from its review log, using the FSRS
+    /// parameters of its home deck's preset, and its retrievability as of
+    /// today. Returns `None` if the card has no reviews that FSRS can use.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::{Database, model::CardId};
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// if let Some(memory) = db.compute_memory_state(CardId::from(1700000000000))? {
+    ///     println!("{:.0}% chance of recall", memory.retrievability * 100.0);
+    /// }
+    /// # Ok::<(), rusqlite::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the provided id does not match a card, if its preset
+    /// can't be decoded or has an unsupported number of parameters, or if
+    /// the database becomes unavailable.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn compute_memory_state(&self, id: CardId) -> Result<Option<ComputedMemory>> {
+        let mut stmt = self.prepare_raw(
+            "SELECT CASE WHEN odid = 0 THEN did ELSE odid END FROM cards WHERE id=?",
+        )?;
+        let deck = stmt.query_row(params![id], |row| row.get(0))?;
+        let options = self.deck_options(deck)?;
+        let params = &options.fsrs_params;
+        let fsrs = Fsrs::new(params).ok_or(ParameterCountError(params.len()))?;
+
+        let mut stmt = self.prepare_raw(
+            "SELECT id, ease, type, lastivl, factor FROM revlog WHERE cid=? ORDER BY id",
+        )?;
+        let entries = stmt
+            .query_map(params![id], RevlogEntry::from_row)?
+            .collect::<Result<Vec<_>>>()?;
+
+        let timing = self.timing_at(now_secs())?;
+        let Some(history) = History::new(&entries, &timing) else {
+            return Ok(None);
+        };
+        let Some(state) = history.memory_state(&fsrs) else {
+            return Ok(None);
+        };
+        let elapsed_days = u32::try_from(-timing.relative_day(history.last_review)).unwrap_or(0);
+
+        Ok(Some(ComputedMemory {
+            state,
+            retrievability: fsrs.retrievability(state, elapsed_days as f32),
+            elapsed_days,
+        }))
+    }
+}
@@ -0,0 +1,362 @@
+//! Fitting FSRS parameters to a collection's review log.
+//!
+//! This follows the approach of Anki's optimizer: each card's history since
+//! it was last in learning becomes one training item, and the parameters
+//! are fitted by minimizing the log loss of the predicted retrievability at
+//! each review with Adam. Gradients are estimated numerically, so this is
+//! slower than Anki's, but needs nothing beyond the review log.
+//!
+//! ```rust,no_run
+//! use ankidb::{Database, fsrs::optimizer, query::{self, AnkiExt}};
+//!
+//! let db = Database::open(&"/path/to/collection.anki2")?;
+//! let cards = query::cards()
+//!     .get_cid()
+//!     .join_cards_notes()
+//!     .where_tag("japanese")
+//!     .take();
+//! let items = optimizer::training_items(&db, &cards)?;
+//! if let Some(optimized) = optimizer::optimize(&items) {
+//!     println!("{:?}", optimized.params);
+//!     println!("log loss {:.4}", optimized.evaluation.log_loss);
+//! }
+//! # Ok::<(), rusqlite::Error>(())
+//! ```
+
+use super::{DEFAULT_PARAMETERS, Fsrs, History, Review, RevlogEntry, S_MIN};
+use crate::{
+    Database,
+    model::{CardId, Ease},
+    table::Revlog,
+};
+use rusqlite::Result;
+use sea_query::{Expr, Order, Query, SelectStatement};
+
+const EPOCHS: usize = 5;
+const MIN_STEPS: usize = 100;
+const BATCH_SIZE: usize = 512;
+const LEARNING_RATE: f64 = 0.04;
+const EPSILON: f32 = 1e-3;
+const RMSE_BINS: usize = 20;
+
+/// The range each parameter is kept within, as in fsrs-rs.
+const BOUNDS: [(f32, f32); 21] = [
+    (S_MIN, 100.0),
+    (S_MIN, 100.0),
+    (S_MIN, 100.0),
+    (S_MIN, 100.0),
+    (1.0, 10.0),
+    (0.001, 4.0),
+    (0.001, 4.0),
+    (0.001, 0.75),
+    (0.0, 4.5),
+    (0.0, 0.8),
+    (0.001, 3.5),
+    (0.001, 5.0),
+    (0.001, 0.25),
+    (0.001, 0.9),
+    (0.0, 4.0),
+    (0.0, 1.0),
+    (1.0, 6.0),
+    (0.0, 2.0),
+    (0.0, 2.0),
+    (0.0, 0.8),
+    (0.1, 0.8),
+];
+
+/// A card's reviews, prepared for training.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TrainingItem {
+    pub card: CardId,
+    /// The card's reviews since it was last in learning, with only the
+    /// first review of each day.
+    pub reviews: Vec<Review>,
+}
+
+/// How well a set of parameters predicts the review log.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Evaluation {
+    pub log_loss: f64,
+    /// The root mean square error between predicted and actual retention,
+    /// with reviews binned by their predicted retrievability.
+    pub rmse: f64,
+    /// The number of reviews that were predicted.
+    pub reviews: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Optimized {
+    pub params: Vec<f32>,
+    pub evaluation: Evaluation,
+}
+
+/// Gets a training item for each card selected by `cards`, which should be
+/// a query on the `cards` table that selects only card ids.
+///
+/// Cards whose learning steps aren't in the review log are skipped, since
+/// their memory state at the first review is unknown.
+///
+/// # Errors
+///
+/// This can fail if `cards` isn't a valid query, or if the database becomes
+/// unavailable.
+pub fn training_items(db: &Database, cards: &SelectStatement) -> Result<Vec<TrainingItem>> {
+    let timing = db.timing()?;
+    let (mut stmt, values) = db.prepare(
+        Query::select()
+            .columns([
+                Revlog::Cid,
+                Revlog::Id,
+                Revlog::Ease,
+                Revlog::Type,
+                Revlog::Lastivl,
+                Revlog::Factor,
+            ])
+            .from(Revlog::Table)
+            .and_where(Expr::col(Revlog::Cid).in_subquery(cards.clone()))
+            .order_by(Revlog::Cid, Order::Asc)
+            .order_by(Revlog::Id, Order::Asc),
+    )?;
+    let mut rows = stmt.query(&*values.as_params())?;
+
+    let mut items = Vec::new();
+    let mut card: Option<CardId> = None;
+    let mut entries = Vec::new();
+    let mut finish = |card: Option<CardId>, entries: &mut Vec<RevlogEntry>| {
+        let history = History::new(entries, &timing);
+        entries.clear();
+        let (Some(card), Some(history)) = (card, history) else {
+            return;
+        };
+        if history.sm2_start.is_some() {
+            return;
+        }
+        let reviews = history
+            .reviews
+            .iter()
+            .enumerate()
+            .filter(|(i, r)| *i == 0 || r.delta_days > 0)
+            .map(|(_, r)| *r)
+            .collect::<Vec<_>>();
+        if reviews.len() > 1 {
+            items.push(TrainingItem { card, reviews });
+        }
+    };
+
+    while let Some(row) = rows.next()? {
+        let cid: CardId = row.get(0)?;
+        if card != Some(cid) {
+            finish(card, &mut entries);
+            card = Some(cid);
+        }
+        entries.push(RevlogEntry {
+            id: row.get(1)?,
+            ease: row.get(2)?,
+            kind: row.get(3)?,
+            lastivl: row.get(4)?,
+            factor: row.get(5)?,
+        });
+    }
+    finish(card, &mut entries);
+
+    Ok(items)
+}
+
+/// Calls `f` with the predicted retrievability and outcome of each review
+/// after the first.
+fn predict(fsrs: &Fsrs, item: &TrainingItem, mut f: impl FnMut(f32, bool)) {
+    let Some((first, rest)) = item.reviews.split_first() else {
+        return;
+    };
+    let mut state = fsrs.initial_state(first.ease);
+    for review in rest {
+        #[allow(clippy::cast_precision_loss)]
+        f(
+            fsrs.retrievability(state, review.delta_days as f32),
+            review.ease != Ease::Again,
+        );
+        state = fsrs.next_state(state, review.ease, review.delta_days);
+    }
+}
+
+fn log_loss(r: f32, recalled: bool) -> f64 {
+    let r = f64::from(r).clamp(1e-7, 1.0 - 1e-7);
+    if recalled { -r.ln() } else { -(1.0 - r).ln() }
+}
+
+fn total_loss(w: &[f32; 21], items: &[TrainingItem]) -> f64 {
+    let fsrs = Fsrs { w: *w };
+    let mut loss = 0.0;
+    for item in items {
+        predict(&fsrs, item, |r, recalled| loss += log_loss(r, recalled));
+    }
+    loss
+}
+
+/// Measures how well `fsrs` predicts the outcomes of the given items.
+#[must_use]
+#[allow(clippy::cast_precision_loss)]
+pub fn evaluate(fsrs: &Fsrs, items: &[TrainingItem]) -> Evaluation {
+    let mut loss = 0.0;
+    let mut reviews = 0;
+    let mut bins = [(0.0, 0.0, 0usize); RMSE_BINS];
+    for item in items {
+        predict(fsrs, item, |r, recalled| {
+            loss += log_loss(r, recalled);
+            reviews += 1;
+            #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+            let bin = ((r * RMSE_BINS as f32) as usize).min(RMSE_BINS - 1);
+            bins[bin].0 += f64::from(r);
+            bins[bin].1 += f64::from(u8::from(recalled));
+            bins[bin].2 += 1;
+        });
+    }
+
+    let squared_error = bins
+        .iter()
+        .filter(|(_, _, n)| *n > 0)
+        .map(|(predicted, actual, n)| (predicted - actual).powi(2) / *n as f64)
+        .sum::<f64>();
+    let n = reviews.max(1) as f64;
+
+    Evaluation {
+        log_loss: loss / n,
+        rmse: (squared_error / n).sqrt(),
+        reviews,
+    }
+}
+
+/// Splits items into batches of roughly `BATCH_SIZE` reviews.
+fn batches(items: &[TrainingItem]) -> Vec<&[TrainingItem]> {
+    let mut batches = Vec::new();
+    let (mut start, mut size) = (0, 0);
+    for (i, item) in items.iter().enumerate() {
+        size += item.reviews.len() - 1;
+        if size >= BATCH_SIZE {
+            batches.push(&items[start..=i]);
+            (start, size) = (i + 1, 0);
+        }
+    }
+    if start < items.len() {
+        batches.push(&items[start..]);
+    }
+    batches
+}
+
+/// Fits FSRS parameters to the given items, starting from the defaults.
+/// Returns `None` if there are no reviews to learn from.
+#[must_use]
+#[allow(clippy::cast_precision_loss, clippy::cast_possible_truncation)]
+pub fn optimize(items: &[TrainingItem]) -> Option<Optimized> {
+    let batches = batches(items);
+    let reviews = items.iter().map(|i| i.reviews.len() - 1).sum::<usize>();
+    if reviews == 0 {
+        return None;
+    }
+
+    let epochs = EPOCHS.max(MIN_STEPS.div_ceil(batches.len()));
+    let steps = (epochs * batches.len()) as f64;
+    let (beta1, beta2) = (0.9f64, 0.999f64);
+    let mut w = DEFAULT_PARAMETERS;
+    let mut first_moment = [0.0f64; 21];
+    let mut second_moment = [0.0f64; 21];
+    let mut step = 0;
+
+    for _ in 0..epochs {
+        for batch in &batches {
+            let batch_reviews = batch.iter().map(|i| i.reviews.len() - 1).sum::<usize>();
+            if batch_reviews == 0 {
+                continue;
+            }
+
+            let mut gradient = [0.0f64; 21];
+            for (i, g) in gradient.iter_mut().enumerate() {
+                let (lower, upper) = BOUNDS[i];
+                let mut plus = w;
+                plus[i] = (w[i] + EPSILON).min(upper);
+                let mut minus = w;
+                minus[i] = (w[i] - EPSILON).max(lower);
+                let delta = f64::from(plus[i] - minus[i]);
+                if delta > 0.0 {
+                    *g = (total_loss(&plus, batch) - total_loss(&minus, batch))
+                        / delta
+                        / batch_reviews as f64;
+                }
+            }
+
+            // Adam, with the learning rate annealed along a cosine
+            step += 1;
+            let progress = f64::from(step) / steps;
+            let rate = LEARNING_RATE * 0.5 * (1.0 + (std::f64::consts::PI * progress).cos());
+            for i in 0..21 {
+                first_moment[i] = beta1.mul_add(first_moment[i], (1.0 - beta1) * gradient[i]);
+                second_moment[i] =
+                    beta2.mul_add(second_moment[i], (1.0 - beta2) * gradient[i].powi(2));
+                let m_hat = first_moment[i] / (1.0 - beta1.powi(step));
+                let v_hat = second_moment[i] / (1.0 - beta2.powi(step));
+                let updated = f64::from(w[i]) - rate * m_hat / (v_hat.sqrt() + 1e-8);
+                let (lower, upper) = BOUNDS[i];
+                w[i] = (updated as f32).clamp(lower, upper);
+            }
+        }
+    }
+
+    let fsrs = Fsrs { w };
+    Some(Optimized {
+        params: w.to_vec(),
+        evaluation: evaluate(&fsrs, items),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        query::{self, AnkiExt},
+        testing::Fixture,
+    };
+
+    #[test]
+    fn training_items_for_a_tag() -> Result<()> {
+        let db = Fixture::new()
+            .with_note("Default", "Basic", &["猫", "cat"], &["japanese"])
+            .with_reviews(&[(20, Ease::Good), (19, Ease::Good), (16, Ease::Again)])
+            .with_note("Default", "Basic", &["犬", "dog"], &["japanese"])
+            .with_reviews(&[(30, Ease::Good), (29, Ease::Good), (25, Ease::Good)])
+            .with_note("Default", "Basic", &["chat", "cat"], &["french"])
+            .with_reviews(&[(10, Ease::Good), (9, Ease::Good)])
+            .with_note("Default", "Basic", &["鳥", "bird"], &["japanese"])
+            .build()?;
+
+        // As in the module's example
+        let cards = query::cards()
+            .get_cid()
+            .join_cards_notes()
+            .where_tag("japanese")
+            .take();
+        let items = training_items(&db, &cards)?;
+        assert_eq!(items.len(), 2);
+        assert!(items.iter().all(|item| item.reviews.len() == 3));
+        assert_eq!(
+            items[0].reviews.iter().map(|r| r.ease).collect::<Vec<_>>(),
+            [Ease::Good, Ease::Good, Ease::Again]
+        );
+        assert_eq!(
+            items[0]
+                .reviews
+                .iter()
+                .map(|r| r.delta_days)
+                .collect::<Vec<_>>(),
+            [0, 1, 3]
+        );
+
+        let optimized = optimize(&items).expect("there are reviews to fit");
+        assert_eq!(optimized.params.len(), 21);
+        assert_eq!(optimized.evaluation.reviews, 4);
+        assert!(optimized.evaluation.log_loss.is_finite());
+        Ok(())
+    }
+}
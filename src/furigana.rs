@@ -0,0 +1,102 @@
+//! Anki's `漢字[かんじ]` ruby syntax, as used by the `furigana:`, `kana:`
+//! and `kanji:` template filters.
+//!
+//! A reading applies to the run of text before it, back to the previous
+//! space or tag. A space before that run is a separator, and is removed.
+//!
+//! ```rust
+//! use ankidb::furigana;
+//!
+//! let text = "日本語[にほんご]を 勉強[べんきょう]する";
+//! assert_eq!(furigana::kana(text), "にほんごをべんきょうする");
+//! assert_eq!(furigana::kanji(text), "日本語を勉強する");
+//! assert_eq!(
+//!     furigana::furigana("犬[いぬ]"),
+//!     "<ruby><rb>犬</rb><rt>いぬ</rt></ruby>",
+//! );
+//! ```
+
+/// A `base[reading]` match, with byte offsets into the original text.
+struct Ruby<'a> {
+    start: usize,
+    end: usize,
+    base: &'a str,
+    reading: &'a str,
+}
+
+/// Finds each `base[reading]` pair, along with an optional leading space
+/// that acts as a separator. Readings of the form `[sound:...]` are left
+/// alone since they're media references, and the base never extends across
+/// a field separator, so that this can be run on `Notes::Flds` as a whole.
+fn find(text: &str) -> Vec<Ruby<'_>> {
+    let mut out = Vec::new();
+    let mut last_end = 0;
+    let mut pos = 0;
+    while let Some(open) = text[pos..].find('[').map(|i| i + pos) {
+        let Some(close) = text[open + 1..]
+            .find([']', '\n', '\x1f'])
+            .map(|i| i + open + 1)
+            .filter(|close| text.as_bytes()[*close] == b']' && *close > open + 1)
+        else {
+            pos = open + 1;
+            continue;
+        };
+
+        let base_start = text[last_end..open]
+            .rfind([' ', '>', '\x1f'])
+            .map_or(last_end, |i| i + last_end + 1);
+        let reading = &text[open + 1..close];
+        if base_start == open || reading.starts_with("sound:") {
+            pos = close + 1;
+            continue;
+        }
+
+        let start = if base_start > last_end && text.as_bytes()[base_start - 1] == b' ' {
+            base_start - 1
+        } else {
+            base_start
+        };
+        out.push(Ruby {
+            start,
+            end: close + 1,
+            base: &text[base_start..open],
+            reading,
+        });
+        last_end = close + 1;
+        pos = close + 1;
+    }
+    out
+}
+
+fn replace(text: &str, f: impl Fn(&Ruby) -> String) -> String {
+    let text = text.replace("&nbsp;", " ");
+    let mut out = String::with_capacity(text.len());
+    let mut pos = 0;
+    for ruby in find(&text) {
+        out.push_str(&text[pos..ruby.start]);
+        out.push_str(&f(&ruby));
+        pos = ruby.end;
+    }
+    out.push_str(&text[pos..]);
+    out
+}
+
+/// Converts `漢字[かんじ]` into `<ruby>` markup.
+#[must_use]
+pub fn furigana(text: &str) -> String {
+    replace(text, |r| {
+        format!("<ruby><rb>{}</rb><rt>{}</rt></ruby>", r.base, r.reading)
+    })
+}
+
+/// Keeps only the readings, turning `漢字[かんじ]` into `かんじ`.
+#[must_use]
+pub fn kana(text: &str) -> String {
+    replace(text, |r| r.reading.to_string())
+}
+
+/// Keeps only the base text, turning `漢字[かんじ]` into `漢字`.
+#[must_use]
+pub fn kanji(text: &str) -> String {
+    replace(text, |r| r.base.to_string())
+}
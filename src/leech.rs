@@ -0,0 +1,156 @@
+//! Finding cards that are hard to remember.
+//!
+//! Anki tags a note `leech` when one of its cards lapses as often as its
+//! deck's options allow, but that only happens while answering. This finds
+//! leeches from the cards and review log directly, including cards that
+//! haven't hit the threshold yet but are failing most of their reviews.
+//!
+//! ```rust,no_run
+//! use ankidb::{Database, leech::LeechCriteria};
+//!
+//! let db = Database::open(&"/path/to/collection.anki2")?;
+//! for leech in db.leeches(&LeechCriteria::default())? {
+//!     println!("{} lapses: {}", leech.lapses, leech.fields[0]);
+//! }
+//! # Ok::<(), rusqlite::Error>(())
+//! ```
+
+use crate::{
+    Database,
+    model::{CardId, DeckId, NoteId, parse_fields, parse_tags},
+};
+use rusqlite::{Result, params};
+use std::collections::HashMap;
+
+/// When a card with too few lapses to be a leech still counts as one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LeechCriteria {
+    /// How many days of reviews to look back over, including today.
+    pub days: u32,
+    /// The fewest reviews in that time for retention to count.
+    pub min_reviews: u32,
+    /// Cards whose retention over that time is below this are leeches.
+    pub min_retention: f64,
+}
+
+impl Default for LeechCriteria {
+    /// Cards that failed at least half of 5 or more reviews in the last 30
+    /// days.
+    fn default() -> Self {
+        Self {
+            days: 30,
+            min_reviews: 5,
+            min_retention: 0.5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Leech {
+    pub card: CardId,
+    pub note: NoteId,
+    /// The card's home deck, even if it's in a filtered deck.
+    pub deck: DeckId,
+    pub lapses: u32,
+    /// The home deck's leech threshold, or 0 if leeches are disabled.
+    pub threshold: u32,
+    /// Reviews of the card in the time covered by [`LeechCriteria::days`].
+    pub recent_reviews: u32,
+    /// How many of those reviews were answered with anything but Again.
+    pub recent_passed: u32,
+    pub fields: Vec<String>,
+    pub tags: Vec<String>,
+}
+
+impl Leech {
+    /// Whether the card has lapsed as often as its deck's options allow.
+    #[must_use]
+    pub const fn exceeds_threshold(&self) -> bool {
+        self.threshold > 0 && self.lapses >= self.threshold
+    }
+
+    #[must_use]
+    pub fn recent_retention(&self) -> Option<f64> {
+        (self.recent_reviews > 0)
+            .then(|| f64::from(self.recent_passed) / f64::from(self.recent_reviews))
+    }
+}
+
+impl Database {
+    /// Lists cards that have lapsed at least as often as their home deck's
+    /// leech threshold, or that have poor recent retention according to
+    /// `criteria`, ordered by card id.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::{Database, leech::LeechCriteria};
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let leeches = db.leeches(&LeechCriteria::default())?;
+    /// let untagged = leeches.iter().filter(|l| !l.tags.iter().any(|t| t == "leech"));
+    /// println!("{} leeches aren't tagged", untagged.count());
+    /// # Ok::<(), rusqlite::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if a card's home deck doesn't exist or has invalid
+    /// options, or if the database becomes unavailable.
+    pub fn leeches(&self, criteria: &LeechCriteria) -> Result<Vec<Leech>> {
+        let timing = self.timing()?;
+        let cutoff = (timing.next_day_at - i64::from(criteria.days) * 86_400) * 1000;
+
+        let mut stmt = self.prepare_raw(
+            "SELECT c.id, c.nid, CASE WHEN c.odid != 0 THEN c.odid ELSE c.did END, c.lapses,
+                    n.flds, n.tags, COALESCE(r.total, 0), COALESCE(r.passed, 0)
+             FROM cards c
+             JOIN notes n ON n.id = c.nid
+             LEFT JOIN (
+                 SELECT cid, COUNT(*) AS total, SUM(ease > 1) AS passed
+                 FROM revlog
+                 WHERE id >= ?1 AND type = 1
+                 GROUP BY cid
+             ) r ON r.cid = c.id
+             WHERE c.lapses > 0 OR r.total >= ?2
+             ORDER BY c.id",
+        )?;
+        let mut rows = stmt.query(params![cutoff, criteria.min_reviews])?;
+
+        let mut thresholds = HashMap::new();
+        let mut leeches = Vec::new();
+        while let Some(row) = rows.next()? {
+            let deck: DeckId = row.get(2)?;
+            let threshold = if let Some(threshold) = thresholds.get(&deck) {
+                *threshold
+            } else {
+                let threshold = self.deck_options(deck)?.leech_threshold;
+                thresholds.insert(deck, threshold);
+                threshold
+            };
+
+            let leech = Leech {
+                card: row.get(0)?,
+                note: row.get(1)?,
+                deck,
+                lapses: row.get(3)?,
+                threshold,
+                recent_reviews: row.get(6)?,
+                recent_passed: row.get(7)?,
+                fields: parse_fields(&row.get::<_, String>(4)?)
+                    .map(String::from)
+                    .collect(),
+                tags: parse_tags(&row.get::<_, String>(5)?)
+                    .map(String::from)
+                    .collect(),
+            };
+            let poor_retention = leech.recent_reviews >= criteria.min_reviews
+                && leech
+                    .recent_retention()
+                    .is_some_and(|r| r < criteria.min_retention);
+            if leech.exceeds_threshold() || poor_retention {
+                leeches.push(leech);
+            }
+        }
+        Ok(leeches)
+    }
+}
@@ -13,11 +13,35 @@
 #![warn(clippy::cargo)]
 #![warn(clippy::pedantic)]
 #![warn(clippy::nursery)]
+// sea-query, serde and postgres-types pull in both syn 2 and syn 3 through
+// their derive macros, which nothing on our side can unify.
+#![allow(clippy::multiple_crate_versions)]
 
+pub mod analytics;
+pub mod check;
+pub mod cloze;
 mod database;
+pub mod deck;
+pub mod duplicates;
+pub mod fsrs;
+pub mod furigana;
+pub mod leech;
+pub mod media;
 pub mod model;
+pub mod notetype;
+pub mod package;
+mod proto;
 pub mod query;
+pub mod scheduler;
+pub mod search;
+pub mod simulator;
+mod stock;
 pub mod table;
+pub mod template;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod text;
+pub mod timing;
 
 pub use database::Database;
 pub use model::{parse_fields, parse_tags};
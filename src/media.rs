@@ -0,0 +1,400 @@
+//! The media folder that sits beside a collection.
+//!
+//! Anki keeps a collection's images, sounds and other files in a folder
+//! named after it: `collection.media` for `collection.anki2`. It also keeps
+//! an index of the folder in `collection.media.db2` for syncing, which it
+//! brings up to date by rescanning the folder whenever the folder has
+//! changed, so files added here are picked up the next time Anki syncs.
+//!
+//! Filenames follow
+//! <https://github.com/ankitects/anki/tree/30ae9f7c5408420c8f347073a9e5e62756a6d7cb/rslib/src/media/files.rs>.
+
+mod check;
+
+pub use check::{Error, MediaCheck};
+
+use crate::{
+    Database,
+    model::{NoteId, NotetypeId, parse_fields},
+    proto::Message,
+    text::{latex_references, media_references},
+};
+use sha1::{Digest, Sha1};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Write,
+    io,
+    path::{Path, PathBuf},
+};
+use unicode_normalization::{UnicodeNormalization, is_nfc};
+
+/// The longest filename, in bytes, that Anki will give a media file.
+pub const MAX_FILENAME_LENGTH: usize = 120;
+
+/// Characters that are stripped from filenames, along with ASCII control
+/// characters, because they aren't allowed in filenames on some platforms or
+/// would break `[sound:...]` references.
+const ILLEGAL_CHARS: &[char] = &['[', ']', '<', '>', ':', '"', '/', '?', '*', '^', '\\', '|'];
+
+fn is_illegal(c: char) -> bool {
+    ILLEGAL_CHARS.contains(&c) || c.is_ascii_control()
+}
+
+/// Characters that Windows doesn't allow at the end of a filename.
+const WINDOWS_TRAILING_CHARS: [char; 2] = ['.', ' '];
+
+/// Names that Windows reserves for devices, with or without an extension.
+const WINDOWS_DEVICE_NAMES: &[&str] = &[
+    "con", "prn", "aux", "nul", "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8",
+    "com9", "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+
+/// The media folder for the collection at `collection`.
+///
+/// ```
+/// use ankidb::media::folder_for;
+/// use std::path::Path;
+///
+/// assert_eq!(
+///     folder_for(&"/path/to/collection.anki2"),
+///     Path::new("/path/to/collection.media"),
+/// );
+/// ```
+pub fn folder_for<P: AsRef<Path>>(collection: &P) -> PathBuf {
+    collection.as_ref().with_extension("media")
+}
+
+/// The media index for the collection at `collection`, which Anki uses to
+/// track changes to the folder for syncing.
+pub fn index_for<P: AsRef<Path>>(collection: &P) -> PathBuf {
+    collection.as_ref().with_extension("media.db2")
+}
+
+/// The SHA-1 of a file's contents, which Anki uses to tell media files
+/// apart.
+#[must_use]
+pub fn checksum(data: &[u8]) -> [u8; 20] {
+    Sha1::digest(data).into()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut out, b| {
+        let _ = write!(out, "{b:02x}");
+        out
+    })
+}
+
+/// Makes a filename safe to use for media, the way Anki does when adding
+/// files.
+///
+/// Names are normalized to NFC, characters that aren't allowed on some
+/// platforms are removed, Windows device names and names ending in a dot or
+/// space get a `_` added, and names longer than [`MAX_FILENAME_LENGTH`] are
+/// truncated, keeping the extension.
+///
+/// ```
+/// use ankidb::media::normalize_filename;
+///
+/// assert_eq!(normalize_filename("cat.jpg"), "cat.jpg");
+/// assert_eq!(normalize_filename("what?.mp3"), "what.mp3");
+/// assert_eq!(normalize_filename("con.txt"), "con_.txt");
+/// assert_eq!(normalize_filename("notes."), "notes._");
+/// assert_eq!(normalize_filename("tab\tbell\u{7}.ogg"), "tabbell.ogg");
+/// assert_eq!(normalize_filename("cafe\u{301}.png"), "caf\u{e9}.png");
+/// ```
+#[must_use]
+pub fn normalize_filename(name: &str) -> Cow<'_, str> {
+    let mut output = Cow::Borrowed(name);
+
+    if !is_nfc(&output) {
+        output = Cow::Owned(output.nfc().collect());
+    }
+
+    if output.contains(is_illegal) {
+        output = Cow::Owned(output.replace(is_illegal, ""));
+    }
+
+    let (stem, extension) = output.split_once('.').unwrap_or((&output, ""));
+    if WINDOWS_DEVICE_NAMES.contains(&stem.to_ascii_lowercase().as_str()) {
+        output = Cow::Owned(if output.contains('.') {
+            format!("{stem}_.{extension}")
+        } else {
+            format!("{stem}_")
+        });
+    }
+
+    if output.ends_with(WINDOWS_TRAILING_CHARS) {
+        output = Cow::Owned(format!("{output}_"));
+    }
+
+    if output.len() > MAX_FILENAME_LENGTH {
+        output = Cow::Owned(truncate(&output, MAX_FILENAME_LENGTH));
+    }
+
+    output
+}
+
+/// Shortens a filename's stem so the whole name fits in `max` bytes.
+fn truncate(name: &str, max: usize) -> String {
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{extension}")),
+        _ => (name, String::new()),
+    };
+    let mut end = max.saturating_sub(extension.len()).min(stem.len());
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    let mut truncated = format!("{}{extension}", &stem[..end]);
+    if truncated.ends_with(WINDOWS_TRAILING_CHARS) {
+        truncated.push('_');
+    }
+    truncated
+}
+
+/// Adds the hash of a file's contents to its name, which is how Anki
+/// renames a file that would otherwise overwrite a different one:
+/// `cat.jpg` becomes `cat-<sha1>.jpg`.
+fn with_hash(name: &str, data: &[u8]) -> String {
+    let hash = hex(&checksum(data));
+    let named = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => format!("{stem}-{hash}.{extension}"),
+        _ => format!("{name}-{hash}"),
+    };
+    if named.len() > MAX_FILENAME_LENGTH {
+        // Shorten the original stem rather than cutting into the hash
+        let over = named.len() - MAX_FILENAME_LENGTH;
+        with_hash(&truncate(name, name.len().saturating_sub(over)), data)
+    } else {
+        named
+    }
+}
+
+/// Whether a name refers to a file directly inside a folder, rather than
+/// being a path like `/etc/passwd` or `../secret`.
+pub(crate) fn is_plain_filename(name: &str) -> bool {
+    Path::new(name).file_name().and_then(|n| n.to_str()) == Some(name)
+}
+
+/// A collection's media folder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaFolder {
+    path: PathBuf,
+}
+
+impl MediaFolder {
+    pub fn new<P: AsRef<Path>>(path: &P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The names of the files in the folder, sorted. Subfolders, hidden
+    /// files, `thumbs.db`, and names that aren't valid UTF-8 are skipped,
+    /// as Anki skips them. A folder that doesn't exist yet has no files.
+    ///
+    /// ```rust,no_run
+    /// use ankidb::media::MediaFolder;
+    ///
+    /// let folder = MediaFolder::new(&"/path/to/collection.media");
+    /// for name in folder.files()? {
+    ///     println!("{name}");
+    /// }
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the folder can't be read.
+    pub fn files(&self) -> io::Result<Vec<String>> {
+        let entries = match std::fs::read_dir(&self.path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            entries => entries?,
+        };
+        let mut files = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                continue;
+            }
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            if name.starts_with('.') || name.eq_ignore_ascii_case("thumbs.db") {
+                continue;
+            }
+            files.push(name);
+        }
+        files.sort_unstable();
+        Ok(files)
+    }
+
+    /// The path of a file in the folder. Only plain filenames are allowed,
+    /// since names come from note fields, and a reference like
+    /// `../../.ssh/id_rsa` mustn't reach outside the folder.
+    fn file_path(&self, name: &str) -> io::Result<PathBuf> {
+        if !is_plain_filename(name) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "media filename isn't a plain filename",
+            ));
+        }
+        Ok(self.path.join(name))
+    }
+
+    /// Reads a file from the folder.
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the file doesn't exist or can't be read, or if the
+    /// name has a directory in it.
+    pub fn read(&self, name: &str) -> io::Result<Vec<u8>> {
+        std::fs::read(self.file_path(name)?)
+    }
+
+    /// The SHA-1 of a file in the folder.
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the file doesn't exist or can't be read, or if the
+    /// name has a directory in it.
+    pub fn checksum(&self, name: &str) -> io::Result<[u8; 20]> {
+        let mut hasher = Sha1::new();
+        io::copy(
+            &mut std::fs::File::open(self.file_path(name)?)?,
+            &mut hasher,
+        )?;
+        Ok(hasher.finalize().into())
+    }
+
+    /// Adds a file to the folder, returning the name it was saved under.
+    ///
+    /// The name is normalized with [`normalize_filename`]. If a file with
+    /// that name already exists with the same contents, nothing is
+    /// written; if its contents differ, the new file's name gets its hash
+    /// added, as Anki does. The folder is created if needed.
+    ///
+    /// ```rust,no_run
+    /// use ankidb::media::MediaFolder;
+    ///
+    /// let folder = MediaFolder::new(&"/path/to/collection.media");
+    /// let name = folder.add_file("cat?.jpg", &std::fs::read("/tmp/cat.jpg")?)?;
+    /// assert_eq!(name, "cat.jpg");
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the name is empty once normalized, or if the folder
+    /// can't be written.
+    pub fn add_file(&self, name: &str, data: &[u8]) -> io::Result<String> {
+        Ok(self.add_file_reporting(name, data)?.0)
+    }
+
+    /// Like [`Self::add_file`], but also says whether the file was written,
+    /// rather than already being in the folder.
+    pub(crate) fn add_file_reporting(&self, name: &str, data: &[u8]) -> io::Result<(String, bool)> {
+        let mut name = normalize_filename(name).into_owned();
+        if name.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "empty media filename",
+            ));
+        }
+
+        std::fs::create_dir_all(&self.path)?;
+        if self.path.join(&name).exists() {
+            if self.checksum(&name)? == checksum(data) {
+                return Ok((name, false));
+            }
+            name = with_hash(&name, data);
+            // Only a file with the same contents could have this name
+            if self.path.join(&name).exists() {
+                return Ok((name, false));
+            }
+        }
+        std::fs::write(self.path.join(&name), data)?;
+        Ok((name, true))
+    }
+}
+
+impl Database {
+    /// The collection's media folder, or `None` for databases that aren't
+    /// backed by a file.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::Database;
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let folder = db.media_folder().unwrap();
+    /// assert_eq!(folder.path(), std::path::Path::new("/path/to/collection.media"));
+    /// # Ok::<(), rusqlite::Error>(())
+    /// ```
+    #[must_use]
+    pub fn media_folder(&self) -> Option<MediaFolder> {
+        self.path().map(|path| MediaFolder::new(&folder_for(&path)))
+    }
+}
+
+/// The media files that notes refer to, with the notes that refer to them.
+/// This includes the images Anki generates for LaTeX.
+pub(crate) fn note_references(db: &Database) -> rusqlite::Result<BTreeMap<String, Vec<NoteId>>> {
+    let mut svg = HashMap::new();
+    let mut stmt = db.prepare_raw("SELECT id, config FROM notetypes")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let id: NotetypeId = row.get(0)?;
+        svg.insert(id, row.get::<_, Message>(1)?.varint(7) != 0);
+    }
+
+    let mut references = BTreeMap::<String, Vec<NoteId>>::new();
+    let mut stmt = db.prepare_raw("SELECT id, mid, flds FROM notes ORDER BY id")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let id: NoteId = row.get(0)?;
+        let svg = svg.get(&row.get(1)?).copied().unwrap_or_default();
+        let mut names = BTreeSet::new();
+        for field in parse_fields(row.get_ref(2)?.as_str()?) {
+            names.extend(media_references(field));
+            if field.contains('[') {
+                names.extend(latex_references(field, svg));
+            }
+        }
+        for name in names {
+            references.entry(name).or_default().push(id);
+        }
+    }
+    Ok(references)
+}
+
+/// The files among `files` that start with `_` and are mentioned in a
+/// notetype's templates or styling. Anki treats these as used, since that's
+/// how fonts and scripts are shared between cards.
+pub(crate) fn template_references<'a>(
+    db: &Database,
+    files: &'a [String],
+) -> rusqlite::Result<Vec<&'a str>> {
+    let mut text = String::new();
+    let mut stmt = db.prepare_raw("SELECT config FROM notetypes")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        text.push_str(&row.get::<_, Message>(0)?.string(3));
+    }
+    let mut stmt = db.prepare_raw("SELECT config FROM templates")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let config: Message = row.get(0)?;
+        text.push_str(&config.string(1));
+        text.push_str(&config.string(2));
+    }
+
+    Ok(files
+        .iter()
+        .map(String::as_str)
+        .filter(|name| name.starts_with('_') && text.contains(name))
+        .collect())
+}
@@ -0,0 +1,167 @@
+//! Finding unused and missing media, like Anki's Check Media.
+
+use super::{MediaFolder, normalize_filename, note_references, template_references, with_hash};
+use crate::{Database, model::NoteId};
+use std::{collections::BTreeSet, io, path::PathBuf};
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Database(rusqlite::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{e}"),
+            Self::Database(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Database(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Self::Database(e)
+    }
+}
+
+/// The results of [`Database::check_media`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MediaCheck {
+    /// Files in the media folder that no note or notetype refers to.
+    pub unused: Vec<String>,
+    /// Files that notes refer to, but which aren't in the media folder.
+    pub missing: Vec<String>,
+    /// The notes that refer to missing files.
+    pub missing_notes: Vec<NoteId>,
+    /// Files whose names Anki would change when adding them, as `(name,
+    /// normalized)`. See [`super::normalize_filename`].
+    pub needs_normalization: Vec<(String, String)>,
+    /// How many unused files were moved to the trash.
+    pub trashed: usize,
+}
+
+/// Where Anki moves deleted media: `media.trash`, beside the media folder.
+fn trash_folder(folder: &MediaFolder) -> PathBuf {
+    folder.path().with_file_name("media.trash")
+}
+
+/// Moves a file to the trash. A file already in the trash with the same
+/// name is kept, and the new one gets its hash added unless they're the
+/// same.
+fn trash(folder: &MediaFolder, name: &str) -> io::Result<()> {
+    let trash = trash_folder(folder);
+    std::fs::create_dir_all(&trash)?;
+    let source = folder.path().join(name);
+    let mut target = trash.join(name);
+    if target.exists() {
+        let data = std::fs::read(&source)?;
+        if std::fs::read(&target)? == data {
+            return std::fs::remove_file(source);
+        }
+        target = trash.join(with_hash(name, &data));
+    }
+    std::fs::rename(source, target)
+}
+
+impl Database {
+    /// Checks the media folder against the files that notes refer to, as
+    /// Anki's Check Media does.
+    ///
+    /// References come from media tags and `[sound:...]` in fields, and
+    /// from the images generated for LaTeX. Files starting with `_` count
+    /// as used if a notetype's templates or styling mention them. Names are
+    /// compared after normalization, so a file whose name only differs from
+    /// a reference by its Unicode normalization is neither unused nor
+    /// missing, but is reported in `needs_normalization`.
+    ///
+    /// With `trash_unused`, unused files are moved to a `media.trash`
+    /// folder beside the media folder, where Anki keeps deleted media.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::Database;
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let check = db.check_media(false)?;
+    /// for name in &check.missing {
+    ///     println!("missing: {name}");
+    /// }
+    /// println!("{} unused files", check.unused.len());
+    /// # Ok::<(), ankidb::media::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the media folder can't be read, if a file can't be
+    /// moved to the trash, or if the database becomes unavailable.
+    pub fn check_media(&self, trash_unused: bool) -> Result<MediaCheck, Error> {
+        let folder = self.media_folder();
+        let files = match &folder {
+            Some(folder) => folder.files()?,
+            None => Vec::new(),
+        };
+        let references = note_references(self)?;
+
+        let mut check = MediaCheck::default();
+        let mut on_disk = BTreeSet::new();
+        for name in &files {
+            let normalized = normalize_filename(name);
+            if normalized != name.as_str() {
+                check
+                    .needs_normalization
+                    .push((name.clone(), normalized.to_string()));
+            }
+            on_disk.insert(normalized.into_owned());
+        }
+
+        let mut missing_notes = BTreeSet::new();
+        for (name, notes) in &references {
+            if !on_disk.contains(&*normalize_filename(name)) {
+                check.missing.push(name.clone());
+                missing_notes.extend(notes);
+            }
+        }
+        check.missing_notes = missing_notes.into_iter().collect();
+
+        let templates = template_references(self, &files)?
+            .into_iter()
+            .collect::<BTreeSet<_>>();
+        let in_notes = references
+            .keys()
+            .map(|name| normalize_filename(name))
+            .collect::<BTreeSet<_>>();
+        check.unused = files
+            .iter()
+            .filter(|name| {
+                !templates.contains(name.as_str()) && !in_notes.contains(&normalize_filename(name))
+            })
+            .cloned()
+            .collect();
+
+        if trash_unused {
+            if let Some(folder) = &folder {
+                for name in &check.unused {
+                    trash(folder, name)?;
+                    check.trashed += 1;
+                }
+            }
+        }
+
+        Ok(check)
+    }
+}
@@ -74,6 +74,15 @@ id_wrapper!(DeckId);
 pub struct NotetypeId(i64);
 id_wrapper!(NotetypeId);
 
+#[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "postgres-types",
+    derive(postgres_types::FromSql, postgres_types::ToSql)
+)]
+pub struct DeckConfigId(i64);
+id_wrapper!(DeckConfigId);
+
 #[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq, Hash)]
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[cfg_attr(
@@ -101,12 +110,67 @@ id_wrapper!(CardId);
 pub struct RevlogId(i64);
 id_wrapper!(RevlogId);
 
-#[must_use]
+/// The kind of a notetype, from `Notetypes::Config`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum NotetypeKind {
+    #[default]
+    Normal,
+    Cloze,
+}
+
+impl From<u64> for NotetypeKind {
+    fn from(value: u64) -> Self {
+        match value {
+            1 => Self::Cloze,
+            _ => Self::Normal,
+        }
+    }
+}
+
+/// What a row in the `graves` table records the deletion of, from
+/// `Graves::Type`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum GraveKind {
+    Card = 0,
+    Note = 1,
+    Deck = 2,
+}
+
+/// The button a card was answered with, from `Revlog::Ease`.
+#[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Ease {
+    Again = 1,
+    Hard = 2,
+    Good = 3,
+    Easy = 4,
+}
+
+impl TryFrom<i64> for Ease {
+    type Error = i64;
+
+    fn try_from(value: i64) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Self::Again),
+            2 => Ok(Self::Hard),
+            3 => Ok(Self::Good),
+            4 => Ok(Self::Easy),
+            _ => Err(value),
+        }
+    }
+}
+
+impl From<Ease> for i64 {
+    fn from(ease: Ease) -> Self {
+        ease as Self
+    }
+}
+
 pub fn parse_fields(fields: &str) -> impl Iterator<Item = &str> {
     fields.split('\x1F')
 }
 
-#[must_use]
 pub fn parse_tags(tags: &str) -> impl Iterator<Item = &str> {
     tags.split(' ').filter(|s| !s.is_empty())
 }
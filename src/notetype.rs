@@ -0,0 +1,232 @@
+//! Creating and editing notetypes, and changing the notetypes of notes.
+//!
+//! Changing notetypes follows
+//! <https://github.com/ankitects/anki/tree/30ae9f7c5408420c8f347073a9e5e62756a6d7cb/rslib/src/notetype/notetypechange.rs>.
+//! Like in Anki, that and most edits to a notetype's fields and templates
+//! alter the collection's schema, so the next sync has to be a full sync.
+
+mod edit;
+
+use crate::{
+    Database,
+    model::{CardId, NoteId, NotetypeId, NotetypeKind, parse_fields},
+    proto::Message,
+    text::{field_checksum, strip_html_preserving_media_filenames},
+    timing::{now_millis, now_secs},
+};
+use rusqlite::params;
+use std::collections::HashSet;
+
+#[derive(Debug)]
+pub enum Error {
+    Database(rusqlite::Error),
+    /// The request doesn't fit the notetypes involved, such as a field map
+    /// of the wrong length or a field name that's already used.
+    Invalid(&'static str),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Database(e) => write!(f, "{e}"),
+            Self::Invalid(reason) => write!(f, "invalid notetype change: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Database(e) => Some(e),
+            Self::Invalid(_) => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Self::Database(e)
+    }
+}
+
+/// Marks the collection's schema as changed, which makes the next sync a
+/// full sync.
+pub(crate) fn bump_schema(db: &Database) -> rusqlite::Result<()> {
+    let now = now_millis();
+    db.prepare_cached_raw("UPDATE col SET scm = ?, mod = ?")?
+        .execute(params![now, now])?;
+    Ok(())
+}
+
+/// Inverts a template map, giving the new ordinal for each old ordinal, or
+/// `None` if its cards are deleted.
+fn template_ords(
+    template_map: &[Option<usize>],
+    old_templates: usize,
+    templates: usize,
+) -> Result<Vec<Option<usize>>, Error> {
+    if template_map.len() != templates {
+        return Err(Error::Invalid(
+            "template map doesn't match the notetype's templates",
+        ));
+    }
+    let mut ords = vec![None; old_templates];
+    for (new, &old) in template_map.iter().enumerate() {
+        let Some(old) = old else {
+            continue;
+        };
+        match ords.get_mut(old) {
+            None => return Err(Error::Invalid("template map refers to a missing template")),
+            Some(Some(_)) => return Err(Error::Invalid("template is mapped more than once")),
+            Some(ord) => *ord = Some(new),
+        }
+    }
+    Ok(ords)
+}
+
+impl Database {
+    /// The notetype's config, from `Notetypes::Config`.
+    fn notetype_config(&self, id: NotetypeId) -> rusqlite::Result<Message> {
+        self.prepare_cached_raw("SELECT config FROM notetypes WHERE id = ?")?
+            .query_row(params![id], |row| row.get(0))
+    }
+
+    fn template_count(&self, id: NotetypeId) -> rusqlite::Result<usize> {
+        self.prepare_cached_raw("SELECT COUNT(*) FROM templates WHERE ntid = ?")?
+            .query_row(params![id], |row| row.get(0))
+    }
+
+    /// Moves notes to another notetype, as Anki's Change Notetype does.
+    ///
+    /// The notes must all have the same notetype. `field_map` has an entry
+    /// for each of the new notetype's fields, giving the index of the old
+    /// field whose content it gets, or `None` to leave it empty. Likewise,
+    /// `template_map` has an entry for each of the new notetype's
+    /// templates, giving the old template whose cards move to it. Cards of
+    /// old templates that aren't mapped are deleted.
+    ///
+    /// `template_map` is ignored when either notetype is a cloze notetype,
+    /// since cloze cards are numbered by their clozes: they keep their
+    /// ordinals, and cards whose ordinal has no template in a normal
+    /// notetype are deleted.
+    ///
+    /// Deleted cards are recorded in the `graves` table. The collection's
+    /// schema is marked as changed, which forces a full sync, as Anki
+    /// requires.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::Database;
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let reversed = db.id_for_notetype("Basic (and reversed card)")?;
+    /// let notes = [1_700_000_000_000.into()];
+    /// // Keep Front and Back, and move the cards to Card 1, adding no cards
+    /// // for Card 2
+    /// db.change_notetype(&notes, reversed, &[Some(0), Some(1)], &[Some(0), None])?;
+    /// # Ok::<(), ankidb::notetype::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if a note or the new notetype doesn't exist, if the
+    /// notes have different notetypes, if the maps don't match the
+    /// notetypes, or if the database becomes unavailable. Nothing is changed
+    /// if it fails.
+    pub fn change_notetype(
+        &self,
+        notes: &[NoteId],
+        notetype: NotetypeId,
+        field_map: &[Option<usize>],
+        template_map: &[Option<usize>],
+    ) -> Result<(), Error> {
+        let mut old = HashSet::new();
+        for &note in notes {
+            let mid: NotetypeId = self
+                .prepare_cached_raw("SELECT mid FROM notes WHERE id = ?")?
+                .query_row(params![note], |row| row.get(0))?;
+            old.insert(mid);
+        }
+        if old.len() > 1 {
+            return Err(Error::Invalid("notes have different notetypes"));
+        }
+        let Some(&old) = old.iter().next() else {
+            return Ok(());
+        };
+
+        let config = self.notetype_config(notetype)?;
+        let sort_field = usize::try_from(config.uint32(2)).unwrap_or_default();
+        let kind = NotetypeKind::from(config.varint(1));
+        let old_kind = self.notetype_kind(old)?;
+
+        let old_fields = self.fields_for_notetype(old)?.len();
+        if field_map.len() != self.fields_for_notetype(notetype)?.len() {
+            return Err(Error::Invalid(
+                "field map doesn't match the notetype's fields",
+            ));
+        }
+        if field_map.iter().flatten().any(|&i| i >= old_fields) {
+            return Err(Error::Invalid("field map refers to a missing field"));
+        }
+
+        let templates = self.template_count(notetype)?;
+        let ords = if kind == NotetypeKind::Cloze || old_kind == NotetypeKind::Cloze {
+            None
+        } else {
+            Some(template_ords(
+                template_map,
+                self.template_count(old)?,
+                templates,
+            )?)
+        };
+
+        Ok(self.transact(|db| {
+            let now = now_secs();
+            for &note in notes {
+                let fields: String = db
+                    .prepare_cached_raw("SELECT flds FROM notes WHERE id = ?")?
+                    .query_row(params![note], |row| row.get(0))?;
+                let fields = parse_fields(&fields).collect::<Vec<_>>();
+                let fields = field_map
+                    .iter()
+                    .map(|old| old.and_then(|i| fields.get(i).copied()).unwrap_or_default())
+                    .collect::<Vec<_>>();
+                db.prepare_cached_raw(
+                    "UPDATE notes SET mid = ?, flds = ?, sfld = ?, csum = ?, mod = ?, usn = -1
+                     WHERE id = ?",
+                )?
+                .execute(params![
+                    notetype,
+                    fields.join("\x1f"),
+                    strip_html_preserving_media_filenames(
+                        fields.get(sort_field).copied().unwrap_or_default()
+                    ),
+                    field_checksum(fields.first().copied().unwrap_or_default()),
+                    now,
+                    note
+                ])?;
+
+                let cards = db
+                    .prepare_cached_raw("SELECT id, ord FROM cards WHERE nid = ?")?
+                    .query_map(params![note], |row| {
+                        Ok((row.get::<_, CardId>(0)?, row.get::<_, usize>(1)?))
+                    })?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                for (card, ord) in cards {
+                    let new_ord = match &ords {
+                        Some(ords) => ords.get(ord).copied().flatten(),
+                        None if kind == NotetypeKind::Normal && ord >= templates => None,
+                        None => Some(ord),
+                    };
+                    if let Some(new_ord) = new_ord {
+                        db.prepare_cached_raw(
+                            "UPDATE cards SET ord = ?, mod = ?, usn = -1 WHERE id = ?",
+                        )?
+                        .execute(params![new_ord, now, card])?;
+                    } else {
+                        db.delete_card(card)?;
+                    }
+                }
+            }
+            bump_schema(db)
+        })?)
+    }
+}
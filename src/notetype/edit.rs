@@ -0,0 +1,592 @@
+//! Creating notetypes, and editing their fields and templates.
+
+use super::{Error, bump_schema};
+use crate::{
+    Database,
+    model::{CardId, DeckId, NoteId, NotetypeId, NotetypeKind, parse_fields},
+    proto::Message,
+    stock, template,
+    text::{field_checksum, field_is_empty, strip_html_preserving_media_filenames},
+    timing::now_secs,
+};
+use rusqlite::{OptionalExtension, params};
+use std::collections::HashSet;
+use unicase::UniCase;
+
+/// Anki's rules for field names, which templates have to be able to refer
+/// to.
+fn check_field_name(name: &str) -> Result<(), Error> {
+    if name.is_empty()
+        || name != name.trim()
+        || name.contains([':', '"', '{', '}'])
+        || name.starts_with(['#', '/', '^'])
+    {
+        return Err(Error::Invalid(
+            "field names can't be empty, contain :, \", { or }, or start with #, / or ^",
+        ));
+    }
+    Ok(())
+}
+
+/// Whether `name` is already used, ignoring case like the `unicase`
+/// collation does.
+fn is_taken<'a>(mut names: impl Iterator<Item = &'a str>, name: &str) -> bool {
+    names.any(|other| UniCase::new(other) == UniCase::new(name))
+}
+
+fn position(names: &[String], name: &str, missing: &'static str) -> Result<usize, Error> {
+    names
+        .iter()
+        .position(|other| other == name)
+        .ok_or(Error::Invalid(missing))
+}
+
+impl Database {
+    /// The notetype's fields and their configs, in order.
+    fn field_configs(&self, id: NotetypeId) -> rusqlite::Result<Vec<(String, Message)>> {
+        self.prepare_cached_raw("SELECT name, config FROM fields WHERE ntid = ? ORDER BY ord")?
+            .query_map(params![id], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect()
+    }
+
+    /// The notetype's templates and their configs, in order.
+    fn template_configs(&self, id: NotetypeId) -> rusqlite::Result<Vec<(String, Message)>> {
+        self.prepare_cached_raw("SELECT name, config FROM templates WHERE ntid = ? ORDER BY ord")?
+            .query_map(params![id], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect()
+    }
+
+    /// Replaces the notetype's templates. They're rewritten as a whole, since
+    /// removing one renumbers the rest.
+    fn write_templates(
+        &self,
+        id: NotetypeId,
+        templates: &[(String, Message)],
+    ) -> rusqlite::Result<()> {
+        let now = now_secs();
+        self.prepare_cached_raw("DELETE FROM templates WHERE ntid = ?")?
+            .execute(params![id])?;
+        for (ord, (name, config)) in templates.iter().enumerate() {
+            self.prepare_cached_raw("INSERT INTO templates VALUES (?, ?, ?, ?, -1, ?)")?
+                .execute(params![id, ord, name, now, config])?;
+        }
+        Ok(())
+    }
+
+    fn touch_notetype(&self, id: NotetypeId) -> rusqlite::Result<()> {
+        self.prepare_cached_raw("UPDATE notetypes SET mtime_secs = ?, usn = -1 WHERE id = ?")?
+            .execute(params![now_secs(), id])?;
+        Ok(())
+    }
+
+    /// Applies `f` to the question and answer formats of each of the
+    /// notetype's templates, including the ones for the browser.
+    fn edit_templates(&self, id: NotetypeId, f: impl Fn(&str) -> String) -> rusqlite::Result<()> {
+        let mut templates = self.template_configs(id)?;
+        for (_, config) in &mut templates {
+            for field in 1..=4 {
+                if config.has(field) {
+                    config.set_string(field, &f(&config.string(field)));
+                }
+            }
+        }
+        self.write_templates(id, &templates)
+    }
+
+    fn write_field_rows(
+        &self,
+        id: NotetypeId,
+        fields: &[(String, Message)],
+    ) -> rusqlite::Result<()> {
+        self.prepare_cached_raw("DELETE FROM fields WHERE ntid = ?")?
+            .execute(params![id])?;
+        for (ord, (name, config)) in fields.iter().enumerate() {
+            self.prepare_cached_raw("INSERT INTO fields VALUES (?, ?, ?, ?)")?
+                .execute(params![id, ord, name, config])?;
+        }
+        Ok(())
+    }
+
+    /// The index of the field that notes are sorted by in the browser.
+    fn sort_field(&self, id: NotetypeId) -> rusqlite::Result<usize> {
+        Ok(usize::try_from(self.notetype_config(id)?.uint32(2)).unwrap_or_default())
+    }
+
+    /// Replaces the notetype's fields, and moves the contents of each note's
+    /// fields to match. `map` has an entry for each new field, giving the
+    /// old field whose contents it takes, or `None` for an empty one.
+    fn write_fields(
+        &self,
+        id: NotetypeId,
+        fields: &[(String, Message)],
+        map: &[Option<usize>],
+        sort_field: usize,
+    ) -> rusqlite::Result<()> {
+        self.write_field_rows(id, fields)?;
+        let mut config = self.notetype_config(id)?;
+        config.set_uint32(2, u32::try_from(sort_field).unwrap_or_default());
+        self.prepare_cached_raw("UPDATE notetypes SET config = ? WHERE id = ?")?
+            .execute(params![config, id])?;
+
+        let now = now_secs();
+        let notes = self
+            .prepare_cached_raw("SELECT id, flds FROM notes WHERE mid = ?")?
+            .query_map(params![id], |row| {
+                Ok((row.get::<_, NoteId>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for (note, fields) in notes {
+            let fields = parse_fields(&fields).collect::<Vec<_>>();
+            let fields = map
+                .iter()
+                .map(|old| old.and_then(|i| fields.get(i).copied()).unwrap_or_default())
+                .collect::<Vec<_>>();
+            self.prepare_cached_raw(
+                "UPDATE notes SET flds = ?, sfld = ?, csum = ?, mod = ?, usn = -1 WHERE id = ?",
+            )?
+            .execute(params![
+                fields.join("\x1f"),
+                strip_html_preserving_media_filenames(
+                    fields.get(sort_field).copied().unwrap_or_default()
+                ),
+                field_checksum(fields.first().copied().unwrap_or_default()),
+                now,
+                note
+            ])?;
+        }
+        self.touch_notetype(id)?;
+        bump_schema(self)
+    }
+
+    /// Where the next new card goes in the new queue, from the `nextPos`
+    /// config key, which is advanced.
+    fn next_position(&self) -> rusqlite::Result<i64> {
+        let position = self.config_i64("nextPos")?.unwrap_or(1);
+        self.set_config_json("nextPos", &(position + 1).to_string())?;
+        Ok(position)
+    }
+
+    /// Adds the cards that notes of a normal notetype are missing for the
+    /// template at `ord`, where its question shows one of the note's
+    /// fields. Returns how many were added.
+    ///
+    /// Like in Anki, a new card goes in the deck of the note's other cards
+    /// and shares their position in the new queue.
+    fn generate_cards(&self, id: NotetypeId, ord: usize) -> rusqlite::Result<usize> {
+        let (_, config) = &self.template_configs(id)?[ord];
+        let question = config.string(1);
+        let names = self.fields_for_notetype(id)?;
+        let notes = self
+            .prepare_raw(
+                "SELECT id, flds FROM notes WHERE mid = ?
+                 AND id NOT IN (SELECT nid FROM cards WHERE ord = ?)",
+            )?
+            .query_map(params![id, ord], |row| {
+                Ok((row.get::<_, NoteId>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let now = now_secs();
+        let mut card = self.next_id("cards")?;
+        let mut added = 0;
+        for (note, fields) in notes {
+            let nonempty = names
+                .iter()
+                .zip(parse_fields(&fields))
+                .filter(|(_, field)| !field_is_empty(field))
+                .map(|(name, _)| name.as_str())
+                .collect::<HashSet<_>>();
+            if !template::renders_with_fields(&question, &nonempty) {
+                continue;
+            }
+            let sibling: Option<(DeckId, Option<i64>)> = self
+                .prepare_cached_raw(
+                    "SELECT CASE WHEN odid = 0 THEN did ELSE odid END,
+                            CASE WHEN type = 0 THEN CASE WHEN odid = 0 THEN due ELSE odue END END
+                     FROM cards WHERE nid = ? ORDER BY type != 0, ord LIMIT 1",
+                )?
+                .query_row(params![note], |row| Ok((row.get(0)?, row.get(1)?)))
+                .optional()?;
+            let deck = sibling.map_or_else(|| DeckId::from(1), |(deck, _)| deck);
+            let due = match sibling.and_then(|(_, due)| due) {
+                Some(due) => due,
+                None => self.next_position()?,
+            };
+            self.prepare_cached_raw(
+                "INSERT INTO cards VALUES (?, ?, ?, ?, ?, -1, 0, 0, ?, 0, 0, 0, 0, 0, 0, 0, 0, '')",
+            )?
+            .execute(params![CardId::from(card), note, deck, ord, now, due])?;
+            card += 1;
+            added += 1;
+        }
+        Ok(added)
+    }
+
+    /// Creates a notetype, with Anki's default styling and field options.
+    /// Templates are given as `(name, question, answer)`, and a cloze
+    /// notetype has exactly one.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::Database;
+    /// use ankidb::model::NotetypeKind;
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let id = db.add_notetype(
+    ///     "Vocab",
+    ///     NotetypeKind::Normal,
+    ///     &["Word", "Meaning"],
+    ///     &[("Recognition", "{{Word}}", "{{FrontSide}}<hr id=answer>{{Meaning}}")],
+    /// )?;
+    /// # Ok::<(), ankidb::notetype::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if a notetype with the name exists, if there are no
+    /// fields or templates, if names are repeated or a field name isn't
+    /// allowed, or if the database becomes unavailable.
+    pub fn add_notetype(
+        &self,
+        name: &str,
+        kind: NotetypeKind,
+        fields: &[&str],
+        templates: &[(&str, &str, &str)],
+    ) -> Result<NotetypeId, Error> {
+        if name.trim().is_empty() {
+            return Err(Error::Invalid("notetype names can't be empty"));
+        }
+        if is_taken(self.notetypes()?.iter().map(|(_, n)| n.as_str()), name) {
+            return Err(Error::Invalid("a notetype with that name already exists"));
+        }
+        if fields.is_empty() || templates.is_empty() {
+            return Err(Error::Invalid("notetypes need a field and a template"));
+        }
+        if kind == NotetypeKind::Cloze && templates.len() > 1 {
+            return Err(Error::Invalid("cloze notetypes have a single template"));
+        }
+        for (i, field) in fields.iter().enumerate() {
+            check_field_name(field)?;
+            if is_taken(fields[..i].iter().copied(), field) {
+                return Err(Error::Invalid("field names are repeated"));
+            }
+        }
+        for (i, (template, ..)) in templates.iter().enumerate() {
+            if template.trim().is_empty() {
+                return Err(Error::Invalid("template names can't be empty"));
+            }
+            if is_taken(templates[..i].iter().map(|(n, ..)| *n), template) {
+                return Err(Error::Invalid("template names are repeated"));
+            }
+        }
+
+        Ok(self.transact(|db| {
+            let id = NotetypeId::from(db.next_id("notetypes")?);
+            let config = stock::notetype_config(u64::from(kind == NotetypeKind::Cloze));
+            db.prepare_cached_raw("INSERT INTO notetypes VALUES (?, ?, ?, -1, ?)")?
+                .execute(params![id, name, now_secs(), config])?;
+            let fields = fields
+                .iter()
+                .map(|name| ((*name).to_string(), stock::field_config()))
+                .collect::<Vec<_>>();
+            db.write_field_rows(id, &fields)?;
+            let templates = templates
+                .iter()
+                .map(|(name, q, a)| ((*name).to_string(), stock::template_config(q, a)))
+                .collect::<Vec<_>>();
+            db.write_templates(id, &templates)?;
+            Ok(id)
+        })?)
+    }
+
+    /// Adds a field to the end of a notetype's fields, which is empty in
+    /// existing notes.
+    ///
+    /// Like all changes to a notetype's fields, this alters the collection's
+    /// schema, which forces a full sync.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::Database;
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let basic = db.id_for_notetype("Basic")?;
+    /// db.add_field(basic, "Notes")?;
+    /// # Ok::<(), ankidb::notetype::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the notetype doesn't exist, if it already has the
+    /// field or the name isn't allowed, or if the database becomes
+    /// unavailable.
+    pub fn add_field(&self, notetype: NotetypeId, name: &str) -> Result<(), Error> {
+        check_field_name(name)?;
+        let mut fields = self.field_configs(notetype)?;
+        if is_taken(fields.iter().map(|(n, _)| n.as_str()), name) {
+            return Err(Error::Invalid("the notetype already has that field"));
+        }
+        let sort_field = self.sort_field(notetype)?;
+        let map = (0..fields.len())
+            .map(Some)
+            .chain([None])
+            .collect::<Vec<_>>();
+        fields.push((name.to_string(), stock::field_config()));
+        Ok(self.transact(|db| db.write_fields(notetype, &fields, &map, sort_field))?)
+    }
+
+    /// Renames a field, along with the references to it in the notetype's
+    /// templates.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::Database;
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let basic = db.id_for_notetype("Basic")?;
+    /// db.rename_field(basic, "Front", "Question")?;
+    /// # Ok::<(), ankidb::notetype::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the notetype or field doesn't exist, if another
+    /// field has the new name or it isn't allowed, or if the database
+    /// becomes unavailable.
+    pub fn rename_field(&self, notetype: NotetypeId, old: &str, new: &str) -> Result<(), Error> {
+        check_field_name(new)?;
+        let names = self.fields_for_notetype(notetype)?;
+        let ord = position(&names, old, "the notetype has no such field")?;
+        let others = names
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i != ord)
+            .map(|(_, n)| n.as_str());
+        if is_taken(others, new) {
+            return Err(Error::Invalid("the notetype already has that field"));
+        }
+        Ok(self.transact(|db| {
+            db.prepare_cached_raw("UPDATE fields SET name = ? WHERE ntid = ? AND ord = ?")?
+                .execute(params![new, notetype, ord])?;
+            db.edit_templates(notetype, |text| template::rename_field(text, old, new))?;
+            db.touch_notetype(notetype)?;
+            bump_schema(db)
+        })?)
+    }
+
+    /// Puts a notetype's fields in a new order, given by their names, and
+    /// moves the contents of each note's fields to match. The sort field
+    /// stays the same field.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::Database;
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let basic = db.id_for_notetype("Basic")?;
+    /// db.reorder_fields(basic, &["Back", "Front"])?;
+    /// # Ok::<(), ankidb::notetype::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the notetype doesn't exist, if `order` doesn't name
+    /// each of its fields once, or if the database becomes unavailable.
+    pub fn reorder_fields(&self, notetype: NotetypeId, order: &[&str]) -> Result<(), Error> {
+        let old = self.field_configs(notetype)?;
+        let names = old.iter().map(|(n, _)| n.clone()).collect::<Vec<_>>();
+        if order.len() != names.len() {
+            return Err(Error::Invalid("order doesn't match the notetype's fields"));
+        }
+        let map = order
+            .iter()
+            .map(|name| position(&names, name, "order names a missing field"))
+            .collect::<Result<Vec<_>, _>>()?;
+        if map.iter().collect::<HashSet<_>>().len() != map.len() {
+            return Err(Error::Invalid("order names a field more than once"));
+        }
+        let sort_field = self.sort_field(notetype)?;
+        let sort_field = map
+            .iter()
+            .position(|&i| i == sort_field)
+            .unwrap_or_default();
+        let fields = map.iter().map(|&i| old[i].clone()).collect::<Vec<_>>();
+        let map = map.into_iter().map(Some).collect::<Vec<_>>();
+        Ok(self.transact(|db| db.write_fields(notetype, &fields, &map, sort_field))?)
+    }
+
+    /// Removes a field, deleting its contents from every note and its
+    /// references from the notetype's templates. Template sections that
+    /// need the field to be filled in are removed, while those for when it's
+    /// empty are kept, so cards look the same apart from the field itself.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::Database;
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let id = db.id_for_notetype("Vocab")?;
+    /// db.remove_field(id, "Hint")?;
+    /// # Ok::<(), ankidb::notetype::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the notetype or field doesn't exist, if it's the
+    /// notetype's only field, or if the database becomes unavailable.
+    pub fn remove_field(&self, notetype: NotetypeId, name: &str) -> Result<(), Error> {
+        let mut fields = self.field_configs(notetype)?;
+        let names = fields.iter().map(|(n, _)| n.clone()).collect::<Vec<_>>();
+        let ord = position(&names, name, "the notetype has no such field")?;
+        if fields.len() == 1 {
+            return Err(Error::Invalid("notetypes need at least one field"));
+        }
+        let sort_field = self.sort_field(notetype)?;
+        let sort_field = match sort_field.cmp(&ord) {
+            std::cmp::Ordering::Less => sort_field,
+            std::cmp::Ordering::Equal => 0,
+            std::cmp::Ordering::Greater => sort_field - 1,
+        };
+        fields.remove(ord);
+        let map = (0..names.len())
+            .filter(|&i| i != ord)
+            .map(Some)
+            .collect::<Vec<_>>();
+        Ok(self.transact(|db| {
+            db.edit_templates(notetype, |text| template::remove_field(text, name))?;
+            db.write_fields(notetype, &fields, &map, sort_field)
+        })?)
+    }
+
+    /// Adds a template to a normal notetype, and generates its cards for
+    /// existing notes whose fields fill in its question. Returns how many
+    /// cards were added.
+    ///
+    /// Like in Anki, adding a template alters the collection's schema,
+    /// which forces a full sync.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::Database;
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let basic = db.id_for_notetype("Basic")?;
+    /// let added = db.add_template(basic, "Card 2", "{{Back}}", "{{FrontSide}}<hr id=answer>{{Front}}")?;
+    /// println!("{added} cards added");
+    /// # Ok::<(), ankidb::notetype::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the notetype doesn't exist or is a cloze notetype,
+    /// if it already has a template with the name, or if the database
+    /// becomes unavailable.
+    pub fn add_template(
+        &self,
+        notetype: NotetypeId,
+        name: &str,
+        question: &str,
+        answer: &str,
+    ) -> Result<usize, Error> {
+        if self.notetype_kind(notetype)? == NotetypeKind::Cloze {
+            return Err(Error::Invalid("cloze notetypes have a single template"));
+        }
+        if name.trim().is_empty() {
+            return Err(Error::Invalid("template names can't be empty"));
+        }
+        let mut templates = self.template_configs(notetype)?;
+        if is_taken(templates.iter().map(|(n, _)| n.as_str()), name) {
+            return Err(Error::Invalid("the notetype already has that template"));
+        }
+        templates.push((name.to_string(), stock::template_config(question, answer)));
+        Ok(self.transact(|db| {
+            db.write_templates(notetype, &templates)?;
+            db.touch_notetype(notetype)?;
+            bump_schema(db)?;
+            db.generate_cards(notetype, templates.len() - 1)
+        })?)
+    }
+
+    /// Changes a template's question and answer. For normal notetypes,
+    /// notes that now fill in the question get cards for it, and how many
+    /// were added is returned. Cards that would now be blank are kept, as
+    /// in Anki, where Empty Cards removes them.
+    ///
+    /// Unlike other changes to notetypes, this doesn't force a full sync.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::Database;
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let basic = db.id_for_notetype("Basic")?;
+    /// db.update_template(basic, "Card 1", "<b>{{Front}}</b>", "{{FrontSide}}<hr id=answer>{{Back}}")?;
+    /// # Ok::<(), ankidb::notetype::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the notetype or template doesn't exist, or if the
+    /// database becomes unavailable.
+    pub fn update_template(
+        &self,
+        notetype: NotetypeId,
+        name: &str,
+        question: &str,
+        answer: &str,
+    ) -> Result<usize, Error> {
+        let mut templates = self.template_configs(notetype)?;
+        let names = templates.iter().map(|(n, _)| n.clone()).collect::<Vec<_>>();
+        let ord = position(&names, name, "the notetype has no such template")?;
+        let kind = self.notetype_kind(notetype)?;
+        templates[ord]
+            .1
+            .set_string(1, question)
+            .set_string(2, answer);
+        Ok(self.transact(|db| {
+            db.write_templates(notetype, &templates)?;
+            db.touch_notetype(notetype)?;
+            match kind {
+                NotetypeKind::Normal => db.generate_cards(notetype, ord),
+                NotetypeKind::Cloze => Ok(0),
+            }
+        })?)
+    }
+
+    /// Removes a template, deleting its cards and recording them in the
+    /// `graves` table. Later templates move down an ordinal, along with their
+    /// cards. Returns how many cards were deleted.
+    ///
+    /// Like in Anki, removing a template alters the collection's schema,
+    /// which forces a full sync.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::Database;
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let reversed = db.id_for_notetype("Basic (and reversed card)")?;
+    /// let deleted = db.remove_template(reversed, "Card 2")?;
+    /// println!("{deleted} cards deleted");
+    /// # Ok::<(), ankidb::notetype::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the notetype or template doesn't exist, if it's the
+    /// notetype's only template, or if the database becomes unavailable.
+    pub fn remove_template(&self, notetype: NotetypeId, name: &str) -> Result<usize, Error> {
+        let mut templates = self.template_configs(notetype)?;
+        let names = templates.iter().map(|(n, _)| n.clone()).collect::<Vec<_>>();
+        let ord = position(&names, name, "the notetype has no such template")?;
+        if templates.len() == 1 {
+            return Err(Error::Invalid("notetypes need at least one template"));
+        }
+        templates.remove(ord);
+        Ok(self.transact(|db| {
+            let cards = db
+                .prepare_raw(
+                    "SELECT id FROM cards WHERE ord = ?
+                     AND nid IN (SELECT id FROM notes WHERE mid = ?)",
+                )?
+                .query_map(params![ord, notetype], |row| row.get::<_, CardId>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            for &card in &cards {
+                db.delete_card(card)?;
+            }
+            db.prepare_raw(
+                "UPDATE cards SET ord = ord - 1, mod = ?, usn = -1 WHERE ord > ?
+                 AND nid IN (SELECT id FROM notes WHERE mid = ?)",
+            )?
+            .execute(params![now_secs(), ord, notetype])?;
+            db.write_templates(notetype, &templates)?;
+            db.touch_notetype(notetype)?;
+            bump_schema(db)?;
+            Ok(cards.len())
+        })?)
+    }
+}
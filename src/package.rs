@@ -0,0 +1,313 @@
+//! Reading the `.apkg` and `.colpkg` files that Anki imports and exports.
+//!
+//! A package is a zip archive holding a collection and its media files. The
+//! collection is unpacked to a temporary directory, where it can be queried
+//! like any other, and is deleted along with the [`Package`].
+//! [`Database::export_apkg`] writes packages, and [`Database::import_apkg`]
+//! merges them into a collection.
+//!
+//! ```rust,no_run
+//! use ankidb::{package::Package, query::{self, AnkiExt}};
+//!
+//! let mut package = Package::open(&"/path/to/shared.apkg")?;
+//! let (mut stmt, bind) = package.database().prepare(query::notes().count_star())?;
+//! let notes: i64 = stmt.query_row(&*bind.as_params(), |row| row.get(0))?;
+//! drop(stmt);
+//! println!("{notes} notes");
+//! for file in package.media() {
+//!     let file = file?;
+//!     println!("{}: {} bytes", file.name, file.data.len());
+//! }
+//! # Ok::<(), ankidb::package::Error>(())
+//! ```
+
+mod export;
+mod import;
+mod upgrade;
+
+pub use export::{ExportOptions, Exported, Selection};
+pub use import::Imported;
+
+use crate::{
+    Database,
+    proto::{DecodeError, Message},
+};
+use std::{collections::HashMap, fs::File, io::Read, path::Path};
+use tempfile::TempDir;
+use zip::{ZipArchive, result::ZipError};
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Zip(ZipError),
+    Database(rusqlite::Error),
+    /// The package has no collection, or its metadata or media list can't
+    /// be read.
+    Invalid(&'static str),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{e}"),
+            Self::Zip(e) => write!(f, "{e}"),
+            Self::Database(e) => write!(f, "{e}"),
+            Self::Invalid(reason) => write!(f, "invalid package: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Zip(e) => Some(e),
+            Self::Database(e) => Some(e),
+            Self::Invalid(_) => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<ZipError> for Error {
+    fn from(e: ZipError) -> Self {
+        Self::Zip(e)
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Self::Database(e)
+    }
+}
+
+impl From<DecodeError> for Error {
+    fn from(_: DecodeError) -> Self {
+        Self::Invalid("invalid protobuf message")
+    }
+}
+
+/// The layout of a package, which depends on the version of Anki that
+/// exported it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Version {
+    /// A schema 11 `collection.anki2`, with a JSON media list.
+    Legacy1,
+    /// A schema 11 `collection.anki21`, with a JSON media list.
+    Legacy2,
+    /// A zstd-compressed `collection.anki21b` with the current schema, and
+    /// a protobuf media list. Used since Anki 2.1.50.
+    Latest,
+}
+
+/// The `Version` enum of Anki's `PackageMetadata`.
+const META_VERSION_LATEST: u64 = 3;
+
+/// A media file in a package.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MediaFile {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+/// A media file's name, and the name of its entry in the archive.
+struct MediaEntry {
+    name: String,
+    zip_name: String,
+}
+
+pub struct Package {
+    // Declared before `dir` so the database is closed before its directory
+    // is removed
+    database: Database,
+    version: Version,
+    archive: ZipArchive<File>,
+    media: Vec<MediaEntry>,
+    dir: TempDir,
+}
+
+impl Package {
+    /// Opens a package, unpacking its collection to a temporary directory.
+    ///
+    /// Packages exported for older versions of Anki hold a schema 11
+    /// collection, which keeps notetypes, decks and their options as JSON
+    /// in the `col` table. Those are upgraded to the current schema as
+    /// they're unpacked, so they can be queried like any other.
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the file can't be read or isn't a zip archive, if it
+    /// doesn't contain a collection, if its media list or legacy collection
+    /// is malformed, or if the temporary directory can't be written.
+    pub fn open<P: AsRef<Path>>(path: &P) -> Result<Self, Error> {
+        let mut archive = ZipArchive::new(File::open(path)?)?;
+
+        let version = if read_entry(&mut archive, "meta")?
+            .map(|meta| Message::decode(&meta))
+            .transpose()?
+            .is_some_and(|meta| meta.varint(1) >= META_VERSION_LATEST)
+        {
+            Version::Latest
+        } else if archive.by_name("collection.anki21").is_ok() {
+            Version::Legacy2
+        } else {
+            Version::Legacy1
+        };
+
+        let dir = tempfile::tempdir()?;
+        let collection = dir.path().join("collection.anki2");
+        {
+            let name = match version {
+                Version::Legacy1 => "collection.anki2",
+                Version::Legacy2 => "collection.anki21",
+                Version::Latest => "collection.anki21b",
+            };
+            let mut entry = match archive.by_name(name) {
+                Err(ZipError::FileNotFound) => return Err(Error::Invalid("missing collection")),
+                entry => entry?,
+            };
+            let mut out = File::create(&collection)?;
+            if version == Version::Latest {
+                zstd::stream::copy_decode(&mut entry, &mut out)?;
+            } else {
+                std::io::copy(&mut entry, &mut out)?;
+            }
+        }
+
+        let media = match read_entry(&mut archive, "media")? {
+            None => Vec::new(),
+            Some(data) if version == Version::Latest => media_entries(&zstd::decode_all(&*data)?)?,
+            Some(data) => legacy_media_entries(&data)?,
+        };
+
+        let database = Database::open(&collection)?;
+        if version != Version::Latest {
+            upgrade::upgrade(&database)?;
+        }
+
+        Ok(Self {
+            database,
+            version,
+            archive,
+            media,
+            dir,
+        })
+    }
+
+    #[must_use]
+    pub const fn database(&self) -> &Database {
+        &self.database
+    }
+
+    #[must_use]
+    pub const fn version(&self) -> Version {
+        self.version
+    }
+
+    /// The path of the unpacked collection, which is removed when the
+    /// package is dropped.
+    #[must_use]
+    pub fn collection_path(&self) -> std::path::PathBuf {
+        self.dir.path().join("collection.anki2")
+    }
+
+    /// The names of the package's media files, in the order [`Self::media`]
+    /// reads them.
+    pub fn media_names(&self) -> impl Iterator<Item = &str> {
+        self.media.iter().map(|m| m.name.as_str())
+    }
+
+    /// Reads each media file from the archive in turn.
+    pub const fn media(&mut self) -> Media<'_> {
+        Media {
+            package: self,
+            next: 0,
+        }
+    }
+
+    fn read_media(&mut self, index: usize) -> Result<MediaFile, Error> {
+        let entry = &self.media[index];
+        let data = read_entry(&mut self.archive, &entry.zip_name)?
+            .ok_or(Error::Invalid("missing media file"))?;
+        let data = if self.version == Version::Latest {
+            zstd::decode_all(&*data)?
+        } else {
+            data
+        };
+        Ok(MediaFile {
+            name: entry.name.clone(),
+            data,
+        })
+    }
+}
+
+/// An iterator over a package's media files, from [`Package::media`].
+pub struct Media<'a> {
+    package: &'a mut Package,
+    next: usize,
+}
+
+impl Iterator for Media<'_> {
+    type Item = Result<MediaFile, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next >= self.package.media.len() {
+            return None;
+        }
+        self.next += 1;
+        Some(self.package.read_media(self.next - 1))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.package.media.len() - self.next;
+        (remaining, Some(remaining))
+    }
+}
+
+/// Reads a whole entry from the archive, if it exists.
+fn read_entry(archive: &mut ZipArchive<File>, name: &str) -> Result<Option<Vec<u8>>, Error> {
+    let mut entry = match archive.by_name(name) {
+        Err(ZipError::FileNotFound) => return Ok(None),
+        entry => entry?,
+    };
+    let mut data = Vec::new();
+    entry.read_to_end(&mut data)?;
+    Ok(Some(data))
+}
+
+/// Parses Anki's `MediaEntries` message, whose entries are stored in the
+/// archive by their index unless they came from a legacy package.
+fn media_entries(data: &[u8]) -> Result<Vec<MediaEntry>, Error> {
+    Ok(Message::decode(data)?
+        .messages(1)?
+        .into_iter()
+        .enumerate()
+        .map(|(index, entry)| MediaEntry {
+            name: entry.string(1),
+            zip_name: if entry.has(255) {
+                entry.uint32(255).to_string()
+            } else {
+                index.to_string()
+            },
+        })
+        .collect())
+}
+
+/// Parses the legacy JSON media list, which maps archive names to filenames.
+fn legacy_media_entries(data: &[u8]) -> Result<Vec<MediaEntry>, Error> {
+    let map: HashMap<String, String> =
+        serde_json::from_slice(data).map_err(|_| Error::Invalid("invalid media list"))?;
+    let mut entries = map
+        .into_iter()
+        .map(|(zip_name, name)| MediaEntry { name, zip_name })
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|e| (e.zip_name.parse::<u64>().ok(), e.zip_name.clone()));
+    Ok(entries)
+}
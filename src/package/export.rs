@@ -0,0 +1,450 @@
+//! Writing `.apkg` files in the layout Anki has used since 2.1.50.
+
+use super::{Error, META_VERSION_LATEST};
+use crate::{
+    Database,
+    fsrs::CardData,
+    media::{MediaFolder, note_references, template_references},
+    model::{CardId, DeckConfigId, DeckId, NoteId, NotetypeId, parse_tags},
+    proto::Message,
+};
+use rusqlite::{Params, params, params_from_iter, types::Value};
+use sha1::{Digest, Sha1};
+use std::{
+    collections::{BTreeSet, HashSet},
+    fs::File,
+    io::Write,
+    path::Path,
+};
+use zip::{CompressionMethod, ZipWriter, write::FileOptions};
+
+/// What to export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Selection<'a> {
+    /// These notes, with all of their cards.
+    Notes(&'a [NoteId]),
+    /// The cards in a deck and its subdecks, with their notes. Cards that
+    /// are in a filtered deck but came from this deck are included.
+    Deck(DeckId),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ExportOptions {
+    /// Keep the cards' scheduling and review history. Otherwise, cards are
+    /// reset to new and the review log is left out, as for a shared deck.
+    pub with_scheduling: bool,
+    /// Include the media files that the notes and their notetypes refer to.
+    pub with_media: bool,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            with_scheduling: false,
+            with_media: true,
+        }
+    }
+}
+
+/// What was written to a package.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Exported {
+    pub notes: usize,
+    pub cards: usize,
+    pub media: usize,
+    /// Media files that were referenced but aren't in the media folder,
+    /// including references that aren't plain filenames, like
+    /// `../secret.txt`.
+    pub missing_media: Vec<String>,
+}
+
+/// Runs each statement against `db`.
+fn execute(db: &Database, statements: &[&str]) -> rusqlite::Result<()> {
+    for sql in statements {
+        db.prepare_raw(sql)?.execute([])?;
+    }
+    Ok(())
+}
+
+/// Copies the rows of `table` selected by `condition` from `from` into
+/// `to`, replacing any rows they conflict with.
+fn copy_rows<P: Params>(
+    from: &Database,
+    to: &Database,
+    table: &str,
+    condition: &str,
+    params: P,
+) -> rusqlite::Result<()> {
+    let mut select =
+        from.prepare_cached_raw(&format!("SELECT * FROM {table} WHERE {condition}"))?;
+    let columns = select
+        .column_names()
+        .into_iter()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    let mut insert = to.prepare_cached_raw(&format!(
+        "INSERT OR REPLACE INTO {table} ({}) VALUES ({})",
+        columns.join(", "),
+        vec!["?"; columns.len()].join(", ")
+    ))?;
+    let mut rows = select.query(params)?;
+    while let Some(row) = rows.next()? {
+        let values = (0..columns.len())
+            .map(|i| row.get::<_, Value>(i))
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        insert.execute(params_from_iter(values))?;
+    }
+    Ok(())
+}
+
+/// Copies the selected cards and notes into a new collection, along with
+/// their notetypes, and the decks, deck options and tags they might need.
+/// The collection's timing is copied too, so that due days still line up.
+fn copy_selection(
+    from: &Database,
+    copy: &Database,
+    cards: &[CardId],
+    notes: &[NoteId],
+    with_scheduling: bool,
+) -> rusqlite::Result<()> {
+    // Only the notetypes in use are kept, so the stock ones go
+    execute(
+        copy,
+        &[
+            "DELETE FROM notetypes",
+            "DELETE FROM fields",
+            "DELETE FROM templates",
+        ],
+    )?;
+
+    for &id in notes {
+        copy_rows(from, copy, "notes", "id = ?", params![id])?;
+    }
+    for &id in cards {
+        copy_rows(from, copy, "cards", "id = ?", params![id])?;
+        if with_scheduling {
+            copy_rows(from, copy, "revlog", "cid = ?", params![id])?;
+        }
+    }
+    let notetypes = copy
+        .prepare_raw("SELECT DISTINCT mid FROM notes ORDER BY mid")?
+        .query_map([], |row| row.get::<_, NotetypeId>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    for &id in &notetypes {
+        copy_rows(from, copy, "notetypes", "id = ?", params![id])?;
+        copy_rows(from, copy, "fields", "ntid = ?", params![id])?;
+        copy_rows(from, copy, "templates", "ntid = ?", params![id])?;
+    }
+    if let Some(id) = notetypes.first() {
+        copy.set_config_json("curModel", &id.to_string())?;
+    }
+    for table in ["decks", "deck_config", "tags"] {
+        copy_rows(from, copy, table, "1", [])?;
+    }
+
+    let crt: i64 = from
+        .prepare_raw("SELECT crt FROM col")?
+        .query_row([], |row| row.get(0))?;
+    copy.prepare_raw("UPDATE col SET crt = ?")?
+        .execute(params![crt])?;
+    for key in ["creationOffset", "localOffset", "rollover"] {
+        copy.prepare_raw("DELETE FROM config WHERE key = ?")?
+            .execute(params![key])?;
+        copy_rows(from, copy, "config", "key = ?", params![key])?;
+    }
+    Ok(())
+}
+
+/// Removes the decks, deck options and tags that the exported cards and
+/// notes don't need.
+fn prune(copy: &Database) -> rusqlite::Result<()> {
+    copy.return_all_cards()?;
+    execute(
+        copy,
+        &[
+            // Keep the default deck, and each deck with cards or descendants
+            // with cards
+            "DELETE FROM decks
+             WHERE id != 1
+               AND NOT EXISTS (
+                   SELECT 1 FROM cards c JOIN decks d ON d.id = c.did
+                   WHERE d.id = decks.id
+                      OR substr(d.name, 1, length(decks.name) + 1) = decks.name || char(31))",
+        ],
+    )?;
+
+    let mut configs = HashSet::from([DeckConfigId::from(1)]);
+    let mut stmt = copy.prepare_raw("SELECT kind FROM decks")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let kind: Message = row.get(0)?;
+        if kind.has(1) {
+            configs.insert(DeckConfigId::from(kind.message(1)?.int64(1)));
+        }
+    }
+    let mut delete = copy.prepare_raw("DELETE FROM deck_config WHERE id = ?")?;
+    for id in copy
+        .prepare_raw("SELECT id FROM deck_config")?
+        .query_map([], |row| row.get::<_, DeckConfigId>(0))?
+    {
+        let id = id?;
+        if !configs.contains(&id) {
+            delete.execute(params![id])?;
+        }
+    }
+
+    // Tags are registered along with their parents, like `a` for `a::b`
+    let mut tags = HashSet::new();
+    let mut stmt = copy.prepare_raw("SELECT tags FROM notes")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        for tag in parse_tags(row.get_ref(0)?.as_str()?) {
+            let tag = tag.to_lowercase();
+            let mut end = tag.len();
+            tags.insert(tag.clone());
+            while let Some(parent) = tag[..end].rfind("::") {
+                tags.insert(tag[..parent].to_string());
+                end = parent;
+            }
+        }
+    }
+    let mut delete = copy.prepare_raw("DELETE FROM tags WHERE tag = ?")?;
+    for tag in copy
+        .prepare_raw("SELECT tag FROM tags")?
+        .query_map([], |row| row.get::<_, String>(0))?
+    {
+        let tag = tag?;
+        if !tags.contains(&tag.to_lowercase()) {
+            delete.execute(params![tag])?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Resets every card to new, keeping new cards' positions and putting other
+/// cards back where they were first added if that's known, or at the end.
+fn reset_scheduling(copy: &Database) -> rusqlite::Result<()> {
+    let mut next_position: i64 = copy
+        .prepare_raw("SELECT COALESCE(MAX(due), 0) + 1 FROM cards WHERE type = 0")?
+        .query_row([], |row| row.get(0))?;
+
+    let mut stmt =
+        copy.prepare_raw("SELECT id, data FROM cards WHERE type != 0 ORDER BY nid, ord")?;
+    let mut update = copy.prepare_raw("UPDATE cards SET due = ? WHERE id = ?")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let id: CardId = row.get(0)?;
+        let data: String = row.get(1)?;
+        let position = CardData::parse(&data).original_position.map_or_else(
+            || {
+                next_position += 1;
+                next_position - 1
+            },
+            i64::from,
+        );
+        update.execute(params![position, id])?;
+    }
+
+    execute(
+        copy,
+        &[
+            "UPDATE cards
+             SET type = 0, queue = 0, ivl = 0, factor = 0, reps = 0, lapses = 0, left = 0,
+                 data = ''",
+            "DELETE FROM revlog",
+        ],
+    )
+}
+
+/// Finds the media that the exported notes and notetypes refer to. Files
+/// starting with `_` are included if a notetype's templates or styling
+/// mention them, as Anki does for fonts and scripts.
+fn media_names(copy: &Database, folder: &MediaFolder) -> rusqlite::Result<BTreeSet<String>> {
+    let mut names = note_references(copy)?.into_keys().collect::<BTreeSet<_>>();
+    let files = folder.files().unwrap_or_default();
+    names.extend(
+        template_references(copy, &files)?
+            .into_iter()
+            .map(String::from),
+    );
+    Ok(names)
+}
+
+impl Database {
+    fn export_ids(&self, selection: Selection) -> rusqlite::Result<(Vec<CardId>, Vec<NoteId>)> {
+        let mut cards = Vec::new();
+        let mut notes = Vec::new();
+        match selection {
+            Selection::Notes(ids) => {
+                let mut stmt = self.prepare_cached_raw("SELECT id FROM cards WHERE nid = ?")?;
+                for id in ids {
+                    for card in stmt.query_map(params![id], |row| row.get(0))? {
+                        cards.push(card?);
+                    }
+                }
+                notes.extend_from_slice(ids);
+            }
+            Selection::Deck(id) => {
+                let decks = self.deck_and_children(id)?;
+                let mut stmt = self.prepare_cached_raw(
+                    "SELECT id, nid FROM cards WHERE did = ?1 OR odid = ?1 ORDER BY id",
+                )?;
+                for deck in decks {
+                    let mut rows = stmt.query(params![deck])?;
+                    while let Some(row) = rows.next()? {
+                        cards.push(row.get(0)?);
+                        notes.push(row.get(1)?);
+                    }
+                }
+                notes.sort_unstable();
+                notes.dedup();
+            }
+        }
+        Ok((cards, notes))
+    }
+
+    /// Exports notes or a deck to an `.apkg` file that Anki 2.1.50 or
+    /// later can import. The package holds a new collection with only the
+    /// selected notes and cards, their notetypes, their decks and those
+    /// decks' parents and options. Cards in filtered decks are returned to
+    /// their home decks.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::Database;
+    /// use ankidb::package::{ExportOptions, Selection};
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let deck = db.id_for_deck("Vocabulary")?;
+    /// let exported = db.export_apkg(
+    ///     &"/path/to/Vocabulary.apkg",
+    ///     Selection::Deck(deck),
+    ///     &ExportOptions::default(),
+    /// )?;
+    /// println!("exported {} notes", exported.notes);
+    /// # Ok::<(), ankidb::package::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the selected deck doesn't exist, if a media file or
+    /// the package can't be written, or if the database becomes
+    /// unavailable.
+    pub fn export_apkg<P: AsRef<Path>>(
+        &self,
+        path: &P,
+        selection: Selection,
+        options: &ExportOptions,
+    ) -> Result<Exported, Error> {
+        let (cards, notes) = self.export_ids(selection)?;
+
+        let dir = tempfile::tempdir()?;
+        let copy_path = dir.path().join("collection.anki2");
+        let copy = Self::create(&copy_path)?;
+        copy.transact(|copy| {
+            copy_selection(self, copy, &cards, &notes, options.with_scheduling)?;
+            prune(copy)?;
+            if !options.with_scheduling {
+                reset_scheduling(copy)?;
+            }
+            let next_position: i64 = copy
+                .prepare_raw("SELECT COALESCE(MAX(due), 0) + 1 FROM cards WHERE type = 0")?
+                .query_row([], |row| row.get(0))?;
+            copy.set_config_json("nextPos", &next_position.to_string())
+        })?;
+
+        let mut exported = Exported {
+            notes: copy
+                .prepare_raw("SELECT COUNT(*) FROM notes")?
+                .query_row([], |row| row.get(0))?,
+            cards: copy
+                .prepare_raw("SELECT COUNT(*) FROM cards")?
+                .query_row([], |row| row.get(0))?,
+            ..Exported::default()
+        };
+        let folder = self.media_folder();
+        let media = match &folder {
+            Some(folder) if options.with_media => media_names(&copy, folder)?,
+            _ => BTreeSet::new(),
+        };
+
+        copy.prepare_raw("PRAGMA journal_mode = delete")?
+            .query_row([], |_| Ok(()))?;
+        execute(&copy, &["VACUUM"])?;
+        drop(copy);
+
+        let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+        let mut zip = ZipWriter::new(File::create(path)?);
+        zip.start_file("meta", options)?;
+        zip.write_all(&Message::new().set_varint(1, META_VERSION_LATEST).encode())?;
+        zip.start_file("collection.anki21b", options)?;
+        zstd::stream::copy_encode(File::open(&copy_path)?, &mut zip, 0)?;
+
+        let mut entries = Vec::new();
+        for name in media {
+            let Some(Ok(data)) = folder.as_ref().map(|f| f.read(&name)) else {
+                exported.missing_media.push(name);
+                continue;
+            };
+            zip.start_file(entries.len().to_string(), options)?;
+            zip.write_all(&zstd::encode_all(&*data, 0)?)?;
+            let size = u32::try_from(data.len()).map_err(|_| Error::Invalid("media too large"))?;
+            entries.push(
+                Message::new()
+                    .set_string(1, &name)
+                    .set_uint32(2, size)
+                    .set_bytes(3, &Sha1::digest(&data))
+                    .clone(),
+            );
+        }
+        exported.media = entries.len();
+        zip.start_file("media", options)?;
+        let entries = Message::new().set_messages(1, &entries).encode();
+        zip.write_all(&zstd::encode_all(&*entries, 0)?)?;
+        zip.finish()?;
+
+        Ok(exported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{package::Package, testing::Fixture};
+
+    #[test]
+    fn media_outside_the_folder_is_not_exported() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let collection = dir.path().join("collection.anki2");
+        Fixture::new()
+            .with_note(
+                "Default",
+                "Basic",
+                &[r#"<img src="cat.jpg"><img src="../secret.txt">"#, "cat"],
+                &[],
+            )
+            .build()?
+            .prepare_raw("VACUUM INTO ?")?
+            .execute([collection.to_string_lossy()])?;
+        std::fs::write(dir.path().join("secret.txt"), "hunter2")?;
+        let db = Database::open(&collection)?;
+        db.media_folder()
+            .expect("file-backed collection")
+            .add_file("cat.jpg", b"meow")?;
+
+        let path = dir.path().join("out.apkg");
+        let ids = db
+            .prepare_raw("SELECT id FROM notes")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<NoteId>>>()?;
+        let exported = db.export_apkg(&path, Selection::Notes(&ids), &ExportOptions::default())?;
+        assert_eq!(exported.media, 1);
+        assert_eq!(exported.missing_media, ["../secret.txt"]);
+        assert_eq!(
+            Package::open(&path)?.media_names().collect::<Vec<_>>(),
+            ["cat.jpg"]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,679 @@
+//! Merging `.apkg` files into a collection, matching notes by guid.
+
+use super::{Error, Package};
+use crate::{
+    Database,
+    media::{MediaFolder, normalize_filename},
+    model::{CardId, DeckConfigId, DeckId, NoteId, NotetypeId, parse_fields, parse_tags},
+    proto::Message,
+    text::{field_checksum, rename_media_references},
+    timing::{now_millis, now_secs},
+};
+use rusqlite::{OptionalExtension, Result, params, types::Value};
+use std::{collections::HashMap, path::Path};
+
+/// What happened to a package's notes and media.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Imported {
+    /// Notes whose guid wasn't in the collection, which were added along
+    /// with their cards and review history.
+    pub added: usize,
+    /// Existing notes that were modified more recently in the package.
+    pub updated: usize,
+    /// Existing notes that were left alone, because they weren't modified
+    /// more recently in the package or their notetype is different, and
+    /// notes whose notetype is missing from the package.
+    pub skipped: usize,
+    pub media_added: usize,
+    /// Media files that were renamed, as `(old, new)`: because their names
+    /// had characters Anki doesn't allow, or because a different file with
+    /// the same name was already in the media folder.
+    pub media_renamed: Vec<(String, String)>,
+}
+
+/// An id in `table` for a new row: `preferred` if it's free, otherwise one
+/// past the largest.
+fn free_id(db: &Database, table: &str, preferred: i64) -> Result<i64> {
+    let taken: bool = db
+        .prepare_cached_raw(&format!(
+            "SELECT EXISTS(SELECT 1 FROM {table} WHERE id = ?)"
+        ))?
+        .query_row(params![preferred], |row| row.get(0))?;
+    if !taken {
+        return Ok(preferred);
+    }
+    db.prepare_cached_raw(&format!("SELECT MAX(id) + 1 FROM {table}"))?
+        .query_row([], |row| row.get(0))
+}
+
+/// `name`, with `+` appended until it's not used in `table`, which is how
+/// Anki makes names unique.
+fn unique_name(db: &Database, table: &str, name: &str) -> Result<String> {
+    let mut stmt = db.prepare_cached_raw(&format!(
+        "SELECT EXISTS(SELECT 1 FROM {table} WHERE name = ?)"
+    ))?;
+    let mut name = name.to_string();
+    while stmt.query_row(params![name], |row| row.get::<_, bool>(0))? {
+        name.push('+');
+    }
+    Ok(name)
+}
+
+/// A notetype's field and template names, in order.
+fn layout(db: &Database, id: NotetypeId) -> Result<(Vec<String>, Vec<String>)> {
+    let mut stmt = db.prepare_cached_raw("SELECT name FROM templates WHERE ntid=? ORDER BY ord")?;
+    let templates = stmt
+        .query_map(params![id], |row| row.get(0))?
+        .collect::<Result<_>>()?;
+    Ok((db.fields_for_notetype(id)?, templates))
+}
+
+struct Importer<'a> {
+    target: &'a Database,
+    source: &'a Database,
+    notetypes: HashMap<NotetypeId, NotetypeId>,
+    decks: HashMap<DeckId, DeckId>,
+    notes: HashMap<NoteId, NoteId>,
+    renamed: &'a [(String, String)],
+    imported: Imported,
+}
+
+impl Importer<'_> {
+    /// Maps each notetype in the package to one with the same layout, or
+    /// one that the package's version adds fields to, or else a copy.
+    fn import_notetypes(&mut self) -> Result<()> {
+        let mut stmt = self
+            .source
+            .prepare_raw("SELECT id, name, config FROM notetypes ORDER BY id")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let id: NotetypeId = row.get(0)?;
+            let name: String = row.get(1)?;
+            let config: Vec<u8> = row.get(2)?;
+            let (fields, templates) = layout(self.source, id)?;
+
+            let mut stmt = self.target.prepare_cached_raw(
+                "SELECT id FROM notetypes WHERE id = ? UNION ALL
+                 SELECT id FROM notetypes WHERE name = ? AND id != ?",
+            )?;
+            let candidates = stmt
+                .query_map(params![id, name, id], |row| row.get::<_, NotetypeId>(0))?
+                .collect::<Result<Vec<_>>>()?;
+            let mut mapped = None;
+            for candidate in candidates {
+                let (local_fields, local_templates) = layout(self.target, candidate)?;
+                if local_templates != templates || !fields.starts_with(&local_fields) {
+                    continue;
+                }
+                if local_fields.len() < fields.len() {
+                    self.add_fields(id, candidate, local_fields.len())?;
+                }
+                mapped = Some(candidate);
+                break;
+            }
+
+            let mapped = match mapped {
+                Some(mapped) => mapped,
+                None => self.copy_notetype(id, &name, &config)?,
+            };
+            self.notetypes.insert(id, mapped);
+        }
+        Ok(())
+    }
+
+    /// Adds the package's extra fields to a local notetype, giving its
+    /// notes empty values for them. As this changes the schema, the next
+    /// sync will be a full sync.
+    fn add_fields(&self, source: NotetypeId, target: NotetypeId, from: usize) -> Result<()> {
+        let mut stmt = self
+            .source
+            .prepare_raw("SELECT ord, name, config FROM fields WHERE ntid = ? AND ord >= ?")?;
+        let mut rows = stmt.query(params![source, from])?;
+        let mut added = 0;
+        while let Some(row) = rows.next()? {
+            self.target
+                .prepare_cached_raw("INSERT INTO fields VALUES (?, ?, ?, ?)")?
+                .execute(params![
+                    target,
+                    row.get::<_, u32>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Vec<u8>>(2)?
+                ])?;
+            added += 1;
+        }
+
+        // Notes keep their modification times, so that they can still be
+        // compared with the package's, and the full sync will send them
+        self.target
+            .prepare_raw("UPDATE notes SET flds = flds || ? WHERE mid = ?")?
+            .execute(params!["\x1f".repeat(added), target])?;
+        self.target
+            .prepare_raw("UPDATE notetypes SET mtime_secs = ?, usn = -1 WHERE id = ?")?
+            .execute(params![now_secs(), target])?;
+        self.target
+            .prepare_raw("UPDATE col SET scm = ?, mod = ?")?
+            .execute(params![now_millis(), now_millis()])?;
+        Ok(())
+    }
+
+    fn copy_notetype(&self, id: NotetypeId, name: &str, config: &[u8]) -> Result<NotetypeId> {
+        let new_id = free_id(self.target, "notetypes", id.into())?;
+        self.target
+            .prepare_raw("INSERT INTO notetypes VALUES (?, ?, ?, -1, ?)")?
+            .execute(params![
+                new_id,
+                unique_name(self.target, "notetypes", name)?,
+                now_secs(),
+                config
+            ])?;
+        for (table, columns) in [
+            ("fields", "?1, ord, name, config"),
+            ("templates", "?1, ord, name, mtime_secs, -1, config"),
+        ] {
+            let mut stmt = self
+                .source
+                .prepare_raw(&format!("SELECT {columns} FROM {table} WHERE ntid = ?2"))?;
+            let rows = stmt
+                .query_map(params![new_id, id], |row| {
+                    (0..row.as_ref().column_count())
+                        .map(|i| row.get::<_, Value>(i))
+                        .collect::<Result<Vec<_>>>()
+                })?
+                .collect::<Result<Vec<_>>>()?;
+            let placeholders = vec!["?"; columns.split(',').count()].join(", ");
+            let mut insert = self
+                .target
+                .prepare_raw(&format!("INSERT INTO {table} VALUES ({placeholders})"))?;
+            for row in rows {
+                insert.execute(rusqlite::params_from_iter(row))?;
+            }
+        }
+        Ok(NotetypeId::from(new_id))
+    }
+
+    /// Maps each normal deck in the package to the local deck with the
+    /// same name, adding any that are missing along with their options.
+    /// Filtered decks aren't imported.
+    fn import_decks(&mut self) -> Result<()> {
+        let mut stmt = self
+            .source
+            .prepare_raw("SELECT id, name, common, kind FROM decks ORDER BY name")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let id: DeckId = row.get(0)?;
+            let name: String = row.get(1)?;
+            let mut kind: Message = row.get(3)?;
+            if !kind.has(1) {
+                continue;
+            }
+
+            let existing = self
+                .target
+                .prepare_cached_raw("SELECT id, kind FROM decks WHERE name = ?")?
+                .query_row(params![name], |row| {
+                    Ok((row.get::<_, DeckId>(0)?, row.get::<_, Message>(1)?))
+                })
+                .optional()?;
+            if let Some((local, local_kind)) = &existing {
+                if local_kind.has(1) {
+                    self.decks.insert(id, *local);
+                    continue;
+                }
+            }
+
+            let mut normal = kind.message(1)?;
+            let config = DeckConfigId::from(normal.int64(1));
+            let source_config = self
+                .source
+                .prepare_cached_raw("SELECT name, config FROM deck_config WHERE id = ?")?
+                .query_row(params![config], |row| {
+                    Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?))
+                })
+                .optional()?;
+            if let Some((config_name, config_blob)) = source_config {
+                self.target
+                    .prepare_cached_raw(
+                        "INSERT OR IGNORE INTO deck_config (id, name, mtime_secs, usn, config)
+                         SELECT ?, ?, ?, -1, ?",
+                    )?
+                    .execute(params![config, config_name, now_secs(), config_blob])?;
+            } else {
+                // Decks whose options are missing use the default options,
+                // as in Anki
+                normal.set_int64(1, DeckConfigId::from(1).into());
+                kind.set_message(1, &normal);
+            }
+
+            let new_id = free_id(self.target, "decks", id.into())?;
+            let name = if existing.is_some() {
+                unique_name(self.target, "decks", &name)?
+            } else {
+                name
+            };
+            self.target
+                .prepare_cached_raw("INSERT INTO decks VALUES (?, ?, ?, -1, ?, ?)")?
+                .execute(params![
+                    new_id,
+                    name,
+                    now_secs(),
+                    row.get::<_, Vec<u8>>(2)?,
+                    kind
+                ])?;
+            self.decks.insert(id, DeckId::from(new_id));
+        }
+        Ok(())
+    }
+
+    fn register_tags(&self, tags: &str) -> Result<()> {
+        let mut stmt = self.target.prepare_cached_raw(
+            "INSERT OR IGNORE INTO tags (tag, usn, collapsed, config) VALUES (?, -1, 0, NULL)",
+        )?;
+        for tag in parse_tags(tags) {
+            stmt.execute(params![tag])?;
+        }
+        Ok(())
+    }
+
+    fn import_notes(&mut self) -> Result<()> {
+        let mut stmt = self.source.prepare_raw(
+            "SELECT id, guid, mid, mod, tags, flds, sfld, flags, data FROM notes ORDER BY id",
+        )?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let id: NoteId = row.get(0)?;
+            let guid: String = row.get(1)?;
+            // Notes whose notetype is missing from the package can't be used
+            let Some(&notetype) = self.notetypes.get(&row.get::<_, NotetypeId>(2)?) else {
+                self.imported.skipped += 1;
+                continue;
+            };
+            let modified: i64 = row.get(3)?;
+            let tags: String = row.get(4)?;
+            let mut fields: String = row.get(5)?;
+            for (old, new) in self.renamed {
+                fields = rename_media_references(&fields, old, new);
+            }
+            let checksum = field_checksum(parse_fields(&fields).next().unwrap_or_default());
+
+            let existing = self
+                .target
+                .prepare_cached_raw("SELECT id, mid, mod FROM notes WHERE guid = ?")?
+                .query_row(params![guid], |row| {
+                    Ok((
+                        row.get::<_, NoteId>(0)?,
+                        row.get::<_, NotetypeId>(1)?,
+                        row.get::<_, i64>(2)?,
+                    ))
+                })
+                .optional()?;
+            match existing {
+                None => {
+                    let new_id = free_id(self.target, "notes", id.into())?;
+                    self.target
+                        .prepare_cached_raw(
+                            "INSERT INTO notes VALUES (?, ?, ?, ?, -1, ?, ?, ?, ?, ?, ?)",
+                        )?
+                        .execute(params![
+                            new_id,
+                            guid,
+                            notetype,
+                            modified,
+                            tags,
+                            fields,
+                            row.get::<_, Value>(6)?,
+                            checksum,
+                            row.get::<_, i64>(7)?,
+                            row.get::<_, String>(8)?
+                        ])?;
+                    self.notes.insert(id, NoteId::from(new_id));
+                    self.imported.added += 1;
+                }
+                Some((local, local_notetype, local_modified))
+                    if local_notetype == notetype && modified > local_modified =>
+                {
+                    self.target
+                        .prepare_cached_raw(
+                            "UPDATE notes SET mod = ?, usn = -1, tags = ?, flds = ?, sfld = ?,
+                                              csum = ?
+                             WHERE id = ?",
+                        )?
+                        .execute(params![
+                            modified,
+                            tags,
+                            fields,
+                            row.get::<_, Value>(6)?,
+                            checksum,
+                            local
+                        ])?;
+                    self.imported.updated += 1;
+                }
+                Some(_) => {
+                    self.imported.skipped += 1;
+                    continue;
+                }
+            }
+            self.register_tags(&tags)?;
+        }
+        Ok(())
+    }
+
+    /// Adds the cards of added notes, and their review history. Cards in
+    /// filtered decks go to their home decks, new cards go to the end of
+    /// the new queue, and due days are shifted to the collection's days.
+    fn import_cards(&self) -> Result<()> {
+        let day_offset = i64::from(self.target.timing()?.days_elapsed)
+            - i64::from(self.source.timing()?.days_elapsed);
+        let new_offset: i64 = self
+            .target
+            .prepare_raw("SELECT COALESCE(MAX(due), 0) FROM cards WHERE type = 0")?
+            .query_row([], |row| row.get(0))?;
+
+        // The package is a temporary copy, so its cards can be sent home
+        // the same way emptying a filtered deck does
+        self.source.return_all_cards()?;
+        let mut stmt = self.source.prepare_raw(
+            "SELECT id, nid, did, ord, mod, type, queue, due, ivl, factor, reps, lapses, left,
+                    flags, data
+             FROM cards ORDER BY id",
+        )?;
+        let mut rows = stmt.query([])?;
+        let mut cards = Vec::new();
+        while let Some(row) = rows.next()? {
+            let Some(note) = self.notes.get(&row.get(1)?) else {
+                continue;
+            };
+            let deck = self
+                .decks
+                .get(&row.get(2)?)
+                .copied()
+                .unwrap_or_else(|| DeckId::from(1));
+            let (kind, queue, mut due): (i64, i64, i64) = (row.get(5)?, row.get(6)?, row.get(7)?);
+            if kind == 0 {
+                due += new_offset;
+            } else if kind == 2 || due < 1_000_000_000 {
+                due += day_offset;
+            }
+
+            let id: CardId = row.get(0)?;
+            let new_id = free_id(self.target, "cards", id.into())?;
+            self.target
+                .prepare_cached_raw(
+                    "INSERT INTO cards VALUES
+                     (?, ?, ?, ?, ?, -1, ?, ?, ?, ?, ?, ?, ?, ?, 0, 0, ?, ?)",
+                )?
+                .execute(params![
+                    new_id,
+                    note,
+                    deck,
+                    row.get::<_, i64>(3)?,
+                    row.get::<_, i64>(4)?,
+                    kind,
+                    queue,
+                    due,
+                    row.get::<_, i64>(8)?,
+                    row.get::<_, i64>(9)?,
+                    row.get::<_, i64>(10)?,
+                    row.get::<_, i64>(11)?,
+                    row.get::<_, i64>(12)?,
+                    row.get::<_, i64>(13)?,
+                    row.get::<_, String>(14)?
+                ])?;
+            cards.push((id, new_id));
+        }
+
+        let mut stmt = self.source.prepare_raw(
+            "SELECT id, ease, ivl, lastivl, factor, time, type FROM revlog WHERE cid = ?",
+        )?;
+        let mut insert = self
+            .target
+            .prepare_raw("INSERT OR IGNORE INTO revlog VALUES (?, ?, -1, ?, ?, ?, ?, ?, ?)")?;
+        for (id, new_id) in cards {
+            let mut rows = stmt.query(params![id])?;
+            while let Some(row) = rows.next()? {
+                insert.execute(params![
+                    row.get::<_, i64>(0)?,
+                    new_id,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, i64>(2)?,
+                    row.get::<_, i64>(3)?,
+                    row.get::<_, i64>(4)?,
+                    row.get::<_, i64>(5)?,
+                    row.get::<_, i64>(6)?
+                ])?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Copies a package's media into `folder`, returning how many files were
+/// added and which had to be renamed. Names are normalized as
+/// [`MediaFolder::add_file`] does, and files that are already there with the
+/// same contents are skipped.
+fn import_media(
+    package: &mut Package,
+    folder: &MediaFolder,
+) -> Result<(usize, Vec<(String, String)>), Error> {
+    let mut added = 0;
+    let mut renamed = Vec::new();
+    for file in package.media() {
+        let file = file?;
+        if normalize_filename(&file.name).is_empty() {
+            continue;
+        }
+        let (name, written) = folder.add_file_reporting(&file.name, &file.data)?;
+        if name != file.name {
+            renamed.push((file.name, name));
+        }
+        added += usize::from(written);
+    }
+    Ok((added, renamed))
+}
+
+impl Database {
+    /// Imports an `.apkg` file, merging it with the notes already in the
+    /// collection.
+    ///
+    /// Notes are matched by guid. A note that's already in the collection
+    /// is updated if the package's copy was modified more recently and has
+    /// the same notetype; cards are only added for new notes. Notetypes are
+    /// reused when one with the same id or name has the same fields and
+    /// templates, and when the package's version only adds fields at the
+    /// end, those fields are added to the local notetype, which requires a
+    /// full sync. Otherwise, a copy of the notetype is added. Decks are
+    /// matched by name. Notes whose notetype is missing from the package are
+    /// skipped, along with their cards.
+    ///
+    /// Media is copied into the collection's media folder, named as
+    /// [`MediaFolder::add_file`] names files: names are normalized, and a
+    /// file that would overwrite a different one is renamed with its hash.
+    /// The imported notes are updated to match.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::Database;
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let imported = db.import_apkg(&"/path/to/shared.apkg")?;
+    /// println!(
+    ///     "{} added, {} updated, {} skipped",
+    ///     imported.added, imported.updated, imported.skipped
+    /// );
+    /// # Ok::<(), ankidb::package::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the package can't be read, if a media file can't be
+    /// written, or if either database becomes unavailable. Nothing is
+    /// changed in the collection if it fails, though media may have been
+    /// copied.
+    pub fn import_apkg<P: AsRef<Path>>(&self, path: &P) -> Result<Imported, Error> {
+        let mut package = Package::open(path)?;
+
+        let (media_added, renamed) = match self.media_folder() {
+            Some(folder) => import_media(&mut package, &folder)?,
+            None => (0, Vec::new()),
+        };
+
+        let imported = self.transact(|target| {
+            let mut importer = Importer {
+                target,
+                source: package.database(),
+                notetypes: HashMap::new(),
+                decks: HashMap::new(),
+                notes: HashMap::new(),
+                renamed: &renamed,
+                imported: Imported::default(),
+            };
+            importer.import_notetypes()?;
+            importer.import_decks()?;
+            importer.import_notes()?;
+            importer.import_cards()?;
+            target
+                .prepare_raw("UPDATE col SET mod = ?")?
+                .execute(params![now_millis()])?;
+            Ok(importer.imported)
+        })?;
+
+        Ok(Imported {
+            media_added,
+            media_renamed: renamed,
+            ..imported
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        package::{ExportOptions, Selection},
+        testing::Fixture,
+    };
+
+    /// Saves a fixture as a collection in `dir`, so it has a media folder.
+    fn save(fixture: Fixture, dir: &Path) -> std::result::Result<Database, Error> {
+        let path = dir.join("collection.anki2");
+        fixture
+            .build()?
+            .prepare_raw("VACUUM INTO ?")?
+            .execute([path.to_string_lossy()])?;
+        Ok(Database::open(&path)?)
+    }
+
+    #[test]
+    fn notes_with_missing_notetypes_are_skipped() -> Result<()> {
+        let source = Fixture::new()
+            .with_note("Default", "Basic", &["猫", "cat"], &[])
+            .with_note("Default", "Basic", &["犬", "dog"], &[])
+            .build()?;
+        source
+            .prepare_raw("UPDATE notes SET mid = 42 WHERE sfld = '犬'")?
+            .execute([])?;
+        let target = Database::in_memory()?;
+
+        let mut importer = Importer {
+            target: &target,
+            source: &source,
+            notetypes: HashMap::new(),
+            decks: HashMap::new(),
+            notes: HashMap::new(),
+            renamed: &[],
+            imported: Imported::default(),
+        };
+        importer.import_notetypes()?;
+        importer.import_decks()?;
+        importer.import_notes()?;
+        importer.import_cards()?;
+        assert_eq!((importer.imported.added, importer.imported.skipped), (1, 1));
+
+        let cards: i64 = target
+            .prepare_raw("SELECT COUNT(*) FROM cards")?
+            .query_row([], |row| row.get(0))?;
+        assert_eq!(cards, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn decks_with_missing_options_use_the_default_options() -> Result<()> {
+        let source = Fixture::new()
+            .with_deck("Vocab")
+            .with_note("Vocab", "Basic", &["猫", "cat"], &[])
+            .build()?;
+        let vocab = source.id_for_deck("Vocab")?;
+        let kind = Message::new()
+            .set_message(1, Message::new().set_int64(1, 42))
+            .clone();
+        source
+            .prepare_raw("UPDATE decks SET kind = ? WHERE id = ?")?
+            .execute(params![kind, vocab])?;
+        let target = Database::in_memory()?;
+
+        let mut importer = Importer {
+            target: &target,
+            source: &source,
+            notetypes: HashMap::new(),
+            decks: HashMap::new(),
+            notes: HashMap::new(),
+            renamed: &[],
+            imported: Imported::default(),
+        };
+        importer.import_decks()?;
+
+        let vocab = target.id_for_deck("Vocab")?;
+        assert_eq!(
+            target.deck_options(vocab)?,
+            target.deck_options(DeckId::from(1))?
+        );
+        let configs: i64 = target
+            .prepare_raw("SELECT COUNT(*) FROM deck_config")?
+            .query_row([], |row| row.get(0))?;
+        assert_eq!(configs, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn media_names_are_normalized() -> std::result::Result<(), Error> {
+        let (source_dir, target_dir) = (tempfile::tempdir()?, tempfile::tempdir()?);
+        let source = save(
+            Fixture::new().with_note("Default", "Basic", &[r#"<img src="what?.jpg">"#, ""], &[]),
+            source_dir.path(),
+        )?;
+        source
+            .media_folder()
+            .expect("file-backed collection")
+            .add_file("what.jpg", b"meow")?;
+        std::fs::rename(
+            source_dir.path().join("collection.media/what.jpg"),
+            source_dir.path().join("collection.media/what?.jpg"),
+        )?;
+        let package = source_dir.path().join("out.apkg");
+        let notes = source
+            .prepare_raw("SELECT id FROM notes")?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<Vec<NoteId>>>()?;
+        source.export_apkg(
+            &package,
+            Selection::Notes(&notes),
+            &ExportOptions::default(),
+        )?;
+
+        let target = save(Fixture::new(), target_dir.path())?;
+        let imported = target.import_apkg(&package)?;
+        assert_eq!(imported.media_added, 1);
+        assert_eq!(
+            imported.media_renamed,
+            [("what?.jpg".to_string(), "what.jpg".to_string())]
+        );
+        let folder = target.media_folder().expect("file-backed collection");
+        assert_eq!(folder.files()?, ["what.jpg"]);
+        let fields: String = target
+            .prepare_raw("SELECT flds FROM notes")?
+            .query_row([], |row| row.get(0))?;
+        assert!(fields.starts_with(r#"<img src="what.jpg">"#));
+
+        // Importing again finds the file already there
+        assert_eq!(target.import_apkg(&package)?.media_added, 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,431 @@
+//! Upgrading the schema 11 collections in legacy packages, which keep their
+//! notetypes, decks, options, config and tags as JSON in the `col` table,
+//! to the tables the rest of the crate reads.
+//!
+//! The conversions follow Anki's, in
+//! <https://github.com/ankitects/anki/tree/30ae9f7c5408420c8f347073a9e5e62756a6d7cb/rslib/src/notetype/schema11.rs>
+//! and its neighbours for decks and options, for the settings this crate
+//! uses.
+
+use super::Error;
+use crate::{Database, proto::Message};
+use rusqlite::params;
+use serde_json::{Map, Value};
+
+/// Converts a schema 11 collection to the current schema in place. Newer
+/// collections are left alone.
+pub(super) fn upgrade(db: &Database) -> Result<(), Error> {
+    let (version, columns): (i64, [String; 5]) = db
+        .prepare_raw("SELECT ver, conf, models, decks, dconf, tags FROM col")?
+        .query_row([], |row| {
+            Ok((
+                row.get(0)?,
+                [
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                    row.get(5)?,
+                ],
+            ))
+        })?;
+    if version >= 18 {
+        return Ok(());
+    }
+    let [conf, models, decks, dconf, tags] = columns.map(|json| {
+        serde_json::from_str::<Map<String, Value>>(&json)
+            .map_err(|_| Error::Invalid("invalid legacy collection"))
+    });
+    let (conf, models, decks, dconf, tags) = (conf?, models?, decks?, dconf?, tags?);
+
+    db.transact(|db| {
+        // Graves kept their columns but not their order
+        db.prepare_raw("ALTER TABLE graves RENAME TO graves_old")?
+            .execute([])?;
+        db.create_missing_tables()?;
+        db.prepare_raw("INSERT OR IGNORE INTO graves SELECT oid, type, usn FROM graves_old")?
+            .execute([])?;
+        db.prepare_raw("DROP TABLE graves_old")?.execute([])?;
+
+        let mut stmt = db.prepare_raw("INSERT INTO config VALUES (?, 0, 0, ?)")?;
+        for (key, val) in &conf {
+            stmt.execute(params![key, val.to_string().into_bytes()])?;
+        }
+
+        let mut stmt = db.prepare_raw("INSERT INTO deck_config VALUES (?, ?, ?, ?, ?)")?;
+        for config in dconf.values() {
+            stmt.execute(params![
+                int(&config["id"]),
+                text(&config["name"]),
+                int(&config["mod"]),
+                int(&config["usn"]),
+                deck_config(config),
+            ])?;
+        }
+
+        let mut stmt = db.prepare_raw("INSERT INTO decks VALUES (?, ?, ?, ?, ?, ?)")?;
+        for deck in decks.values() {
+            stmt.execute(params![
+                int(&deck["id"]),
+                text(&deck["name"]).replace("::", "\x1f"),
+                int(&deck["mod"]),
+                int(&deck["usn"]),
+                deck_common(deck),
+                deck_kind(deck),
+            ])?;
+        }
+
+        for model in models.values() {
+            write_notetype(db, model)?;
+        }
+
+        let mut stmt = db.prepare_raw("INSERT OR IGNORE INTO tags VALUES (?, ?, 0, NULL)")?;
+        for (tag, usn) in &tags {
+            stmt.execute(params![tag, int(usn)])?;
+        }
+
+        db.prepare_raw(
+            "UPDATE col SET ver = 18, conf = '', models = '', decks = '', dconf = '', tags = ''",
+        )?
+        .execute([])?;
+        Ok(())
+    })?;
+    Ok(())
+}
+
+fn int(value: &Value) -> i64 {
+    value.as_i64().unwrap_or_default()
+}
+
+fn uint(value: &Value) -> u32 {
+    uint_or(value, 0)
+}
+
+fn uint_or(value: &Value, default: u32) -> u32 {
+    value
+        .as_u64()
+        .map_or(default, |v| u32::try_from(v).unwrap_or(default))
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn float(value: &Value, default: f32) -> f32 {
+    value.as_f64().map_or(default, |v| v as f32)
+}
+
+fn floats(value: &Value) -> Vec<f32> {
+    value
+        .as_array()
+        .map(|values| values.iter().map(|v| float(v, 0.0)).collect())
+        .unwrap_or_default()
+}
+
+/// A flag, which older versions of Anki stored as `0` or `1`.
+fn flag(value: &Value) -> u64 {
+    value
+        .as_bool()
+        .map(u64::from)
+        .or_else(|| value.as_u64().map(|v| u64::from(v != 0)))
+        .unwrap_or_default()
+}
+
+fn text(value: &Value) -> &str {
+    value.as_str().unwrap_or_default()
+}
+
+/// Writes a notetype, with its fields and templates, from its entry in
+/// `col.models`.
+fn write_notetype(db: &Database, model: &Value) -> rusqlite::Result<()> {
+    let id = int(&model["id"]);
+    let config = Message::new()
+        .set_varint(1, uint(&model["type"]).into())
+        .set_uint32(2, uint(&model["sortf"]))
+        .set_string(3, text(&model["css"]))
+        .set_int64(4, int(&model["did"]))
+        .set_string(5, text(&model["latexPre"]))
+        .set_string(6, text(&model["latexPost"]))
+        .set_varint(7, flag(&model["latexsvg"]))
+        .set_varint(9, uint(&model["originalStockKind"]).into())
+        .clone();
+    db.prepare_raw("INSERT INTO notetypes VALUES (?, ?, ?, ?, ?)")?
+        .execute(params![
+            id,
+            text(&model["name"]),
+            int(&model["mod"]),
+            int(&model["usn"]),
+            config
+        ])?;
+
+    let mut stmt = db.prepare_raw("INSERT INTO fields VALUES (?, ?, ?, ?)")?;
+    for field in model["flds"].as_array().into_iter().flatten() {
+        let config = Message::new()
+            .set_varint(1, flag(&field["sticky"]))
+            .set_varint(2, flag(&field["rtl"]))
+            .set_string(3, text(&field["font"]))
+            .set_uint32(4, uint(&field["size"]))
+            .set_string(5, text(&field["description"]))
+            .set_varint(6, flag(&field["plainText"]))
+            .clone();
+        stmt.execute(params![
+            id,
+            int(&field["ord"]),
+            text(&field["name"]),
+            config
+        ])?;
+    }
+
+    let mut stmt = db.prepare_raw("INSERT INTO templates VALUES (?, ?, ?, ?, ?, ?)")?;
+    for template in model["tmpls"].as_array().into_iter().flatten() {
+        let config = Message::new()
+            .set_string(1, text(&template["qfmt"]))
+            .set_string(2, text(&template["afmt"]))
+            .set_string(3, text(&template["bqfmt"]))
+            .set_string(4, text(&template["bafmt"]))
+            .set_int64(5, int(&template["did"]))
+            .set_string(6, text(&template["bfont"]))
+            .set_uint32(7, uint(&template["bsize"]))
+            .clone();
+        stmt.execute(params![
+            id,
+            int(&template["ord"]),
+            text(&template["name"]),
+            int(&model["mod"]),
+            int(&model["usn"]),
+            config
+        ])?;
+    }
+    Ok(())
+}
+
+/// A deck's `common`, from the `[day, count]` pairs that schema 11 keeps
+/// for what's been studied today.
+fn deck_common(deck: &Value) -> Message {
+    Message::new()
+        .set_varint(1, flag(&deck["collapsed"]))
+        .set_varint(2, flag(&deck["browserCollapsed"]))
+        .set_uint32(3, uint(&deck["newToday"][0]))
+        .set_int32(
+            4,
+            i32::try_from(int(&deck["newToday"][1])).unwrap_or_default(),
+        )
+        .set_int32(
+            5,
+            i32::try_from(int(&deck["revToday"][1])).unwrap_or_default(),
+        )
+        .set_int32(
+            6,
+            i32::try_from(int(&deck["lrnToday"][1])).unwrap_or_default(),
+        )
+        .set_int32(
+            7,
+            i32::try_from(int(&deck["timeToday"][1])).unwrap_or_default(),
+        )
+        .clone()
+}
+
+fn deck_kind(deck: &Value) -> Message {
+    if flag(&deck["dyn"]) == 0 {
+        let mut normal = Message::new();
+        normal
+            .set_int64(1, int(&deck["conf"]))
+            .set_string(4, text(&deck["desc"]));
+        for (field, key) in [(6, "reviewLimit"), (7, "newLimit")] {
+            if deck[key].is_u64() {
+                normal.set_uint32(field, uint(&deck[key]));
+            }
+        }
+        for (field, key) in [(8, "reviewLimitToday"), (9, "newLimitToday")] {
+            if deck[key].is_object() {
+                let today = Message::new()
+                    .set_uint32(1, uint(&deck[key]["limit"]))
+                    .set_uint32(2, uint(&deck[key]["today"]))
+                    .clone();
+                normal.set_message(field, &today);
+            }
+        }
+        return Message::new().set_message(1, &normal).clone();
+    }
+
+    let terms = deck["terms"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|term| {
+            Message::new()
+                .set_string(1, text(&term[0]))
+                .set_uint32(2, uint(&term[1]))
+                .set_varint(3, uint(&term[2]).into())
+                .clone()
+        })
+        .collect::<Vec<_>>();
+    let filtered = Message::new()
+        .set_varint(1, flag(&deck["resched"]))
+        .set_messages(2, &terms)
+        .set_floats(3, &floats(&deck["delays"]))
+        .set_uint32(5, uint_or(&deck["previewHardSecs"], 600))
+        .set_uint32(6, uint(&deck["previewGoodSecs"]))
+        .set_uint32(7, uint_or(&deck["previewAgainSecs"], 60))
+        .clone();
+    Message::new().set_message(2, &filtered).clone()
+}
+
+/// The options of a preset, from its entry in `col.dconf`.
+fn deck_config(config: &Value) -> Message {
+    let (new, review, lapse) = (&config["new"], &config["rev"], &config["lapse"]);
+    Message::new()
+        .set_floats(1, &floats(&new["delays"]))
+        .set_floats(2, &floats(&lapse["delays"]))
+        .set_floats(3, &floats(&config["fsrsWeights"]))
+        .set_floats(5, &floats(&config["fsrsParams5"]))
+        .set_floats(6, &floats(&config["fsrsParams6"]))
+        .set_uint32(9, uint(&new["perDay"]))
+        .set_uint32(10, uint(&review["perDay"]))
+        .set_float(11, float(&new["initialFactor"], 2500.0) / 1000.0)
+        .set_float(12, float(&review["ease4"], 1.3))
+        .set_float(13, float(&review["hardFactor"], 1.2))
+        .set_float(14, float(&lapse["mult"], 0.0))
+        .set_float(15, float(&review["ivlFct"], 1.0))
+        .set_uint32(16, uint(&review["maxIvl"]))
+        .set_uint32(17, uint(&lapse["minInt"]))
+        .set_uint32(18, uint(&new["ints"][0]))
+        .set_uint32(19, uint(&new["ints"][1]))
+        // Schema 11 numbers new card orders the other way around
+        .set_varint(20, u64::from(uint_or(&new["order"], 1) == 0))
+        .set_varint(21, uint(&lapse["leechAction"]).into())
+        .set_uint32(22, uint(&lapse["leechFails"]))
+        .set_uint32(24, uint(&config["maxTaken"]))
+        .set_varint(32, uint(&config["newSortOrder"]).into())
+        .set_varint(34, uint(&config["newGatherPriority"]).into())
+        .set_float(37, float(&config["desiredRetention"], 0.9))
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        deck::{FilteredOrder, FilteredSearch},
+        model::DeckId,
+        testing::Fixture,
+    };
+    use serde_json::json;
+
+    /// Rewrites a collection as schema 11 would have stored it, with its
+    /// stock options, its Basic notetype and its decks, plus a filtered
+    /// deck with id 99.
+    fn downgrade(db: &Database) -> rusqlite::Result<()> {
+        let notetype: i64 = db
+            .prepare_raw("SELECT id FROM notetypes WHERE name = 'Basic'")?
+            .query_row([], |row| row.get(0))?;
+        let mut decks = db
+            .prepare_raw("SELECT id, name FROM decks")?
+            .query_map([], |row| {
+                let (id, name): (i64, String) = (row.get(0)?, row.get(1)?);
+                Ok((
+                    id.to_string(),
+                    json!({"id": id, "name": name.replace('\x1f', "::"), "dyn": 0, "conf": 1}),
+                ))
+            })?
+            .collect::<rusqlite::Result<Map<_, _>>>()?;
+        decks.insert(
+            "99".to_string(),
+            json!({
+                "id": 99,
+                "name": "Cram",
+                "dyn": 1,
+                "resched": true,
+                "terms": [["deck:Languages", 100, 1]],
+            }),
+        );
+        let field = |name, ord| json!({"name": name, "ord": ord, "font": "Arial", "size": 20});
+        let models = json!({notetype.to_string(): {
+            "id": notetype,
+            "name": "Basic",
+            "type": 0,
+            "sortf": 0,
+            "did": null,
+            "css": ".card {}",
+            "flds": [field("Front", 0), field("Back", 1)],
+            "tmpls": [{
+                "name": "Card 1",
+                "ord": 0,
+                "qfmt": "{{Front}}",
+                "afmt": "{{FrontSide}}<hr id=answer>{{Back}}",
+            }],
+        }});
+        let dconf = json!({"1": {
+            "id": 1,
+            "name": "Default",
+            "maxTaken": 60,
+            "new": {"delays": [1, 10], "ints": [1, 4, 0], "initialFactor": 2500, "perDay": 20},
+            "rev": {"perDay": 200, "ease4": 1.3, "hardFactor": 1.2, "ivlFct": 1, "maxIvl": 36500},
+            "lapse": {"delays": [10], "mult": 0, "minInt": 1, "leechFails": 8, "leechAction": 1},
+        }});
+
+        for table in [
+            "config",
+            "deck_config",
+            "decks",
+            "notetypes",
+            "fields",
+            "templates",
+            "tags",
+            "graves",
+        ] {
+            db.prepare_raw(&format!("DROP TABLE {table}"))?
+                .execute([])?;
+        }
+        db.prepare_raw("CREATE TABLE graves (usn integer, oid integer, type integer)")?
+            .execute([])?;
+        db.prepare_raw("INSERT INTO graves VALUES (-1, 42, 1)")?
+            .execute([])?;
+        db.prepare_raw(
+            "UPDATE col SET ver = 11, conf = ?, models = ?, decks = ?, dconf = ?, tags = ?",
+        )?
+        .execute(params![
+            json!({"curModel": notetype}).to_string(),
+            models.to_string(),
+            Value::from(decks).to_string(),
+            dconf.to_string(),
+            json!({"animal": 0}).to_string(),
+        ])?;
+        Ok(())
+    }
+
+    #[test]
+    fn legacy_collections_are_upgraded() -> Result<(), Error> {
+        let db = Fixture::new()
+            .with_deck("Languages::Japanese")
+            .with_note("Languages::Japanese", "Basic", &["猫", "cat"], &["animal"])
+            .build()?;
+        let options = db.deck_options(DeckId::from(1))?;
+        downgrade(&db)?;
+
+        upgrade(&db)?;
+        // Upgraded collections are left alone
+        upgrade(&db)?;
+
+        assert_eq!(db.deck_options(DeckId::from(1))?, options);
+        assert!(db.check(false)?.is_ok());
+        let cram = db.filtered_deck(DeckId::from(99))?;
+        assert!(cram.reschedule);
+        assert_eq!(
+            cram.search_terms,
+            [FilteredSearch {
+                search: "deck:Languages".to_string(),
+                limit: 100,
+                order: FilteredOrder::Random,
+            }]
+        );
+        let japanese: i64 = db
+            .prepare_raw(
+                "SELECT COUNT(*) FROM decks WHERE name = 'Languages' || char(31) || 'Japanese'",
+            )?
+            .query_row([], |row| row.get(0))?;
+        assert_eq!(japanese, 1);
+        let grave: (i64, i64) = db
+            .prepare_raw("SELECT oid, type FROM graves")?
+            .query_row([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        assert_eq!(grave, (42, 1));
+        Ok(())
+    }
+}
@@ -0,0 +1,297 @@
+//! A minimal protobuf codec for the config blobs that Anki stores in its
+//! newer tables (`notetypes.config`, `decks.common`, `deck_config.config`,
+//! etc.)
+//!
+//! Fields are kept in the order they were read, including any we don't know
+//! about, so that a blob can be decoded, modified, and re-encoded without
+//! losing data written by newer versions of Anki.
+
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeError;
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "invalid protobuf message")
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<DecodeError> for rusqlite::Error {
+    fn from(e: DecodeError) -> Self {
+        Self::FromSqlConversionFailure(0, rusqlite::types::Type::Blob, Box::new(e))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Varint(u64),
+    Fixed64([u8; 8]),
+    Bytes(Vec<u8>),
+    Fixed32([u8; 4]),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Message {
+    fields: Vec<(u32, Value)>,
+}
+
+fn read_varint(bytes: &[u8], pos: &mut usize) -> Result<u64, DecodeError> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *bytes.get(*pos).ok_or(DecodeError)?;
+        *pos += 1;
+        value |= u64::from(byte & 0x7F) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(DecodeError)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        #[allow(clippy::cast_possible_truncation)]
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    #[allow(clippy::cast_possible_truncation)]
+    out.push(value as u8);
+}
+
+fn take<'a>(bytes: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8], DecodeError> {
+    let end = pos.checked_add(len).ok_or(DecodeError)?;
+    let slice = bytes.get(*pos..end).ok_or(DecodeError)?;
+    *pos = end;
+    Ok(slice)
+}
+
+impl Message {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut fields = Vec::new();
+        let mut pos = 0;
+        while pos < bytes.len() {
+            let key = read_varint(bytes, &mut pos)?;
+            let field = u32::try_from(key >> 3).map_err(|_| DecodeError)?;
+            let value = match key & 0x7 {
+                0 => Value::Varint(read_varint(bytes, &mut pos)?),
+                1 => Value::Fixed64(take(bytes, &mut pos, 8)?.try_into().unwrap()),
+                2 => {
+                    let len =
+                        usize::try_from(read_varint(bytes, &mut pos)?).map_err(|_| DecodeError)?;
+                    Value::Bytes(take(bytes, &mut pos, len)?.to_vec())
+                }
+                5 => Value::Fixed32(take(bytes, &mut pos, 4)?.try_into().unwrap()),
+                _ => return Err(DecodeError),
+            };
+            fields.push((field, value));
+        }
+        Ok(Self { fields })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (field, value) in &self.fields {
+            let wire_type = match value {
+                Value::Varint(_) => 0,
+                Value::Fixed64(_) => 1,
+                Value::Bytes(_) => 2,
+                Value::Fixed32(_) => 5,
+            };
+            write_varint(&mut out, (u64::from(*field) << 3) | wire_type);
+            match value {
+                Value::Varint(v) => write_varint(&mut out, *v),
+                Value::Fixed64(b) => out.extend_from_slice(b),
+                Value::Bytes(b) => {
+                    write_varint(&mut out, b.len() as u64);
+                    out.extend_from_slice(b);
+                }
+                Value::Fixed32(b) => out.extend_from_slice(b),
+            }
+        }
+        out
+    }
+
+    fn last(&self, field: u32) -> Option<&Value> {
+        self.fields
+            .iter()
+            .rev()
+            .find(|(f, _)| *f == field)
+            .map(|(_, v)| v)
+    }
+
+    fn all(&self, field: u32) -> impl Iterator<Item = &Value> {
+        self.fields
+            .iter()
+            .filter(move |(f, _)| *f == field)
+            .map(|(_, v)| v)
+    }
+
+    pub fn has(&self, field: u32) -> bool {
+        self.last(field).is_some()
+    }
+
+    pub fn varint(&self, field: u32) -> u64 {
+        match self.last(field) {
+            Some(Value::Varint(v)) => *v,
+            _ => 0,
+        }
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn uint32(&self, field: u32) -> u32 {
+        self.varint(field) as u32
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn int32(&self, field: u32) -> i32 {
+        self.varint(field) as i32
+    }
+
+    #[allow(clippy::cast_possible_wrap)]
+    pub fn int64(&self, field: u32) -> i64 {
+        self.varint(field) as i64
+    }
+
+    pub fn float(&self, field: u32) -> f32 {
+        match self.last(field) {
+            Some(Value::Fixed32(b)) => f32::from_le_bytes(*b),
+            _ => 0.0,
+        }
+    }
+
+    /// Reads a repeated float field, which may be packed or unpacked.
+    pub fn floats(&self, field: u32) -> Result<Vec<f32>, DecodeError> {
+        let mut out = Vec::new();
+        for value in self.all(field) {
+            match value {
+                Value::Fixed32(b) => out.push(f32::from_le_bytes(*b)),
+                Value::Bytes(b) if b.len() % 4 == 0 => out.extend(
+                    b.chunks_exact(4)
+                        .map(|c| f32::from_le_bytes(c.try_into().unwrap())),
+                ),
+                _ => return Err(DecodeError),
+            }
+        }
+        Ok(out)
+    }
+
+    pub fn bytes(&self, field: u32) -> &[u8] {
+        match self.last(field) {
+            Some(Value::Bytes(b)) => b,
+            _ => &[],
+        }
+    }
+
+    pub fn string(&self, field: u32) -> String {
+        String::from_utf8_lossy(self.bytes(field)).into_owned()
+    }
+
+    pub fn message(&self, field: u32) -> Result<Self, DecodeError> {
+        Self::decode(self.bytes(field))
+    }
+
+    pub fn messages(&self, field: u32) -> Result<Vec<Self>, DecodeError> {
+        self.all(field)
+            .map(|v| match v {
+                Value::Bytes(b) => Self::decode(b),
+                _ => Err(DecodeError),
+            })
+            .collect()
+    }
+
+    /// Replaces every occurrence of `field` with the given values, keeping
+    /// fields ordered by number like prost does.
+    fn replace(&mut self, field: u32, values: impl IntoIterator<Item = Value>) -> &mut Self {
+        let at = self
+            .fields
+            .iter()
+            .position(|(f, _)| *f >= field)
+            .unwrap_or(self.fields.len());
+        self.fields.retain(|(f, _)| *f != field);
+        let at = at.min(self.fields.len());
+        let new = values.into_iter().map(|v| (field, v)).collect::<Vec<_>>();
+        self.fields.splice(at..at, new);
+        self
+    }
+
+    /// Sets a scalar varint field. As in proto3, the default value is
+    /// omitted from the encoding.
+    pub fn set_varint(&mut self, field: u32, value: u64) -> &mut Self {
+        self.replace(field, (value != 0).then_some(Value::Varint(value)))
+    }
+
+    pub fn set_uint32(&mut self, field: u32, value: u32) -> &mut Self {
+        self.set_varint(field, value.into())
+    }
+
+    #[allow(clippy::cast_sign_loss)]
+    pub fn set_int32(&mut self, field: u32, value: i32) -> &mut Self {
+        self.set_varint(field, i64::from(value) as u64)
+    }
+
+    #[allow(clippy::cast_sign_loss)]
+    pub fn set_int64(&mut self, field: u32, value: i64) -> &mut Self {
+        self.set_varint(field, value as u64)
+    }
+
+    pub fn set_float(&mut self, field: u32, value: f32) -> &mut Self {
+        self.replace(
+            field,
+            (value != 0.0).then(|| Value::Fixed32(value.to_le_bytes())),
+        )
+    }
+
+    /// Sets a repeated float field, using packed encoding.
+    pub fn set_floats(&mut self, field: u32, values: &[f32]) -> &mut Self {
+        let packed = values
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect::<Vec<_>>();
+        self.set_bytes(field, &packed)
+    }
+
+    pub fn set_bytes(&mut self, field: u32, value: &[u8]) -> &mut Self {
+        self.replace(
+            field,
+            (!value.is_empty()).then(|| Value::Bytes(value.to_vec())),
+        )
+    }
+
+    pub fn set_string(&mut self, field: u32, value: &str) -> &mut Self {
+        self.set_bytes(field, value.as_bytes())
+    }
+
+    /// Sets a singular embedded message. Unlike scalars, an empty message is
+    /// still encoded, since its presence is meaningful.
+    pub fn set_message(&mut self, field: u32, value: &Self) -> &mut Self {
+        self.replace(field, [Value::Bytes(value.encode())])
+    }
+
+    pub fn set_messages(&mut self, field: u32, values: &[Self]) -> &mut Self {
+        self.replace(field, values.iter().map(|m| Value::Bytes(m.encode())))
+    }
+}
+
+impl FromSql for Message {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let bytes = match value {
+            ValueRef::Null => return Ok(Self::new()),
+            ValueRef::Blob(b) | ValueRef::Text(b) => b,
+            _ => return Err(FromSqlError::InvalidType),
+        };
+        Self::decode(bytes).map_err(|e| FromSqlError::Other(Box::new(e)))
+    }
+}
+
+impl ToSql for Message {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.encode()))
+    }
+}
@@ -11,6 +11,12 @@ pub enum FieldMatcher {
     Prefix(String),
     Suffix(String),
     Contains(String),
+    /// Matches a field whose reading equals the string, like the `kana:`
+    /// filter, e.g. `かんじ` matches `漢字[かんじ]`.
+    Kana(String),
+    /// Matches a field whose base text equals the string, like the `kanji:`
+    /// filter, e.g. `漢字` matches `漢字[かんじ]`.
+    Kanji(String),
 }
 
 #[must_use]
@@ -95,6 +101,7 @@ pub trait AnkiExt {
     fn where_fields_like(self, pattern: &str) -> Self;
     fn where_fields_match(self, fields: &[FieldMatcher]) -> Self;
     fn where_tag(self, tag: &str) -> Self;
+    fn where_leech(self) -> Self;
 
     fn not_did_mid(self, did: DeckId, mid: NotetypeId) -> Self;
 
@@ -189,7 +196,9 @@ impl AnkiExt for &mut SelectStatement {
         let spec = fields
             .iter()
             .map(|f| match f {
-                FieldMatcher::Any => String::from("%"),
+                FieldMatcher::Any | FieldMatcher::Kana(_) | FieldMatcher::Kanji(_) => {
+                    String::from("%")
+                }
                 FieldMatcher::Equals(s) => s.clone(),
                 FieldMatcher::Prefix(s) => format!("{s}%"),
                 FieldMatcher::Suffix(s) => format!("%{s}"),
@@ -198,7 +207,45 @@ impl AnkiExt for &mut SelectStatement {
             .collect::<Vec<_>>()
             .join("\x1F");
 
-        self.where_fields_like(&spec)
+        self.where_fields_like(&spec);
+
+        // Readings are matched separately, by passing the fields through the
+        // `kana` and `kanji` SQL functions that `Database` registers
+        if fields.iter().any(|f| matches!(f, FieldMatcher::Kana(_))) {
+            let spec = fields
+                .iter()
+                .map(|f| match f {
+                    FieldMatcher::Kana(s) => s.clone(),
+                    _ => String::from("%"),
+                })
+                .collect::<Vec<_>>()
+                .join("\x1F");
+            self.and_where(
+                Expr::expr(
+                    Func::cust(Alias::new("kana")).arg(Expr::col((Notes::Table, Notes::Flds))),
+                )
+                .like(spec),
+            );
+        }
+
+        if fields.iter().any(|f| matches!(f, FieldMatcher::Kanji(_))) {
+            let spec = fields
+                .iter()
+                .map(|f| match f {
+                    FieldMatcher::Kanji(s) => s.clone(),
+                    _ => String::from("%"),
+                })
+                .collect::<Vec<_>>()
+                .join("\x1F");
+            self.and_where(
+                Expr::expr(
+                    Func::cust(Alias::new("kanji")).arg(Expr::col((Notes::Table, Notes::Flds))),
+                )
+                .like(spec),
+            );
+        }
+
+        self
     }
 
     fn where_tag(self, tag: &str) -> Self {
@@ -208,6 +255,13 @@ impl AnkiExt for &mut SelectStatement {
         self.and_where(Expr::col((Notes::Table, Notes::Tags)).like(pattern))
     }
 
+    /// Matches notes that Anki has tagged as leeches. Which cards count as
+    /// leeches depends on their deck's options, so use `Database::leeches`
+    /// to find cards that haven't been tagged yet.
+    fn where_leech(self) -> Self {
+        self.where_tag("leech")
+    }
+
     fn not_did_mid(self, did: DeckId, mid: NotetypeId) -> Self {
         self.cond_where(
             Cond::all()
@@ -0,0 +1,934 @@
+//! Answering cards with the v3 scheduler's SM-2 algorithm.
+//!
+//! This follows the state machine in
+//! <https://github.com/ankitects/anki/tree/30ae9f7c5408420c8f347073a9e5e62756a6d7cb/rslib/src/scheduler/states>,
+//! including learning steps, graduating intervals, lapses, leeches and fuzz.
+//! Fuzz comes from a different random number generator than Anki's, so
+//! fuzzed intervals will be in the same range but won't match exactly.
+//!
+//! It also lists new cards in the order they'll be shown, and can change
+//! their positions.
+
+use crate::{
+    Database,
+    deck::{DeckOptions, FilteredDeck, LeechAction},
+    model::{CardId, DeckId, Ease, NoteId, RevlogId, parse_tags},
+    proto::Message,
+    timing::{SchedTiming, now_millis, now_secs},
+};
+use rusqlite::{Result, params};
+use std::time::Duration;
+
+mod new;
+
+const DAY_SECS: u32 = 86_400;
+const MINIMUM_EASE: f32 = 1.3;
+
+/// How much review intervals are fuzzed: each `(start, end, factor)` adds
+/// `factor` days of fuzz for every day of the interval in that range.
+const FUZZ_RANGES: [(f32, f32, f32); 3] =
+    [(2.5, 7.0, 0.15), (7.0, 20.0, 0.1), (20.0, f32::MAX, 0.05)];
+
+/// Learning or relearning steps, in minutes.
+pub(crate) struct Steps<'a>(pub &'a [f32]);
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+impl Steps<'_> {
+    fn secs(&self, index: usize) -> Option<u32> {
+        self.0.get(index).map(|minutes| (minutes * 60.0) as u32)
+    }
+
+    pub fn count(&self) -> u32 {
+        u32::try_from(self.0.len()).unwrap_or(u32::MAX)
+    }
+
+    /// The index of the current step, given the number remaining, which is
+    /// what `Cards::Left` holds.
+    fn index(&self, remaining: u32) -> usize {
+        let total = self.0.len();
+        total
+            .saturating_sub(remaining as usize % 1000)
+            .min(total.saturating_sub(1))
+    }
+
+    fn current(&self, remaining: u32) -> Option<u32> {
+        self.secs(self.index(remaining))
+    }
+
+    fn again(&self) -> Option<u32> {
+        self.secs(0)
+    }
+
+    /// Hard repeats the current step, except on the first step, where it
+    /// goes halfway to the next one.
+    fn hard(&self, remaining: u32) -> Option<u32> {
+        let index = self.index(remaining);
+        let current = self.secs(index)?;
+        if index > 0 {
+            return Some(current);
+        }
+        Some(self.secs(1).map_or_else(
+            || (current.saturating_mul(3) / 2).min(current + DAY_SECS),
+            |next| u32::midpoint(current, next),
+        ))
+    }
+
+    fn good(&self, remaining: u32) -> Option<u32> {
+        self.secs(self.index(remaining) + 1)
+    }
+
+    fn remaining_after_good(&self, remaining: u32) -> u32 {
+        let remaining = self.0.len().saturating_sub(self.index(remaining) + 1);
+        u32::try_from(remaining).unwrap_or_default()
+    }
+}
+
+/// The scheduling state a card moves into after being answered.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Next {
+    Learning {
+        remaining: u32,
+        secs: u32,
+    },
+    Relearning {
+        remaining: u32,
+        secs: u32,
+        ivl: u32,
+        factor: f32,
+    },
+    Review {
+        ivl: u32,
+        factor: f32,
+    },
+}
+
+fn fuzz_delta(interval: f32) -> f32 {
+    if interval < 2.5 {
+        return 0.0;
+    }
+    FUZZ_RANGES
+        .iter()
+        .map(|(start, end, factor)| factor * (interval.min(*end) - start).max(0.0))
+        .sum::<f32>()
+        + 1.0
+}
+
+/// Picks an interval near `interval`, within `minimum..=maximum`, using a
+/// `fuzz` factor between 0 and 1.
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    clippy::cast_precision_loss
+)]
+pub(crate) fn with_review_fuzz(fuzz: f32, interval: f32, minimum: u32, maximum: u32) -> u32 {
+    let minimum = minimum.min(maximum);
+    let interval = interval.clamp(minimum as f32, maximum as f32);
+    let delta = fuzz_delta(interval);
+    let lower = ((interval - delta).round() as u32).clamp(minimum, maximum);
+    let mut upper = ((interval + delta).round() as u32).clamp(minimum, maximum);
+    if upper == lower && upper > 2 && upper < maximum {
+        upper = lower + 1;
+    }
+    (fuzz.mul_add((1 + upper - lower) as f32, lower as f32)).floor() as u32
+}
+
+/// A random number between 0 and 1 that's fixed for each card and number
+/// of reviews, so answering is repeatable.
+#[allow(clippy::cast_sign_loss, clippy::cast_precision_loss)]
+fn fuzz_factor(id: CardId, reps: u32) -> f32 {
+    // splitmix64
+    let mut z = (i64::from(id) as u64)
+        .wrapping_add(u64::from(reps))
+        .wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^= z >> 31;
+    (z >> 40) as f32 / (1u64 << 24) as f32
+}
+
+/// Whether a card with this many lapses has just become a leech. Past the
+/// threshold, cards are flagged again every half threshold, rounded up.
+pub(crate) fn is_leech(lapses: u32, threshold: u32) -> bool {
+    if threshold == 0 || lapses < threshold {
+        return false;
+    }
+    (lapses - threshold) % threshold.div_ceil(2).max(1) == 0
+}
+
+/// What gets written to the card and the review log.
+#[derive(Debug, Clone, Copy)]
+struct Outcome {
+    kind: i64,
+    queue: i64,
+    due: i64,
+    ivl: u32,
+    factor: u32,
+    left: u32,
+    revlog_ivl: i64,
+}
+
+pub(crate) struct Context<'a> {
+    pub options: &'a DeckOptions,
+    pub fuzz: f32,
+}
+
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    clippy::cast_precision_loss
+)]
+impl Context<'_> {
+    pub fn maximum_interval(&self) -> u32 {
+        self.options.maximum_review_interval.max(1)
+    }
+
+    fn review_interval(&self, interval: f32, minimum: u32, fuzz: bool) -> u32 {
+        let maximum = self.maximum_interval();
+        let minimum = minimum.clamp(1, maximum);
+        let interval = interval * self.options.interval_multiplier;
+        if fuzz {
+            with_review_fuzz(self.fuzz, interval, minimum, maximum)
+        } else {
+            (interval.round() as u32).clamp(minimum, maximum)
+        }
+    }
+
+    /// Intraday learning steps are delayed by up to a quarter, capped at
+    /// five minutes, so cards answered together drift apart.
+    fn learning_fuzz(&self, secs: u32) -> u32 {
+        secs + (self.fuzz * (secs as f32 * 0.25).min(300.0)) as u32
+    }
+
+    /// Converts a state into the card's columns. Like Anki, learning steps
+    /// that end after the next day starts are scheduled by day, like
+    /// reviews, rather than by timestamp, and logged in days.
+    fn outcome(&self, next: Next, card_factor: u32, timing: SchedTiming, now: i64) -> Outcome {
+        let today = timing.days_elapsed;
+        let until_rollover = u32::try_from(timing.next_day_at - now).unwrap_or_default();
+        let learning_due = |secs: u32| {
+            if secs >= until_rollover {
+                let days = (secs - until_rollover) / DAY_SECS + 1;
+                (3, i64::from(today + days), i64::from(days))
+            } else {
+                let secs = self.learning_fuzz(secs);
+                (1, now + i64::from(secs), -i64::from(secs))
+            }
+        };
+        match next {
+            Next::Learning { remaining, secs } => {
+                let (queue, due, revlog_ivl) = learning_due(secs);
+                Outcome {
+                    kind: 1,
+                    queue,
+                    due,
+                    ivl: 0,
+                    factor: card_factor,
+                    left: remaining,
+                    revlog_ivl,
+                }
+            }
+            Next::Relearning {
+                remaining,
+                secs,
+                ivl,
+                factor,
+            } => {
+                let (queue, due, revlog_ivl) = learning_due(secs);
+                Outcome {
+                    kind: 3,
+                    queue,
+                    due,
+                    ivl,
+                    factor: (factor * 1000.0).round() as u32,
+                    left: remaining,
+                    revlog_ivl,
+                }
+            }
+            Next::Review { ivl, factor } => Outcome {
+                kind: 2,
+                queue: 2,
+                due: i64::from(today + ivl),
+                ivl,
+                factor: (factor * 1000.0).round() as u32,
+                left: 0,
+                revlog_ivl: i64::from(ivl),
+            },
+        }
+    }
+
+    fn graduate(&self, ease: Ease) -> Next {
+        let ivl = if ease == Ease::Easy {
+            self.options.graduating_interval_easy
+        } else {
+            self.options.graduating_interval_good
+        };
+        Next::Review {
+            ivl: with_review_fuzz(self.fuzz, ivl as f32, 1, self.maximum_interval()),
+            factor: self.options.initial_ease,
+        }
+    }
+
+    pub fn learning(&self, steps: &Steps, remaining: u32, ease: Ease) -> Next {
+        let step = match ease {
+            Ease::Again => steps.again().map(|secs| (steps.count(), secs)),
+            Ease::Hard => steps.hard(remaining).map(|secs| (remaining, secs)),
+            Ease::Good => steps
+                .good(remaining)
+                .map(|secs| (steps.remaining_after_good(remaining), secs)),
+            Ease::Easy => None,
+        };
+        match step {
+            Some((remaining, secs)) => Next::Learning { remaining, secs },
+            None => self.graduate(ease),
+        }
+    }
+
+    pub fn relearning(
+        &self,
+        steps: &Steps,
+        remaining: u32,
+        ivl: u32,
+        factor: f32,
+        ease: Ease,
+    ) -> Next {
+        let step = match ease {
+            Ease::Again => steps.again().map(|secs| (steps.count(), secs)),
+            Ease::Hard => steps.hard(remaining).map(|secs| (remaining, secs)),
+            Ease::Good => steps
+                .good(remaining)
+                .map(|secs| (steps.remaining_after_good(remaining), secs)),
+            Ease::Easy => {
+                return Next::Review {
+                    ivl: (ivl + 1).min(self.maximum_interval()),
+                    factor,
+                };
+            }
+        };
+        match step {
+            Some((remaining, secs)) => Next::Relearning {
+                remaining,
+                secs,
+                ivl,
+                factor,
+            },
+            None => Next::Review { ivl, factor },
+        }
+    }
+
+    pub fn review(&self, steps: &Steps, ivl: u32, factor: f32, days_late: i64, ease: Ease) -> Next {
+        let options = self.options;
+        let current = ivl as f32;
+
+        if ease == Ease::Again {
+            let factor = (factor - 0.2).max(MINIMUM_EASE);
+            // Unlike other intervals, the interval multiplier doesn't apply,
+            // and it's truncated rather than rounded
+            let ivl = ((current * options.lapse_multiplier) as u32)
+                .max(options.minimum_lapse_interval)
+                .max(1)
+                .min(self.maximum_interval());
+            return steps
+                .again()
+                .map_or(Next::Review { ivl, factor }, |secs| Next::Relearning {
+                    remaining: steps.count(),
+                    secs,
+                    ivl,
+                    factor,
+                });
+        }
+
+        let hard_multiplier = options.hard_multiplier;
+        let (hard_ivl, good_ivl, easy_ivl) = if days_late < 0 {
+            // Reviewed early, so only the time actually elapsed counts
+            let elapsed = (i64::from(ivl) + days_late).max(0) as f32;
+            let passed = (elapsed * factor).max(current);
+            let easy_bonus = options.easy_multiplier - (options.easy_multiplier - 1.0) / 2.0;
+            (
+                self.review_interval(
+                    (elapsed * hard_multiplier).max(current * hard_multiplier / 2.0),
+                    0,
+                    false,
+                ),
+                self.review_interval(passed, 0, false),
+                self.review_interval(passed * easy_bonus, 0, false),
+            )
+        } else {
+            let days_late = days_late as f32;
+            let hard_minimum = if hard_multiplier <= 1.0 { 0 } else { ivl + 1 };
+            let hard_ivl = self.review_interval(current * hard_multiplier, hard_minimum, true);
+            let good_minimum = if hard_multiplier <= 1.0 {
+                ivl + 1
+            } else {
+                hard_ivl + 1
+            };
+            let good_ivl =
+                self.review_interval((current + days_late / 2.0) * factor, good_minimum, true);
+            let easy_ivl = self.review_interval(
+                (current + days_late) * factor * options.easy_multiplier,
+                good_ivl + 1,
+                true,
+            );
+            (hard_ivl, good_ivl, easy_ivl)
+        };
+
+        match ease {
+            Ease::Hard => Next::Review {
+                ivl: hard_ivl,
+                factor: (factor - 0.15).max(MINIMUM_EASE),
+            },
+            Ease::Easy => Next::Review {
+                ivl: easy_ivl,
+                factor: factor + 0.15,
+            },
+            _ => Next::Review {
+                ivl: good_ivl,
+                factor,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CardRow {
+    nid: NoteId,
+    did: DeckId,
+    odid: DeckId,
+    kind: i64,
+    due: i64,
+    odue: i64,
+    ivl: u32,
+    factor: u32,
+    left: u32,
+    reps: u32,
+    lapses: u32,
+}
+
+impl CardRow {
+    fn read(db: &Database, id: CardId) -> Result<Self> {
+        let mut stmt = db.prepare_cached_raw(
+            "SELECT nid, did, odid, type, due, odue, ivl, factor, left, reps, lapses
+             FROM cards WHERE id=?",
+        )?;
+        stmt.query_row(params![id], |row| {
+            Ok(Self {
+                nid: row.get(0)?,
+                did: row.get(1)?,
+                odid: row.get(2)?,
+                kind: row.get(3)?,
+                due: row.get(4)?,
+                odue: row.get(5)?,
+                ivl: row.get(6)?,
+                factor: row.get(7)?,
+                left: row.get(8)?,
+                reps: row.get(9)?,
+                lapses: row.get(10)?,
+            })
+        })
+    }
+
+    /// The state the card moves into, along with the review log's type and
+    /// last interval for the answer.
+    fn next(&self, ctx: &Context, today: u32, ease: Ease) -> (Next, i64, i64) {
+        let learn_steps = Steps(&ctx.options.learn_steps);
+        let relearn_steps = Steps(&ctx.options.relearn_steps);
+        #[allow(clippy::cast_precision_loss)]
+        let factor = self.factor as f32 / 1000.0;
+        let secs = |secs: Option<u32>| -i64::from(secs.unwrap_or_default());
+
+        match self.kind {
+            2 => {
+                let due = if i64::from(self.odid) != 0 && self.odue != 0 {
+                    self.odue
+                } else {
+                    self.due
+                };
+                let days_late = i64::from(today) - due;
+                (
+                    ctx.review(&relearn_steps, self.ivl, factor, days_late, ease),
+                    1,
+                    i64::from(self.ivl),
+                )
+            }
+            3 => (
+                ctx.relearning(&relearn_steps, self.left, self.ivl, factor, ease),
+                2,
+                secs(relearn_steps.current(self.left)),
+            ),
+            0 => (ctx.learning(&learn_steps, learn_steps.count(), ease), 0, 0),
+            _ => (
+                ctx.learning(&learn_steps, self.left, ease),
+                0,
+                secs(learn_steps.current(self.left)),
+            ),
+        }
+    }
+}
+
+impl Database {
+    /// Adds a tag to a note, if it doesn't already have it.
+    pub(crate) fn add_tag(&self, id: NoteId, tag: &str) -> Result<()> {
+        let mut stmt = self.prepare_cached_raw("SELECT tags FROM notes WHERE id=?")?;
+        let tags: String = stmt.query_row(params![id], |row| row.get(0))?;
+        if parse_tags(&tags).any(|t| t.eq_ignore_ascii_case(tag)) {
+            return Ok(());
+        }
+
+        let mut tags = parse_tags(&tags).collect::<Vec<_>>();
+        tags.push(tag);
+        let mut stmt =
+            self.prepare_cached_raw("UPDATE notes SET tags=?, mod=?, usn=-1 WHERE id=?")?;
+        stmt.execute(params![format!(" {} ", tags.join(" ")), now_secs(), id])?;
+        let mut stmt = self.prepare_cached_raw(
+            "INSERT OR IGNORE INTO tags (tag, usn, collapsed, config) VALUES (?, -1, 0, NULL)",
+        )?;
+        stmt.execute(params![tag])?;
+        Ok(())
+    }
+
+    /// Picks an id for a new review log entry, which is the current time in
+    /// milliseconds unless that's already taken.
+    pub(crate) fn next_revlog_id(&self) -> Result<RevlogId> {
+        let mut stmt = self.prepare_cached_raw(
+            "SELECT MAX(?1, COALESCE(MAX(id) + 1, 0)) FROM revlog WHERE id >= ?1",
+        )?;
+        stmt.query_row(params![now_millis()], |row| row.get(0))
+    }
+
+    /// Counts an answer towards what's been studied today in a deck and its
+    /// parents, which the deck list's limits are based on.
+    fn record_studied(&self, deck: DeckId, today: u32, kind: i64, millis: i64) -> Result<()> {
+        let mut stmt = self.prepare_cached_raw(
+            "SELECT d.id, d.common FROM decks d, decks child
+             WHERE child.id = ?
+               AND (d.id = child.id
+                    OR substr(child.name, 1, length(d.name) + 1) = d.name || char(31))",
+        )?;
+        let decks = stmt
+            .query_map(params![deck], |row| {
+                Ok((row.get::<_, DeckId>(0)?, row.get::<_, Message>(1)?))
+            })?
+            .collect::<Result<Vec<_>>>()?;
+
+        let field = match kind {
+            0 => 4,
+            2 => 5,
+            _ => 6,
+        };
+        let millis = i32::try_from(millis).unwrap_or(i32::MAX);
+        let mut stmt = self.prepare_cached_raw("UPDATE decks SET common=?, usn=-1 WHERE id=?")?;
+        for (id, mut common) in decks {
+            if common.uint32(3) != today {
+                common
+                    .set_uint32(3, today)
+                    .set_int32(4, 0)
+                    .set_int32(5, 0)
+                    .set_int32(6, 0)
+                    .set_int32(7, 0);
+            }
+            let (count, total) = (
+                common.int32(field) + 1,
+                common.int32(7).saturating_add(millis),
+            );
+            common.set_int32(field, count).set_int32(7, total);
+            stmt.execute(params![common, id])?;
+        }
+        Ok(())
+    }
+
+    /// Answers a card in a filtered deck that doesn't reschedule cards,
+    /// which only delays it, or sends it home once it's done. Returns the
+    /// delay in seconds.
+    fn preview_card(
+        &self,
+        id: CardId,
+        reps: u32,
+        deck: &FilteredDeck,
+        ease: Ease,
+        now: i64,
+    ) -> Result<u32> {
+        let delay = match ease {
+            Ease::Again => deck.preview_again_secs,
+            Ease::Hard => deck.preview_hard_secs,
+            Ease::Good => deck.preview_good_secs,
+            Ease::Easy => 0,
+        };
+        if delay == 0 {
+            let mut stmt = self.prepare_raw("UPDATE cards SET reps=?, mod=?, usn=-1 WHERE id=?")?;
+            stmt.execute(params![reps + 1, now, id])?;
+            self.return_card(id)?;
+        } else {
+            let mut stmt = self
+                .prepare_raw("UPDATE cards SET queue=4, due=?, reps=?, mod=?, usn=-1 WHERE id=?")?;
+            stmt.execute(params![now + i64::from(delay), reps + 1, now, id])?;
+        }
+        Ok(delay)
+    }
+
+    /// Answers a card as if it had been studied in Anki, updating its
+    /// scheduling and writing an entry to the review log.
+    ///
+    /// The card is scheduled with SM-2 according to its home deck's
+    /// options. If it lapses often enough to become a leech, its note is
+    /// tagged `leech`, and the card is suspended if the options say so.
+    ///
+    /// Cards in filtered decks go back to their home decks once they reach
+    /// the review queue. In filtered decks that don't reschedule cards, the
+    /// card is only shown again after the deck's preview delay, and goes
+    /// home unchanged once it's answered Easy, or with a delay of zero.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::{Database, model::{CardId, Ease}};
+    /// use std::time::Duration;
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let id = CardId::from(1700000000000);
+    /// db.answer_card(id, Ease::Good, Duration::from_secs(5))?;
+    /// # Ok::<(), rusqlite::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the provided id does not match a card, if the card's
+    /// deck options can't be read, or if the database becomes unavailable.
+    pub fn answer_card(&self, id: CardId, ease: Ease, time_taken: Duration) -> Result<RevlogId> {
+        self.answer_card_at(id, ease, time_taken, now_secs())
+    }
+
+    /// Answers a card like [`Self::answer_card`], as if the time were `now`,
+    /// in seconds.
+    #[allow(clippy::cast_possible_truncation)]
+    pub(crate) fn answer_card_at(
+        &self,
+        id: CardId,
+        ease: Ease,
+        time_taken: Duration,
+        now: i64,
+    ) -> Result<RevlogId> {
+        self.transact(|db| {
+            let card = CardRow::read(db, id)?;
+            let filtered = i64::from(card.odid) != 0;
+
+            let options = db.deck_options(if filtered { card.odid } else { card.did })?;
+            let timing = db.timing_at(now)?;
+            let today = timing.days_elapsed;
+            let ctx = Context {
+                options: &options,
+                fuzz: fuzz_factor(id, card.reps),
+            };
+            let cap = match options.cap_answer_time {
+                0 => 60,
+                cap => cap,
+            };
+            let millis = time_taken.min(Duration::from_secs(cap.into())).as_millis() as i64;
+
+            let lapses = card.lapses + u32::from(card.kind == 2 && ease == Ease::Again);
+            let (next, revlog_type, last_ivl) = card.next(&ctx, today, ease);
+
+            let preview = if filtered {
+                Some(db.filtered_deck(card.did)?).filter(|deck| !deck.reschedule)
+            } else {
+                None
+            };
+            let (revlog_type, revlog_ivl, last_ivl, revlog_factor) = if let Some(deck) = preview {
+                let delay = db.preview_card(id, card.reps, &deck, ease, now)?;
+                (3, -i64::from(delay), last_ivl, card.factor)
+            } else {
+                let leech = card.kind == 2
+                    && ease == Ease::Again
+                    && is_leech(lapses, options.leech_threshold);
+                let outcome = ctx.outcome(next, card.factor, timing, now);
+                let queue = if leech && options.leech_action == LeechAction::Suspend {
+                    -1
+                } else {
+                    outcome.queue
+                };
+
+                // Like Anki, cards leave filtered decks once they're
+                // rescheduled as reviews
+                let (did, odid, odue) = if filtered && outcome.kind == 2 {
+                    (card.odid, DeckId::from(0), 0)
+                } else {
+                    (card.did, card.odid, card.odue)
+                };
+                let mut stmt = db.prepare_raw(
+                    "UPDATE cards
+                     SET did=?, odid=?, type=?, queue=?, due=?, odue=?, ivl=?, factor=?,
+                         left=?, reps=?, lapses=?, mod=?, usn=-1
+                     WHERE id=?",
+                )?;
+                stmt.execute(params![
+                    did,
+                    odid,
+                    outcome.kind,
+                    queue,
+                    outcome.due,
+                    odue,
+                    outcome.ivl,
+                    outcome.factor,
+                    outcome.left,
+                    card.reps + 1,
+                    lapses,
+                    now,
+                    id
+                ])?;
+                if leech {
+                    db.add_tag(card.nid, "leech")?;
+                }
+
+                let revlog_factor = if outcome.kind == 1 { 0 } else { outcome.factor };
+                (revlog_type, outcome.revlog_ivl, last_ivl, revlog_factor)
+            };
+
+            let revlog_id = db.next_revlog_id()?;
+            let mut stmt = db.prepare_raw(
+                "INSERT INTO revlog (id, cid, usn, ease, ivl, lastivl, factor, time, type)
+                 VALUES (?, ?, -1, ?, ?, ?, ?, ?, ?)",
+            )?;
+            stmt.execute(params![
+                revlog_id,
+                id,
+                i64::from(ease),
+                revlog_ivl,
+                last_ivl,
+                revlog_factor,
+                millis,
+                revlog_type
+            ])?;
+            db.record_studied(card.did, today, card.kind, millis)?;
+
+            Ok(revlog_id)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        deck::{FilteredOrder, FilteredSearch},
+        testing::Fixture,
+    };
+
+    /// 2023-11-14 22:13:20 UTC, almost six hours before the fixtures' day
+    /// ends at 4am UTC.
+    const NOW: i64 = 1_700_000_000;
+
+    fn options() -> Result<DeckOptions> {
+        Database::in_memory()?.deck_options(1.into())
+    }
+
+    fn timing(now: i64, until_rollover: i64) -> SchedTiming {
+        SchedTiming {
+            days_elapsed: 100,
+            next_day_at: now + until_rollover,
+        }
+    }
+
+    fn card(db: &Database, id: CardId) -> Result<CardRow> {
+        CardRow::read(db, id)
+    }
+
+    fn last_revlog(db: &Database) -> Result<(i64, i64, i64)> {
+        db.prepare_raw("SELECT ivl, lastIvl, type FROM revlog ORDER BY id DESC LIMIT 1")?
+            .query_row([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+    }
+
+    #[test]
+    fn lapse_interval_is_truncated_without_interval_multiplier() -> Result<()> {
+        let mut options = options()?;
+        options.relearn_steps.clear();
+        options.lapse_multiplier = 0.5;
+        options.interval_multiplier = 2.0;
+        options.minimum_lapse_interval = 1;
+        let ctx = Context {
+            options: &options,
+            fuzz: 0.5,
+        };
+        let steps = Steps(&[]);
+        assert!(matches!(
+            ctx.review(&steps, 5, 2.5, 0, Ease::Again),
+            Next::Review { ivl: 2, .. }
+        ));
+
+        options.minimum_lapse_interval = 3;
+        options.maximum_review_interval = 100;
+        let ctx = Context {
+            options: &options,
+            fuzz: 0.5,
+        };
+        assert!(matches!(
+            ctx.review(&steps, 5, 2.5, 0, Ease::Again),
+            Next::Review { ivl: 3, .. }
+        ));
+        options.lapse_multiplier = 1.0;
+        let ctx = Context {
+            options: &options,
+            fuzz: 0.5,
+        };
+        assert!(matches!(
+            ctx.review(&steps, 500, 2.5, 0, Ease::Again),
+            Next::Review { ivl: 100, .. }
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn leeches_are_flagged_every_half_threshold_rounded_up() {
+        let flagged = |threshold| {
+            (0..=12)
+                .filter(|&lapses| is_leech(lapses, threshold))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(flagged(5), [5, 8, 11]);
+        assert_eq!(flagged(8), [8, 12]);
+        assert_eq!(flagged(1), (1..=12).collect::<Vec<_>>());
+        assert!(flagged(0).is_empty());
+    }
+
+    #[test]
+    fn easy_graduating_interval_is_used_as_is() -> Result<()> {
+        let mut options = options()?;
+        options.graduating_interval_good = 3;
+        options.graduating_interval_easy = 2;
+        let ctx = Context {
+            options: &options,
+            fuzz: 0.5,
+        };
+        let steps = Steps(&options.learn_steps);
+        assert!(matches!(
+            ctx.learning(&steps, steps.count(), Ease::Easy),
+            Next::Review { ivl: 2, .. }
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn learning_steps_past_the_rollover_are_interday() -> Result<()> {
+        let options = options()?;
+        let ctx = Context {
+            options: &options,
+            fuzz: 0.0,
+        };
+        let learning = |secs| Next::Learning { remaining: 1, secs };
+
+        let outcome = ctx.outcome(learning(600), 0, timing(NOW, 3600), NOW);
+        assert_eq!(
+            (outcome.queue, outcome.due, outcome.revlog_ivl),
+            (1, NOW + 600, -600)
+        );
+
+        let outcome = ctx.outcome(learning(600), 0, timing(NOW, 300), NOW);
+        assert_eq!(
+            (outcome.queue, outcome.due, outcome.revlog_ivl),
+            (3, 101, 1)
+        );
+
+        let outcome = ctx.outcome(learning(2 * DAY_SECS), 0, timing(NOW, 3600), NOW);
+        assert_eq!(
+            (outcome.queue, outcome.due, outcome.revlog_ivl),
+            (3, 102, 2)
+        );
+
+        let relearning = Next::Relearning {
+            remaining: 1,
+            secs: DAY_SECS,
+            ivl: 10,
+            factor: 2.5,
+        };
+        let outcome = ctx.outcome(relearning, 0, timing(NOW, 3600), NOW);
+        assert_eq!(
+            (outcome.kind, outcome.queue, outcome.due, outcome.revlog_ivl),
+            (3, 3, 101, 1)
+        );
+        Ok(())
+    }
+
+    fn filtered_fixture(reschedule: bool) -> Result<(Database, DeckId, CardId, CardId)> {
+        let db = Fixture::at(NOW)
+            .with_deck("Japanese")
+            .with_note("Japanese", "Basic", &["猫", "cat"], &[])
+            .with_reviews(&[(20, Ease::Good), (19, Ease::Good), (16, Ease::Good)])
+            .with_note("Japanese", "Basic", &["犬", "dog"], &[])
+            .build()?;
+        let review = db.search_cards("is:review").expect("valid search")[0];
+        let new = db.search_cards("is:new").expect("valid search")[0];
+        let deck = db
+            .add_filtered_deck(
+                "Cram",
+                &FilteredDeck {
+                    search_terms: vec![FilteredSearch {
+                        search: "deck:Japanese".to_string(),
+                        limit: 10,
+                        order: FilteredOrder::Added,
+                    }],
+                    reschedule,
+                    ..FilteredDeck::default()
+                },
+            )
+            .expect("valid filtered deck");
+        db.build_filtered_deck(deck).expect("cards to gather");
+        Ok((db, deck, review, new))
+    }
+
+    #[test]
+    fn rescheduled_reviews_leave_filtered_decks() -> Result<()> {
+        let (db, deck, review, new) = filtered_fixture(true)?;
+        let home = db.id_for_deck("Japanese")?;
+        let before = card(&db, review)?;
+        assert_eq!(before.did, deck);
+
+        db.answer_card_at(review, Ease::Good, Duration::from_secs(5), NOW)?;
+        let after = card(&db, review)?;
+        assert_eq!((after.did, after.odid, after.odue), (home, 0.into(), 0));
+        assert_eq!(after.kind, 2);
+        assert!(after.ivl > before.ivl);
+        assert_eq!(
+            after.due,
+            i64::from(db.timing_at(NOW)?.days_elapsed + after.ivl)
+        );
+
+        // Learning cards stay until they graduate
+        let before = card(&db, new)?;
+        db.answer_card_at(new, Ease::Again, Duration::from_secs(5), NOW)?;
+        let after = card(&db, new)?;
+        assert_eq!(
+            (after.did, after.odid, after.odue),
+            (deck, home, before.odue)
+        );
+        assert_eq!(after.kind, 1);
+        db.answer_card_at(new, Ease::Easy, Duration::from_secs(5), NOW + 60)?;
+        let after = card(&db, new)?;
+        assert_eq!((after.did, after.odid, after.odue), (home, 0.into(), 0));
+        assert_eq!(after.kind, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn previewed_cards_keep_their_scheduling() -> Result<()> {
+        let (db, deck, review, _) = filtered_fixture(false)?;
+        let home = db.id_for_deck("Japanese")?;
+        let before = card(&db, review)?;
+
+        db.answer_card_at(review, Ease::Again, Duration::from_secs(5), NOW)?;
+        let after = card(&db, review)?;
+        assert_eq!((after.did, after.odid), (deck, home));
+        assert_eq!((after.due, after.reps), (NOW + 60, before.reps + 1));
+        assert_eq!(
+            (after.kind, after.ivl, after.factor, after.lapses),
+            (2, before.ivl, before.factor, before.lapses)
+        );
+        assert_eq!(last_revlog(&db)?, (-60, i64::from(before.ivl), 3));
+
+        db.answer_card_at(review, Ease::Easy, Duration::from_secs(5), NOW + 60)?;
+        let after = card(&db, review)?;
+        assert_eq!((after.did, after.odid, after.odue), (home, 0.into(), 0));
+        assert_eq!(
+            (after.due, after.ivl, after.factor),
+            (before.odue, before.ivl, before.factor)
+        );
+        let queue: i64 = db
+            .prepare_raw("SELECT queue FROM cards WHERE id = ?")?
+            .query_row(params![review], |row| row.get(0))?;
+        assert_eq!(queue, 2);
+        Ok(())
+    }
+}
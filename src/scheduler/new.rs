@@ -0,0 +1,224 @@
+//! The order new cards are shown in, and changing their positions.
+
+use crate::{
+    Database,
+    deck::{NewCardGatherPriority, NewCardSortOrder},
+    model::{CardId, DeckId, NoteId},
+    timing::now_secs,
+};
+use rusqlite::{OptionalExtension, Result, params};
+use std::{cmp::Reverse, collections::HashMap};
+
+/// A key that puts ids in a random order which stays the same all day, as
+/// Anki's salted hashes do. It won't match Anki's order.
+fn shuffled(id: i64, salt: i64) -> u64 {
+    // FNV-1a
+    id.to_le_bytes()
+        .into_iter()
+        .chain(salt.to_le_bytes())
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+struct NewCard {
+    id: CardId,
+    note: i64,
+    /// Where the card's deck is in the deck list.
+    deck: usize,
+    position: i64,
+    ord: i64,
+}
+
+impl Database {
+    /// Lists a deck's new cards, including those in its subdecks, in the
+    /// order the v3 scheduler would show them, following the new card
+    /// gather order and sort order in the deck's options. Suspended and
+    /// buried cards are left out.
+    ///
+    /// Anki only gathers as many cards as the deck's daily limit allows
+    /// before sorting them, and `limit` does the same. Pass the new count
+    /// from [`Self::deck_counts`] to get today's queue, or `None` for
+    /// every new card. Random orders change each day, and won't match
+    /// Anki's.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::Database;
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let id = db.id_for_deck("Japanese")?;
+    /// let today = db.deck_counts(id)?.new as usize;
+    /// for card in db.new_card_queue(id, Some(today))? {
+    ///     println!("{card:?}");
+    /// }
+    /// # Ok::<(), rusqlite::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the provided id does not match a deck, or if the
+    /// database becomes unavailable.
+    pub fn new_card_queue(&self, id: DeckId, limit: Option<usize>) -> Result<Vec<CardId>> {
+        let (gather, sort) = match self.deck_options(id) {
+            Ok(options) => (
+                options.new_card_gather_priority,
+                options.new_card_sort_order,
+            ),
+            // Filtered decks show cards in the order they were gathered
+            Err(rusqlite::Error::QueryReturnedNoRows) => (
+                NewCardGatherPriority::PositionLowestFirst,
+                NewCardSortOrder::NoSort,
+            ),
+            Err(e) => return Err(e),
+        };
+        let salt = i64::from(self.timing()?.days_elapsed);
+
+        // Decks in the deck list's order, each by position, which is the
+        // order for NewCardGatherPriority::Deck
+        let mut stmt = self.prepare_cached_raw(
+            "SELECT c.id, c.nid, c.did, c.due, c.ord
+             FROM cards c JOIN decks d ON d.id = c.did, decks parent
+             WHERE parent.id = ? AND c.queue = 0
+               AND (d.id = parent.id
+                    OR substr(d.name, 1, length(parent.name) + 1) = parent.name || char(31))
+             ORDER BY d.name, c.due, c.ord",
+        )?;
+        let mut rows = stmt.query(params![id])?;
+        let mut cards = Vec::new();
+        let mut last_deck = None;
+        let mut deck = 0;
+        while let Some(row) = rows.next()? {
+            let did: DeckId = row.get(2)?;
+            if last_deck.is_some_and(|last| last != did) {
+                deck += 1;
+            }
+            last_deck = Some(did);
+            cards.push(NewCard {
+                id: row.get(0)?,
+                note: row.get(1)?,
+                deck,
+                position: row.get(3)?,
+                ord: row.get(4)?,
+            });
+        }
+
+        match gather {
+            NewCardGatherPriority::Deck => {}
+            NewCardGatherPriority::PositionLowestFirst => {
+                cards.sort_by_key(|card| (card.position, card.ord));
+            }
+            NewCardGatherPriority::PositionHighestFirst => {
+                cards.sort_by_key(|card| (Reverse(card.position), card.ord));
+            }
+            NewCardGatherPriority::RandomNotes => {
+                cards.sort_by_key(|card| (shuffled(card.note, salt), card.ord));
+            }
+            NewCardGatherPriority::RandomCards => {
+                cards.sort_by_key(|card| shuffled(card.id.into(), salt));
+            }
+            NewCardGatherPriority::DeckThenRandomNotes => {
+                cards.sort_by_key(|card| (card.deck, shuffled(card.note, salt), card.ord));
+            }
+        }
+        if let Some(limit) = limit {
+            cards.truncate(limit);
+        }
+        // Sorts are stable, so ties stay in the order they were gathered
+        match sort {
+            NewCardSortOrder::Template => cards.sort_by_key(|card| card.ord),
+            NewCardSortOrder::NoSort => {}
+            NewCardSortOrder::TemplateThenRandom => {
+                cards.sort_by_key(|card| (card.ord, shuffled(card.id.into(), salt)));
+            }
+            NewCardSortOrder::RandomNoteThenTemplate => {
+                cards.sort_by_key(|card| (shuffled(card.note, salt), card.ord));
+            }
+            NewCardSortOrder::RandomCard => {
+                cards.sort_by_key(|card| shuffled(card.id.into(), salt));
+            }
+        }
+
+        Ok(cards.into_iter().map(|card| card.id).collect())
+    }
+
+    /// Gives new cards positions in the new queue, in the order given,
+    /// starting from `start` and going up by `step`, as Anki's Reposition
+    /// does. Like in Anki, cards of the same note share a position, and
+    /// cards that aren't new are skipped. Returns how many cards were
+    /// repositioned.
+    ///
+    /// With `shift_existing`, other new cards at or after `start` are moved
+    /// back to make room.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::Database;
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let cards = db.search_cards("deck:Japanese tag:jlpt-n5 is:new")?;
+    /// // Study these first
+    /// db.reposition_new_cards(&cards, 0, 1, true)?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the database becomes unavailable. Nothing is changed
+    /// if it fails.
+    pub fn reposition_new_cards(
+        &self,
+        cards: &[CardId],
+        start: u32,
+        step: u32,
+        shift_existing: bool,
+    ) -> Result<usize> {
+        let now = now_secs();
+        self.transact(|db| {
+            let mut positions = HashMap::new();
+            let mut new_cards = Vec::new();
+            for &card in cards {
+                let note: Option<NoteId> = db
+                    .prepare_cached_raw("SELECT nid FROM cards WHERE id = ? AND type = 0")?
+                    .query_row(params![card], |row| row.get(0))
+                    .optional()?;
+                if let Some(note) = note {
+                    let next = positions.len();
+                    let index = *positions.entry(note).or_insert(next);
+                    new_cards.push((card, index));
+                }
+            }
+            let position = |index: usize| {
+                i64::from(start) + i64::from(step) * i64::try_from(index).unwrap_or(i64::MAX)
+            };
+
+            // Cards in filtered decks keep their position in Cards::Odue
+            if shift_existing && !positions.is_empty() {
+                db.prepare_cached_raw(
+                    "UPDATE cards SET due = CASE WHEN odid = 0 THEN due + ?1 ELSE due END,
+                                      odue = CASE WHEN odid = 0 THEN odue ELSE odue + ?1 END,
+                                      mod = ?2, usn = -1
+                     WHERE type = 0 AND (CASE WHEN odid = 0 THEN due ELSE odue END) >= ?3",
+                )?
+                .execute(params![position(positions.len()) - i64::from(start), now, start])?;
+            }
+            for &(card, index) in &new_cards {
+                db.prepare_cached_raw(
+                    "UPDATE cards SET due = CASE WHEN odid = 0 THEN ?1 ELSE due END,
+                                      odue = CASE WHEN odid = 0 THEN odue ELSE ?1 END,
+                                      mod = ?2, usn = -1
+                     WHERE id = ?3",
+                )?
+                .execute(params![position(index), now, card])?;
+            }
+
+            // Cards added later go after the last position
+            let last: Option<i64> = db
+                .prepare_cached_raw(
+                    "SELECT MAX(CASE WHEN odid = 0 THEN due ELSE odue END) FROM cards WHERE type = 0",
+                )?
+                .query_row([], |row| row.get(0))?;
+            let next = db.config_i64("nextPos")?.unwrap_or(1);
+            if let Some(last) = last.filter(|&last| last >= next) {
+                db.set_config_json("nextPos", &(last + 1).to_string())?;
+            }
+            Ok(new_cards.len())
+        })
+    }
+}
@@ -0,0 +1,136 @@
+-- The schema of a version 18 collection, as Anki creates it.
+-- https://github.com/ankitects/anki/tree/30ae9f7c5408420c8f347073a9e5e62756a6d7cb/rslib/src/storage
+-- Objects that already exist are kept, so that this also adds the tables a
+-- schema 11 collection is missing.
+create table if not exists col (
+  id integer primary key,
+  crt integer not null,
+  mod integer not null,
+  scm integer not null,
+  ver integer not null,
+  dty integer not null,
+  usn integer not null,
+  ls integer not null,
+  conf text not null,
+  models text not null,
+  decks text not null,
+  dconf text not null,
+  tags text not null
+);
+create table if not exists notes (
+  id integer primary key,
+  guid text not null,
+  mid integer not null,
+  mod integer not null,
+  usn integer not null,
+  tags text not null,
+  flds text not null,
+  -- integer, so that numbers in the sort field sort numerically
+  sfld integer not null,
+  csum integer not null,
+  flags integer not null,
+  data text not null
+);
+create table if not exists cards (
+  id integer primary key,
+  nid integer not null,
+  did integer not null,
+  ord integer not null,
+  mod integer not null,
+  usn integer not null,
+  type integer not null,
+  queue integer not null,
+  due integer not null,
+  ivl integer not null,
+  factor integer not null,
+  reps integer not null,
+  lapses integer not null,
+  left integer not null,
+  odue integer not null,
+  odid integer not null,
+  flags integer not null,
+  data text not null
+);
+create table if not exists revlog (
+  id integer primary key,
+  cid integer not null,
+  usn integer not null,
+  ease integer not null,
+  ivl integer not null,
+  lastivl integer not null,
+  factor integer not null,
+  time integer not null,
+  type integer not null
+);
+create table if not exists graves (
+  oid integer not null,
+  type integer not null,
+  usn integer not null,
+  primary key (oid, type)
+) without rowid;
+create table if not exists deck_config (
+  id integer primary key not null,
+  name text not null collate unicase,
+  mtime_secs integer not null,
+  usn integer not null,
+  config blob not null
+);
+create table if not exists config (
+  key text not null primary key,
+  usn integer not null,
+  mtime_secs integer not null,
+  val blob not null
+) without rowid;
+create table if not exists fields (
+  ntid integer not null,
+  ord integer not null,
+  name text not null collate unicase,
+  config blob not null,
+  primary key (ntid, ord)
+) without rowid;
+create table if not exists templates (
+  ntid integer not null,
+  ord integer not null,
+  name text not null collate unicase,
+  mtime_secs integer not null,
+  usn integer not null,
+  config blob not null,
+  primary key (ntid, ord)
+) without rowid;
+create table if not exists notetypes (
+  id integer not null primary key,
+  name text not null collate unicase,
+  mtime_secs integer not null,
+  usn integer not null,
+  config blob not null
+);
+create table if not exists decks (
+  id integer primary key not null,
+  name text not null collate unicase,
+  mtime_secs integer not null,
+  usn integer not null,
+  common blob not null,
+  kind blob not null
+);
+create table if not exists tags (
+  tag text not null primary key collate unicase,
+  usn integer not null,
+  collapsed boolean not null,
+  config blob null
+) without rowid;
+
+create index if not exists ix_notes_usn on notes (usn);
+create index if not exists ix_cards_usn on cards (usn);
+create index if not exists ix_revlog_usn on revlog (usn);
+create index if not exists ix_cards_nid on cards (nid);
+create index if not exists ix_cards_sched on cards (did, queue, due);
+create index if not exists ix_revlog_cid on revlog (cid);
+create index if not exists ix_notes_csum on notes (csum);
+create index if not exists idx_notes_mid on notes (mid);
+create index if not exists idx_cards_odid on cards (odid) where odid != 0;
+create unique index if not exists idx_fields_name_ntid on fields (name, ntid);
+create unique index if not exists idx_templates_name_ntid on templates (name, ntid);
+create index if not exists idx_templates_usn on templates (usn);
+create unique index if not exists idx_notetypes_name on notetypes (name);
+create index if not exists idx_notetypes_usn on notetypes (usn);
+create unique index if not exists idx_decks_name on decks (name);
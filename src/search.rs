@@ -0,0 +1,597 @@
+//! Finding cards with Anki's search syntax.
+//!
+//! This covers the searches that come up most often, from
+//! <https://docs.ankiweb.net/searching.html>:
+//!
+//! - text, matched anywhere in a note's fields
+//! - `field:text`, matched against the whole of a field
+//! - `deck:`, `note:`, `tag:` and `card:`, where decks and tags include
+//!   their children, and `deck:filtered` matches cards in filtered decks
+//! - `is:new`, `is:learn`, `is:review`, `is:due`, `is:suspended` and
+//!   `is:buried`
+//! - `flag:N`, `rated:N`, `rated:N:EASE`, `added:N`, `nid:` and `cid:`
+//! - `prop:` with `ivl`, `due`, `reps`, `lapses`, `ease` and `pos`
+//!
+//! Text, names and tags can use `*` for any run of characters and `_` for
+//! a single one, and are matched ignoring case. Terms are combined with
+//! `and` (or just a space) and `or`, negated with a leading `-`, and
+//! grouped with parentheses. Quotes keep spaces in a term, and `\` escapes
+//! the character after it.
+//!
+//! ```rust,no_run
+//! # use ankidb::Database;
+//! let db = Database::open(&"/path/to/collection.anki2")?;
+//! let cards = db.search_cards("deck:Japanese (is:due or is:new) -tag:leech")?;
+//! println!("{} cards", cards.len());
+//! # Ok::<(), ankidb::search::Error>(())
+//! ```
+
+use crate::{Database, model::CardId, timing::now_secs};
+use rusqlite::{params_from_iter, types::Value};
+
+#[derive(Debug)]
+pub enum Error {
+    Database(rusqlite::Error),
+    /// The search couldn't be understood.
+    Invalid(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Database(e) => write!(f, "{e}"),
+            Self::Invalid(reason) => write!(f, "invalid search: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Database(e) => Some(e),
+            Self::Invalid(_) => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Self::Database(e)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Open,
+    Close,
+    Not,
+    And,
+    Or,
+    Term(String),
+}
+
+/// Splits a search into tokens. Quotes are removed, but escapes are kept
+/// for [`like_pattern`] and [`glob_matches`] to handle.
+fn tokenize(search: &str) -> Result<Vec<Token>, Error> {
+    let mut tokens = Vec::new();
+    let mut chars = search.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' | '-' => {
+                chars.next();
+                tokens.push(match c {
+                    '(' => Token::Open,
+                    ')' => Token::Close,
+                    _ => Token::Not,
+                });
+            }
+            _ => {
+                let mut term = String::new();
+                let mut quoted = false;
+                let mut was_quoted = false;
+                while let Some(&c) = chars.peek() {
+                    if !quoted && (c.is_whitespace() || c == '(' || c == ')') {
+                        break;
+                    }
+                    chars.next();
+                    match c {
+                        '"' => {
+                            quoted = !quoted;
+                            was_quoted = true;
+                        }
+                        '\\' => {
+                            term.push('\\');
+                            term.extend(chars.next());
+                        }
+                        c => term.push(c),
+                    }
+                }
+                if quoted {
+                    return Err(Error::Invalid("unclosed quote".to_string()));
+                }
+                tokens.push(match term.to_ascii_lowercase().as_str() {
+                    "and" if !was_quoted => Token::And,
+                    "or" if !was_quoted => Token::Or,
+                    _ => Token::Term(term),
+                });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Splits a term at its first unescaped `:`.
+fn split_term(term: &str) -> Option<(&str, &str)> {
+    let mut escaped = false;
+    for (i, c) in term.char_indices() {
+        match c {
+            ':' if !escaped => return Some((&term[..i], &term[i + 1..])),
+            '\\' => escaped = !escaped,
+            _ => escaped = false,
+        }
+    }
+    None
+}
+
+/// Turns text with Anki's wildcards into a pattern for `LIKE ... ESCAPE
+/// '\'`.
+fn like_pattern(text: &str) -> String {
+    let mut pattern = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(c @ ('_' | '%' | '\\')) => {
+                    pattern.push('\\');
+                    pattern.push(c);
+                }
+                Some(c) => pattern.push(c),
+                None => pattern.push_str("\\\\"),
+            },
+            '*' => pattern.push('%'),
+            '%' => pattern.push_str("\\%"),
+            c => pattern.push(c),
+        }
+    }
+    pattern
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Glob {
+    Any,
+    One,
+    Char(char),
+}
+
+/// Whether a name matches text with Anki's wildcards, ignoring case.
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let mut glob = Vec::new();
+    let mut chars = pattern.chars().flat_map(char::to_lowercase);
+    while let Some(c) = chars.next() {
+        glob.push(match c {
+            '*' => Glob::Any,
+            '_' => Glob::One,
+            '\\' => Glob::Char(chars.next().unwrap_or('\\')),
+            c => Glob::Char(c),
+        });
+    }
+    let name = name
+        .chars()
+        .flat_map(char::to_lowercase)
+        .collect::<Vec<_>>();
+
+    // Matches greedily, going back to the last `*` on a mismatch
+    let (mut g, mut n) = (0, 0);
+    let mut backtrack = None;
+    while n < name.len() {
+        match glob.get(g) {
+            Some(Glob::One) => (g, n) = (g + 1, n + 1),
+            Some(Glob::Char(c)) if *c == name[n] => (g, n) = (g + 1, n + 1),
+            Some(Glob::Any) => {
+                backtrack = Some((g, n));
+                g += 1;
+            }
+            _ => match backtrack {
+                Some((star, from)) => {
+                    backtrack = Some((star, from + 1));
+                    (g, n) = (star + 1, from + 1);
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[g..].iter().all(|&g| g == Glob::Any)
+}
+
+/// Lists ids for `IN`, or gives a condition that's never met for none.
+fn any_of(column: &str, ids: &[i64]) -> String {
+    if ids.is_empty() {
+        return "false".to_string();
+    }
+    let ids = ids.iter().map(i64::to_string).collect::<Vec<_>>();
+    format!("{column} IN ({})", ids.join(", "))
+}
+
+/// Parses the comma-separated ids of `nid:` and `cid:`.
+fn ids(value: &str, term: &str) -> Result<Vec<i64>, Error> {
+    value.split(',').map(|id| number(id.trim(), term)).collect()
+}
+
+fn number<T: std::str::FromStr>(text: &str, term: &str) -> Result<T, Error> {
+    text.parse()
+        .map_err(|_| Error::Invalid(format!("expected a number in {term}")))
+}
+
+/// A card's due date, or its home deck's one while it's in a filtered
+/// deck.
+const DUE: &str = "(CASE WHEN c.odue != 0 THEN c.odue ELSE c.due END)";
+
+/// Turns tokens into a condition on `cards c` joined with `notes n`.
+struct Writer<'a> {
+    db: &'a Database,
+    tokens: Vec<Token>,
+    pos: usize,
+    params: Vec<Value>,
+    today: i64,
+    day_start: i64,
+    learn_cutoff: i64,
+}
+
+impl Writer<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn or(&mut self) -> Result<String, Error> {
+        let mut parts = vec![self.and()?];
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            parts.push(self.and()?);
+        }
+        Ok(format!("({})", parts.join(") OR (")))
+    }
+
+    fn and(&mut self) -> Result<String, Error> {
+        let mut parts = vec![self.unary()?];
+        loop {
+            match self.peek() {
+                None | Some(Token::Close | Token::Or) => break,
+                Some(Token::And) => self.pos += 1,
+                _ => {}
+            }
+            parts.push(self.unary()?);
+        }
+        Ok(format!("({})", parts.join(") AND (")))
+    }
+
+    fn unary(&mut self) -> Result<String, Error> {
+        let token = self.peek().cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Not) => Ok(format!("NOT ({})", self.unary()?)),
+            Some(Token::Open) => {
+                let inner = self.or()?;
+                if self.peek() != Some(&Token::Close) {
+                    return Err(Error::Invalid("missing )".to_string()));
+                }
+                self.pos += 1;
+                Ok(inner)
+            }
+            Some(Token::Term(term)) => self.term(&term),
+            Some(token) => Err(Error::Invalid(format!("unexpected {token:?}"))),
+            None => Err(Error::Invalid("unexpected end of search".to_string())),
+        }
+    }
+
+    fn term(&mut self, term: &str) -> Result<String, Error> {
+        let Some((key, value)) = split_term(term) else {
+            self.params
+                .push(Value::Text(format!("%{}%", like_pattern(term))));
+            return Ok("n.flds LIKE ? ESCAPE '\\'".to_string());
+        };
+        match key.to_ascii_lowercase().as_str() {
+            "deck" => self.deck(value),
+            "note" => self.names("n.mid", "SELECT id, name FROM notetypes", value),
+            "tag" => Ok(self.tag(value)),
+            "card" => self.card(value),
+            "is" => self.state(value),
+            "flag" => match value.parse::<u8>() {
+                Ok(flag @ 0..=7) => Ok(format!("(c.flags & 7) = {flag}")),
+                _ => Err(Error::Invalid(format!("flags are 0 to 7 in {term}"))),
+            },
+            "prop" => self.prop(value, term),
+            "rated" => self.rated(value, term),
+            "added" => {
+                let days: i64 = number(value, term)?;
+                Ok(format!("c.id > {}", self.cutoff(days)))
+            }
+            "nid" => Ok(any_of("n.id", &ids(value, term)?)),
+            "cid" => Ok(any_of("c.id", &ids(value, term)?)),
+            _ => self.field(key, value),
+        }
+    }
+
+    /// The id that reviews and cards from the last `days` days, including
+    /// today, are newer than.
+    const fn cutoff(&self, days: i64) -> i64 {
+        (self.day_start - (days - 1) * 86_400) * 1000
+    }
+
+    fn deck(&self, value: &str) -> Result<String, Error> {
+        match value {
+            "*" => return Ok("true".to_string()),
+            "filtered" => return Ok("c.odid != 0".to_string()),
+            _ => {}
+        }
+        let decks = self
+            .db
+            .prepare_raw("SELECT id, name FROM decks")?
+            .query_map([], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let ids = decks
+            .into_iter()
+            .filter(|(_, name)| {
+                // A deck matches if it or one of its parents does
+                let name = name.replace('\x1f', "::");
+                name.match_indices("::")
+                    .map(|(end, _)| end)
+                    .chain([name.len()])
+                    .any(|end| glob_matches(value, &name[..end]))
+            })
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+        Ok(format!(
+            "{} OR {}",
+            any_of("c.did", &ids),
+            any_of("c.odid", &ids)
+        ))
+    }
+
+    /// Matches a column against the ids whose names match `value`.
+    fn names(&self, column: &str, sql: &str, value: &str) -> Result<String, Error> {
+        let ids = self
+            .db
+            .prepare_raw(sql)?
+            .query_map([], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+            })?
+            .filter_map(|row| match row {
+                Ok((id, name)) if glob_matches(value, &name) => Some(Ok(id)),
+                Ok(_) => None,
+                Err(e) => Some(Err(e)),
+            })
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(any_of(column, &ids))
+    }
+
+    fn tag(&mut self, value: &str) -> String {
+        if value.eq_ignore_ascii_case("none") {
+            return "n.tags = ''".to_string();
+        }
+        let tag = like_pattern(value);
+        self.params.push(Value::Text(format!("% {tag} %")));
+        self.params.push(Value::Text(format!("% {tag}::%")));
+        "n.tags LIKE ? ESCAPE '\\' OR n.tags LIKE ? ESCAPE '\\'".to_string()
+    }
+
+    fn card(&self, value: &str) -> Result<String, Error> {
+        if let Ok(number) = value.parse::<u32>() {
+            return Ok(format!("c.ord = {}", i64::from(number) - 1));
+        }
+        let templates = self
+            .db
+            .prepare_raw("SELECT ntid, ord, name FROM templates")?
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let conditions = templates
+            .into_iter()
+            .filter(|(_, _, name)| glob_matches(value, name))
+            .map(|(ntid, ord, _)| format!("(n.mid = {ntid} AND c.ord = {ord})"))
+            .collect::<Vec<_>>();
+        Ok(if conditions.is_empty() {
+            "false".to_string()
+        } else {
+            conditions.join(" OR ")
+        })
+    }
+
+    fn state(&self, value: &str) -> Result<String, Error> {
+        Ok(match value.to_ascii_lowercase().as_str() {
+            "new" => "c.type = 0".to_string(),
+            "learn" => "c.queue IN (1, 3)".to_string(),
+            "review" => "c.type IN (2, 3)".to_string(),
+            "due" => format!(
+                "(c.queue IN (2, 3) AND {DUE} <= {}) OR (c.queue IN (1, 4) AND {DUE} <= {})",
+                self.today, self.learn_cutoff
+            ),
+            "suspended" => "c.queue = -1".to_string(),
+            "buried" => "c.queue IN (-2, -3)".to_string(),
+            _ => return Err(Error::Invalid(format!("unknown state is:{value}"))),
+        })
+    }
+
+    fn prop(&mut self, value: &str, term: &str) -> Result<String, Error> {
+        let at = value
+            .find(['<', '>', '=', '!'])
+            .ok_or_else(|| Error::Invalid(format!("missing comparison in {term}")))?;
+        let (name, rest) = value.split_at(at);
+        let op = ["<=", ">=", "!=", "=", "<", ">"]
+            .into_iter()
+            .find(|op| rest.starts_with(op))
+            .ok_or_else(|| Error::Invalid(format!("unknown comparison in {term}")))?;
+        let rest = &rest[op.len()..];
+        Ok(match name.to_ascii_lowercase().as_str() {
+            "ivl" => format!("c.ivl {op} {}", number::<i64>(rest, term)?),
+            "due" => format!(
+                "c.queue IN (2, 3) AND {DUE} - {} {op} {}",
+                self.today,
+                number::<i64>(rest, term)?
+            ),
+            "reps" => format!("c.reps {op} {}", number::<i64>(rest, term)?),
+            "lapses" => format!("c.lapses {op} {}", number::<i64>(rest, term)?),
+            "pos" => format!("c.type = 0 AND c.due {op} {}", number::<i64>(rest, term)?),
+            "ease" => {
+                self.params
+                    .push(Value::Real(number::<f64>(rest, term)? * 1000.0));
+                format!("c.factor {op} ?")
+            }
+            _ => return Err(Error::Invalid(format!("unknown property in {term}"))),
+        })
+    }
+
+    fn rated(&self, value: &str, term: &str) -> Result<String, Error> {
+        let (days, ease) = match value.split_once(':') {
+            Some((days, ease)) => (days, Some(ease)),
+            None => (value, None),
+        };
+        let days: i64 = number(days, term)?;
+        let ease = match ease.map(str::parse::<u8>) {
+            None => "ease > 0".to_string(),
+            Some(Ok(ease @ 1..=4)) => format!("ease = {ease}"),
+            Some(_) => return Err(Error::Invalid(format!("answers are 1 to 4 in {term}"))),
+        };
+        Ok(format!(
+            "c.id IN (SELECT cid FROM revlog WHERE id > {} AND {ease})",
+            self.cutoff(days)
+        ))
+    }
+
+    fn field(&mut self, key: &str, value: &str) -> Result<String, Error> {
+        let fields = self
+            .db
+            .prepare_raw("SELECT ntid, ord, name FROM fields")?
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let pattern = like_pattern(value);
+        let mut conditions = Vec::new();
+        for (ntid, ord, _) in fields.iter().filter(|(_, _, name)| glob_matches(key, name)) {
+            self.params.push(Value::Text(pattern.clone()));
+            conditions.push(format!(
+                "(n.mid = {ntid} AND field_at_index(n.flds, {ord}) LIKE ? ESCAPE '\\')"
+            ));
+        }
+        Ok(if conditions.is_empty() {
+            "false".to_string()
+        } else {
+            conditions.join(" OR ")
+        })
+    }
+}
+
+/// Turns a search into a condition on `cards c` joined with `notes n`, and
+/// the parameters it needs.
+pub(crate) fn card_condition(db: &Database, search: &str) -> Result<(String, Vec<Value>), Error> {
+    let tokens = tokenize(search)?;
+    if tokens.is_empty() {
+        return Ok(("true".to_string(), Vec::new()));
+    }
+    let now = now_secs();
+    let timing = db.timing_at(now)?;
+    let mut writer = Writer {
+        db,
+        tokens,
+        pos: 0,
+        params: Vec::new(),
+        today: timing.days_elapsed.into(),
+        day_start: timing.day_start(),
+        learn_cutoff: now + db.config_i64("collapseTime")?.unwrap_or(1200),
+    };
+    let sql = writer.or()?;
+    if writer.pos < writer.tokens.len() {
+        return Err(Error::Invalid("unmatched )".to_string()));
+    }
+    Ok((sql, writer.params))
+}
+
+impl Database {
+    /// Finds the cards that match a search in Anki's search syntax, in the
+    /// order they were added. See the [module docs](self) for what's
+    /// supported.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::Database;
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let cards = db.search_cards("deck:Japanese::Vocab tag:verb is:review prop:ivl>=21")?;
+    /// # Ok::<(), ankidb::search::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the search can't be understood, or if the database
+    /// becomes unavailable.
+    pub fn search_cards(&self, search: &str) -> Result<Vec<CardId>, Error> {
+        let (condition, params) = card_condition(self, search)?;
+        let mut stmt = self.prepare_raw(&format!(
+            "SELECT c.id FROM cards c JOIN notes n ON n.id = c.nid WHERE {condition} ORDER BY c.id"
+        ))?;
+        let cards = stmt
+            .query_map(params_from_iter(params), |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(cards)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Fixture;
+
+    /// The first fields of the notes of the cards a search finds.
+    fn search(db: &Database, search: &str) -> Vec<String> {
+        let cards = db.search_cards(search).expect("valid search");
+        let mut stmt = db
+            .prepare_raw("SELECT n.sfld FROM cards c JOIN notes n ON n.id = c.nid WHERE c.id = ?")
+            .expect("valid query");
+        cards
+            .into_iter()
+            .map(|id| stmt.query_row([id], |row| row.get(0)).expect("card"))
+            .collect()
+    }
+
+    fn animals() -> rusqlite::Result<Database> {
+        Fixture::new()
+            .with_note("Default", "Basic", &["cat"], &["animal"])
+            .with_note("Default", "Basic", &["dog"], &["animal"])
+            .with_note("Default", "Basic", &["book"], &[])
+            .build()
+    }
+
+    #[test]
+    fn ids_are_matched_whatever_the_case() -> rusqlite::Result<()> {
+        let db = animals()?;
+        let (note, card): (i64, i64) = db
+            .prepare_raw("SELECT nid, id FROM cards ORDER BY id DESC")?
+            .query_row([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        assert_ne!(note, card);
+        assert_eq!(search(&db, &format!("NID:{note}")), ["book"]);
+        assert_eq!(search(&db, &format!("Cid:{card}")), ["book"]);
+        Ok(())
+    }
+
+    #[test]
+    fn negation_binds_tighter_than_and_and_or() -> rusqlite::Result<()> {
+        let db = animals()?;
+        assert_eq!(search(&db, "-tag:animal or dog"), ["dog", "book"]);
+        assert_eq!(search(&db, "cat or dog -tag:animal"), ["cat"]);
+        assert_eq!(search(&db, "-(cat or dog)"), ["book"]);
+        assert_eq!(search(&db, "- -cat"), ["cat"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,466 @@
+//! Projecting a deck's future workload by simulating reviews day by day.
+//!
+//! The simulation starts from the deck's cards as they are now, and decides
+//! whether each review passes or fails at random: with SM-2, at the rate
+//! the deck's young and mature cards have historically been passed; with
+//! FSRS, at the retrievability FSRS predicts. Which button is pressed, and
+//! how long answers take, are also drawn from the deck's review log.
+//!
+//! ```rust,no_run
+//! use ankidb::{Database, simulator::{self, Algorithm, SimulationConfig}};
+//!
+//! let db = Database::open(&"/path/to/collection.anki2")?;
+//! let deck = db.id_for_deck("Japanese")?;
+//! let config = SimulationConfig {
+//!     days: 90,
+//!     new_cards_per_day: 20,
+//!     review_limit: u32::MAX,
+//!     algorithm: Algorithm::Sm2,
+//!     seed: 1,
+//! };
+//! let days = simulator::simulate(&db, deck, &config)?;
+//! let last = days.last().unwrap();
+//! println!("{} reviews, {:.0} minutes", last.review, last.seconds / 60.0);
+//! # Ok::<(), rusqlite::Error>(())
+//! ```
+
+use crate::{
+    Database,
+    analytics::MATURE_IVL,
+    deck::DeckOptions,
+    fsrs::{CardData, Fsrs, MemoryState, ParameterCountError},
+    model::{DeckConfigId, DeckId, Ease},
+    scheduler::{Context, Next, Steps, with_review_fuzz},
+};
+use rusqlite::Result;
+use std::collections::{HashMap, HashSet, hash_map::Entry};
+
+/// The most answers a card can take to get through its learning steps in a
+/// day, in case the review log suggests it would never graduate.
+const MAX_STEPS: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Algorithm {
+    Sm2,
+    Fsrs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SimulationConfig {
+    /// How many days to simulate, starting today.
+    pub days: u32,
+    /// How many new cards to introduce each day. These don't need to exist
+    /// yet, since new cards are all alike until they're studied.
+    pub new_cards_per_day: u32,
+    /// The most reviews to do each day. Any others are left for the next
+    /// day, overdue.
+    pub review_limit: u32,
+    pub algorithm: Algorithm,
+    /// Seeds the random outcomes, so that a simulation can be repeated.
+    pub seed: u64,
+}
+
+/// The answers given on a simulated day, like [`crate::analytics::Reviews`].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SimulatedDay {
+    /// The day, where `0` is today.
+    pub day: u32,
+    pub new: u32,
+    pub learning: u32,
+    pub review: u32,
+    pub relearning: u32,
+    /// The time spent answering, in seconds.
+    pub seconds: f64,
+}
+
+impl SimulatedDay {
+    #[must_use]
+    pub const fn total(&self) -> u32 {
+        self.learning + self.review + self.relearning
+    }
+}
+
+/// A random number generator (xorshift64*), which is plenty for sampling
+/// outcomes.
+struct Rng(u64);
+
+impl Rng {
+    const fn new(seed: u64) -> Self {
+        Self(seed ^ 0x2545_F491_4F6C_DD1D | 1)
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn next(&mut self) -> f32 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// Picks a button, weighted by how often each was pressed.
+    fn ease(&mut self, counts: &[f64; 4]) -> Ease {
+        let total = counts.iter().sum::<f64>();
+        let mut target = f64::from(self.next()) * total;
+        for (ease, count) in [Ease::Again, Ease::Hard, Ease::Good, Ease::Easy]
+            .into_iter()
+            .zip(counts)
+        {
+            if target < *count {
+                return ease;
+            }
+            target -= count;
+        }
+        Ease::Good
+    }
+}
+
+/// How the deck has been studied, from its review log.
+#[derive(Debug, Clone, Copy)]
+struct Behaviour {
+    /// Buttons pressed for new and learning cards.
+    learning: [f64; 4],
+    /// Buttons pressed for relearning cards.
+    relearning: [f64; 4],
+    /// Buttons pressed for young and mature review cards.
+    young: [f64; 4],
+    mature: [f64; 4],
+    /// Seconds per answer, for learning, review and relearning cards.
+    seconds: [f64; 3],
+}
+
+impl Behaviour {
+    /// Reads the review log for cards whose home deck is one of `decks`.
+    /// Without any history, it's assumed that 90% of reviews pass, and
+    /// answers take ten seconds.
+    #[allow(clippy::cast_precision_loss)]
+    fn read(db: &Database, decks: &HashSet<DeckId>) -> Result<Self> {
+        let mut behaviour = Self {
+            learning: [0.0; 4],
+            relearning: [0.0; 4],
+            young: [0.0; 4],
+            mature: [0.0; 4],
+            seconds: [0.0; 3],
+        };
+        let mut answers = [0.0; 3];
+
+        let mut stmt = db.prepare_raw(
+            "SELECT CASE WHEN c.odid = 0 THEN c.did ELSE c.odid END,
+                 r.type, r.ease, r.lastivl, r.time
+             FROM revlog r JOIN cards c ON c.id = r.cid
+             WHERE r.ease BETWEEN 1 AND 4 AND r.type < 3",
+        )?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            if !decks.contains(&row.get(0)?) {
+                continue;
+            }
+            let (kind, ease, lastivl, millis): (usize, usize, i64, f64) =
+                (row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?);
+            let counts = match kind {
+                0 => &mut behaviour.learning,
+                2 => &mut behaviour.relearning,
+                _ if lastivl < MATURE_IVL => &mut behaviour.young,
+                _ => &mut behaviour.mature,
+            };
+            counts[ease - 1] += 1.0;
+            behaviour.seconds[kind] += millis / 1000.0;
+            answers[kind] += 1.0;
+        }
+
+        for counts in [&mut behaviour.learning, &mut behaviour.relearning] {
+            if counts.iter().sum::<f64>() == 0.0 {
+                *counts = [0.0, 0.0, 1.0, 0.0];
+            }
+        }
+        for counts in [&mut behaviour.young, &mut behaviour.mature] {
+            if counts.iter().sum::<f64>() == 0.0 {
+                *counts = [1.0, 0.0, 9.0, 0.0];
+            }
+        }
+        for (seconds, answers) in behaviour.seconds.iter_mut().zip(answers) {
+            *seconds = if answers > 0.0 {
+                *seconds / answers
+            } else {
+                10.0
+            };
+        }
+
+        Ok(behaviour)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SimulatedCard {
+    preset: DeckConfigId,
+    due: i64,
+    last_review: i64,
+    ivl: u32,
+    factor: f32,
+    memory: Option<MemoryState>,
+}
+
+struct Simulator<'a> {
+    config: &'a SimulationConfig,
+    presets: HashMap<DeckConfigId, (DeckOptions, Fsrs)>,
+    behaviour: Behaviour,
+    rng: Rng,
+}
+
+impl Simulator<'_> {
+    /// Takes a card through its learning or relearning steps, answering
+    /// until it graduates, and schedules its next review.
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    fn graduate(
+        &mut self,
+        card: &mut SimulatedCard,
+        mut next: Next,
+        day: i64,
+        stats: &mut SimulatedDay,
+    ) {
+        let (options, fsrs) = &self.presets[&card.preset];
+        let learning = matches!(next, Next::Learning { .. });
+        for _ in 0..MAX_STEPS {
+            let ctx = Context {
+                options,
+                fuzz: self.rng.next(),
+            };
+            let (counts, steps) = match next {
+                Next::Learning { .. } => (&self.behaviour.learning, Steps(&options.learn_steps)),
+                Next::Relearning { .. } => {
+                    (&self.behaviour.relearning, Steps(&options.relearn_steps))
+                }
+                Next::Review { .. } => break,
+            };
+            let ease = self.rng.ease(counts);
+            if learning {
+                stats.learning += 1;
+                stats.seconds += self.behaviour.seconds[0];
+            } else {
+                stats.relearning += 1;
+                stats.seconds += self.behaviour.seconds[2];
+            }
+            card.memory = Some(card.memory.map_or_else(
+                || fsrs.initial_state(ease),
+                |memory| fsrs.next_state(memory, ease, 0),
+            ));
+            next = match next {
+                Next::Learning { remaining, .. } => ctx.learning(&steps, remaining, ease),
+                Next::Relearning {
+                    remaining,
+                    ivl,
+                    factor,
+                    ..
+                } => ctx.relearning(&steps, remaining, ivl, factor, ease),
+                Next::Review { .. } => next,
+            };
+        }
+
+        let (ivl, factor) = match next {
+            Next::Review { ivl, factor } | Next::Relearning { ivl, factor, .. } => (ivl, factor),
+            Next::Learning { .. } => (options.graduating_interval_good.max(1), card.factor),
+        };
+        card.factor = factor;
+        card.ivl = match (self.config.algorithm, card.memory) {
+            (Algorithm::Fsrs, Some(memory)) => {
+                let ctx = Context {
+                    options,
+                    fuzz: self.rng.next(),
+                };
+                let interval = fsrs.interval(memory, options.desired_retention);
+                with_review_fuzz(ctx.fuzz, interval, 1, ctx.maximum_interval())
+            }
+            _ => ivl,
+        };
+        card.last_review = day;
+        card.due = day + i64::from(card.ivl);
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn review(&mut self, card: &mut SimulatedCard, day: i64, stats: &mut SimulatedDay) {
+        let (options, fsrs) = &self.presets[&card.preset];
+        let elapsed = (day - card.last_review).max(0);
+        let counts = if i64::from(card.ivl) < MATURE_IVL {
+            self.behaviour.young
+        } else {
+            self.behaviour.mature
+        };
+        let pass_rate =
+            if let (Algorithm::Fsrs, Some(memory)) = (self.config.algorithm, card.memory) {
+                f64::from(fsrs.retrievability(memory, elapsed as f32))
+            } else {
+                1.0 - counts[0] / counts.iter().sum::<f64>()
+            };
+
+        let passed = f64::from(self.rng.next()) < pass_rate;
+        let ease = if passed {
+            self.rng.ease(&[0.0, counts[1], counts[2], counts[3]])
+        } else {
+            Ease::Again
+        };
+        stats.review += 1;
+        stats.seconds += self.behaviour.seconds[1];
+
+        let ctx = Context {
+            options,
+            fuzz: self.rng.next(),
+        };
+        let next = ctx.review(
+            &Steps(&options.relearn_steps),
+            card.ivl,
+            card.factor,
+            day - card.due,
+            ease,
+        );
+        card.memory = card
+            .memory
+            .map(|memory| fsrs.next_state(memory, ease, u32::try_from(elapsed).unwrap_or(0)));
+
+        if passed {
+            if let Next::Review { ivl, factor } = next {
+                card.factor = factor;
+                card.ivl = match (self.config.algorithm, card.memory) {
+                    (Algorithm::Fsrs, Some(memory)) => with_review_fuzz(
+                        ctx.fuzz,
+                        fsrs.interval(memory, options.desired_retention),
+                        1,
+                        ctx.maximum_interval(),
+                    ),
+                    _ => ivl,
+                };
+            }
+            card.last_review = day;
+            card.due = day + i64::from(card.ivl);
+        } else {
+            self.graduate(card, next, day, stats);
+        }
+    }
+
+    fn run(
+        &mut self,
+        mut cards: Vec<SimulatedCard>,
+        new_preset: DeckConfigId,
+    ) -> Vec<SimulatedDay> {
+        let mut days = Vec::new();
+        for day in 0..self.config.days {
+            let mut stats = SimulatedDay {
+                day,
+                ..SimulatedDay::default()
+            };
+            let today = i64::from(day);
+
+            let mut due = (0..cards.len())
+                .filter(|i| cards[*i].due <= today)
+                .collect::<Vec<_>>();
+            due.sort_by_key(|i| cards[*i].due);
+            for i in due.into_iter().take(self.config.review_limit as usize) {
+                let mut card = cards[i];
+                self.review(&mut card, today, &mut stats);
+                cards[i] = card;
+            }
+
+            let (options, _) = &self.presets[&new_preset];
+            let (initial_ease, steps) = (options.initial_ease, Steps(&options.learn_steps).count());
+            for _ in 0..self.config.new_cards_per_day {
+                let mut card = SimulatedCard {
+                    preset: new_preset,
+                    due: today,
+                    last_review: today,
+                    ivl: 0,
+                    factor: initial_ease,
+                    memory: None,
+                };
+                let next = Next::Learning {
+                    remaining: steps,
+                    secs: 0,
+                };
+                stats.new += 1;
+                self.graduate(&mut card, next, today, &mut stats);
+                cards.push(card);
+            }
+
+            days.push(stats);
+        }
+        days
+    }
+}
+
+/// Simulates studying a deck and its children for `config.days` days.
+///
+/// # Errors
+///
+/// This can fail if the provided id does not match a normal deck, if a
+/// preset can't be decoded or has an unsupported number of FSRS
+/// parameters, or if the database becomes unavailable.
+#[allow(clippy::cast_precision_loss)]
+pub fn simulate(
+    db: &Database,
+    deck: DeckId,
+    config: &SimulationConfig,
+) -> Result<Vec<SimulatedDay>> {
+    let decks = db.deck_and_children(deck)?;
+    let mut presets = HashMap::new();
+    let mut deck_presets = HashMap::new();
+    for id in &decks {
+        // Filtered decks have no preset, and none of the cards' home decks
+        // can be filtered
+        let Ok(options) = db.deck_options(*id) else {
+            continue;
+        };
+        deck_presets.insert(*id, options.id);
+        if let Entry::Vacant(entry) = presets.entry(options.id) {
+            let params = &options.fsrs_params;
+            let fsrs = Fsrs::new(params).ok_or(ParameterCountError(params.len()))?;
+            entry.insert((options, fsrs));
+        }
+    }
+    let new_preset = db.deck_options(deck)?.id;
+    let decks = decks.into_iter().collect::<HashSet<_>>();
+    let today = i64::from(db.timing()?.days_elapsed);
+
+    let mut cards = Vec::new();
+    let mut stmt = db.prepare_raw(
+        "SELECT CASE WHEN odid = 0 THEN did ELSE odid END,
+             CASE WHEN odid != 0 AND odue != 0 THEN odue ELSE due END,
+             ivl, factor, data
+         FROM cards
+         WHERE type IN (2, 3) AND queue != -1",
+    )?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let Some(preset) = deck_presets.get(&row.get(0)?) else {
+            continue;
+        };
+        let (due, ivl, factor): (i64, u32, u32) = (row.get(1)?, row.get(2)?, row.get(3)?);
+        let factor = factor as f32 / 1000.0;
+        let data = CardData::parse(&row.get::<_, String>(4)?);
+        let fsrs = &presets[preset].1;
+        // Relearning cards are due by timestamp, and were reviewed today
+        let (due, last_review) = if due > 1_000_000_000 {
+            (0, 0)
+        } else {
+            (due - today, due - today - i64::from(ivl))
+        };
+        cards.push(SimulatedCard {
+            preset: *preset,
+            due,
+            last_review,
+            ivl,
+            factor,
+            memory: Some(
+                data.memory
+                    .unwrap_or_else(|| fsrs.memory_state_from_sm2(factor, ivl as f32, 0.9)),
+            ),
+        });
+    }
+
+    let mut simulator = Simulator {
+        config,
+        presets,
+        behaviour: Behaviour::read(db, &decks)?,
+        rng: Rng::new(config.seed),
+    };
+    Ok(simulator.run(cards, new_preset))
+}
@@ -0,0 +1,154 @@
+//! The notetypes, deck and options that Anki creates new collections with.
+//!
+//! These follow
+//! <https://github.com/ankitects/anki/tree/30ae9f7c5408420c8f347073a9e5e62756a6d7cb/rslib/src/notetype/stock.rs>
+//! and Anki's default deck options.
+
+use crate::{model::DeckConfigId, proto::Message};
+
+const DEFAULT_CSS: &str = "\
+.card {
+    font-family: arial;
+    font-size: 20px;
+    line-height: 1.5;
+    text-align: center;
+    color: black;
+    background-color: white;
+}
+";
+
+const CLOZE_CSS: &str = "\
+.cloze {
+    font-weight: bold;
+    color: blue;
+}
+.nightMode .cloze {
+    color: lightblue;
+}
+";
+
+const DEFAULT_LATEX_HEADER: &str = r"\documentclass[12pt]{article}
+\special{papersize=3in,5in}
+\usepackage[utf8]{inputenc}
+\usepackage{amssymb,amsmath}
+\pagestyle{empty}
+\setlength{\parindent}{0in}
+\begin{document}
+";
+
+const DEFAULT_LATEX_FOOTER: &str = r"\end{document}";
+
+/// The `Notetype.Config.OriginalStockKind` that Anki records, so that stock
+/// notetypes can be restored to their defaults.
+const ORIGINAL_STOCK_BASIC: u64 = 1;
+const ORIGINAL_STOCK_BASIC_AND_REVERSED: u64 = 2;
+const ORIGINAL_STOCK_CLOZE: u64 = 5;
+
+pub struct StockNotetype {
+    pub name: &'static str,
+    pub config: Message,
+    pub fields: Vec<(&'static str, Message)>,
+    pub templates: Vec<(&'static str, Message)>,
+}
+
+/// The config of a notetype with Anki's defaults. `kind` is 1 for cloze
+/// notetypes.
+pub fn notetype_config(kind: u64) -> Message {
+    let css = if kind == 1 {
+        format!("{DEFAULT_CSS}\n{CLOZE_CSS}")
+    } else {
+        DEFAULT_CSS.to_string()
+    };
+    Message::new()
+        .set_varint(1, kind)
+        .set_string(3, &css)
+        .set_string(5, DEFAULT_LATEX_HEADER)
+        .set_string(6, DEFAULT_LATEX_FOOTER)
+        .clone()
+}
+
+pub fn field_config() -> Message {
+    Message::new()
+        .set_string(3, "Arial")
+        .set_uint32(4, 20)
+        .clone()
+}
+
+pub fn template_config(question: &str, answer: &str) -> Message {
+    Message::new()
+        .set_string(1, question)
+        .set_string(2, answer)
+        .clone()
+}
+
+fn answer_with(field: &str) -> String {
+    format!("{{{{FrontSide}}}}\n\n<hr id=answer>\n\n{{{{{field}}}}}")
+}
+
+/// Basic, Basic (and reversed card), and Cloze.
+pub fn notetypes() -> Vec<StockNotetype> {
+    let basic_fields = || vec![("Front", field_config()), ("Back", field_config())];
+    let card_1 = || ("Card 1", template_config("{{Front}}", &answer_with("Back")));
+
+    vec![
+        StockNotetype {
+            name: "Basic",
+            config: notetype_config(0)
+                .set_varint(9, ORIGINAL_STOCK_BASIC)
+                .clone(),
+            fields: basic_fields(),
+            templates: vec![card_1()],
+        },
+        StockNotetype {
+            name: "Basic (and reversed card)",
+            config: notetype_config(0)
+                .set_varint(9, ORIGINAL_STOCK_BASIC_AND_REVERSED)
+                .clone(),
+            fields: basic_fields(),
+            templates: vec![
+                card_1(),
+                ("Card 2", template_config("{{Back}}", &answer_with("Front"))),
+            ],
+        },
+        StockNotetype {
+            name: "Cloze",
+            config: notetype_config(1)
+                .set_varint(9, ORIGINAL_STOCK_CLOZE)
+                .clone(),
+            fields: vec![("Text", field_config()), ("Back Extra", field_config())],
+            templates: vec![(
+                "Cloze",
+                template_config("{{cloze:Text}}", "{{cloze:Text}}<br>\n{{Back Extra}}"),
+            )],
+        },
+    ]
+}
+
+/// The options of Anki's Default preset.
+pub fn deck_config() -> Message {
+    Message::new()
+        .set_floats(1, &[1.0, 10.0])
+        .set_floats(2, &[10.0])
+        .set_uint32(9, 20)
+        .set_uint32(10, 200)
+        .set_float(11, 2.5)
+        .set_float(12, 1.3)
+        .set_float(13, 1.2)
+        .set_float(15, 1.0)
+        .set_uint32(16, 36_500)
+        .set_uint32(17, 1)
+        .set_uint32(18, 1)
+        .set_uint32(19, 4)
+        .set_varint(21, 1)
+        .set_uint32(22, 8)
+        .set_uint32(24, 60)
+        .set_float(37, 0.9)
+        .clone()
+}
+
+/// The `kind` of a normal deck using the given options.
+pub fn normal_deck_kind(config: DeckConfigId) -> Message {
+    Message::new()
+        .set_message(1, Message::new().set_int64(1, config.into()))
+        .clone()
+}
@@ -0,0 +1,485 @@
+//! Rendering of card templates, using Anki's mustache-like syntax.
+//!
+//! ```rust
+//! use ankidb::template::{self, Side};
+//! use std::collections::{HashMap, HashSet};
+//!
+//! let fields = HashMap::from([("Front", "犬[いぬ]"), ("Back", "dog")]);
+//! let question = template::render("{{kanji:Front}}{{#Hint}}?{{/Hint}}", &fields, 0, Side::Question);
+//! assert_eq!(question, "犬");
+//! ```
+
+use crate::{
+    Database, cloze, furigana,
+    model::{CardId, NotetypeId, NotetypeKind},
+    proto::Message,
+    text::{encode_attribute, field_is_empty, strip_html},
+};
+use rusqlite::{Result, params};
+use std::collections::{HashMap, HashSet};
+
+/// Which side of a card is being rendered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Side {
+    Question,
+    Answer,
+}
+
+/// The rendered HTML for both sides of a card.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenderedCard {
+    pub question: String,
+    pub answer: String,
+}
+
+#[derive(Debug)]
+enum Node<'a> {
+    Text(&'a str),
+    Replacement {
+        key: &'a str,
+        filters: Vec<&'a str>,
+    },
+    Conditional {
+        key: &'a str,
+        negated: bool,
+        children: Vec<Self>,
+    },
+}
+
+fn parse(template: &str) -> Vec<Node<'_>> {
+    // Each entry is an open section's key, whether it's negated, and the
+    // nodes collected so far
+    let mut stack: Vec<(&str, bool, Vec<Node>)> = vec![("", false, Vec::new())];
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start + 2..].find("}}").map(|i| i + start + 2) else {
+            break;
+        };
+        if start > 0 {
+            stack.last_mut().unwrap().2.push(Node::Text(&rest[..start]));
+        }
+        let tag = rest[start + 2..end].trim();
+        let raw = &rest[start..end + 2];
+        rest = &rest[end + 2..];
+
+        if let Some(key) = tag.strip_prefix('#') {
+            stack.push((key.trim(), false, Vec::new()));
+        } else if let Some(key) = tag.strip_prefix('^') {
+            stack.push((key.trim(), true, Vec::new()));
+        } else if let Some(key) = tag.strip_prefix('/') {
+            let key = key.trim();
+            if stack.len() > 1 && stack.last().unwrap().0 == key {
+                let (key, negated, children) = stack.pop().unwrap();
+                stack.last_mut().unwrap().2.push(Node::Conditional {
+                    key,
+                    negated,
+                    children,
+                });
+            } else {
+                stack.last_mut().unwrap().2.push(Node::Text(raw));
+            }
+        } else {
+            let mut parts = tag.rsplit(':');
+            let key = parts.next().unwrap_or_default().trim();
+            let mut filters = parts.map(str::trim).collect::<Vec<_>>();
+            filters.reverse();
+            stack
+                .last_mut()
+                .unwrap()
+                .2
+                .push(Node::Replacement { key, filters });
+        }
+    }
+
+    if !rest.is_empty() {
+        stack.last_mut().unwrap().2.push(Node::Text(rest));
+    }
+
+    // Unclosed sections extend to the end of the template
+    while stack.len() > 1 {
+        let (key, negated, children) = stack.pop().unwrap();
+        stack.last_mut().unwrap().2.push(Node::Conditional {
+            key,
+            negated,
+            children,
+        });
+    }
+
+    stack.pop().unwrap().2
+}
+
+/// A stable hash, so that hint ids don't change between renders.
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn hint(text: &str, field: &str) -> String {
+    if text.trim().is_empty() {
+        return String::new();
+    }
+    let id = fnv1a(text);
+    format!(
+        r##"<a class=hint href="#" onclick="this.style.display='none';document.getElementById('hint{id}').style.display='block';return false;" draggable=false>{field}</a><div id="hint{id}" class=hint style="display: none">{text}</div>"##
+    )
+}
+
+fn tts(filter: &str, text: &str) -> String {
+    let mut args = filter.split_whitespace().skip(1);
+    let lang = args.next().unwrap_or_default();
+    let options = args.collect::<Vec<_>>();
+    if options.is_empty() {
+        format!("[anki:tts lang={lang}]{text}[/anki:tts]")
+    } else {
+        format!(
+            "[anki:tts lang={lang} {}]{text}[/anki:tts]",
+            options.join(" ")
+        )
+    }
+}
+
+fn type_answer(spec: &str, text: &str, side: Side) -> String {
+    match side {
+        Side::Question => format!(r#"<input type="text" id="typeans" data-field="{spec}">"#),
+        // strip_html decodes entities, so they have to be escaped again
+        Side::Answer => format!(
+            r#"<code id="typeans">{}</code>"#,
+            encode_attribute(&strip_html(text))
+        ),
+    }
+}
+
+fn apply_filters(key: &str, filters: &[&str], text: &str, ord: u16, side: Side) -> String {
+    if filters.first() == Some(&"type") {
+        let spec = filters[1..]
+            .iter()
+            .chain(std::iter::once(&key))
+            .copied()
+            .collect::<Vec<_>>()
+            .join(":");
+        let text = if filters.contains(&"cloze") {
+            cloze::parse(text)
+                .iter()
+                .filter_map(|node| match node {
+                    cloze::Node::Cloze(c) if c.ordinals.contains(&(ord + 1)) => Some(c.text()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        } else {
+            text.to_string()
+        };
+        return type_answer(&spec, &text, side);
+    }
+
+    let mut text = text.to_string();
+    for filter in filters.iter().rev() {
+        text = match *filter {
+            "text" => strip_html(&text),
+            "furigana" => furigana::furigana(&text),
+            "kana" => furigana::kana(&text),
+            "kanji" => furigana::kanji(&text),
+            "hint" => hint(&text, key),
+            "cloze" => match side {
+                Side::Question => cloze::render_question(&text, ord),
+                Side::Answer => cloze::render_answer(&text, ord),
+            },
+            f if f == "tts" || f.starts_with("tts ") => tts(f, &text),
+            // Unknown filters are left to other tools, like Anki's add-ons
+            _ => text,
+        };
+    }
+    text
+}
+
+fn render_nodes(
+    nodes: &[Node],
+    fields: &HashMap<&str, &str>,
+    ord: u16,
+    side: Side,
+    out: &mut String,
+) {
+    for node in nodes {
+        match node {
+            Node::Text(t) => out.push_str(t),
+            Node::Replacement { key, filters } => match fields.get(key) {
+                Some(text) => out.push_str(&apply_filters(key, filters, text, ord, side)),
+                None if filters.first() == Some(&"type") => {
+                    out.push_str(&apply_filters(key, filters, "", ord, side));
+                }
+                None => {
+                    out.push_str("{unknown field ");
+                    out.push_str(key);
+                    out.push('}');
+                }
+            },
+            Node::Conditional {
+                key,
+                negated,
+                children,
+            } => {
+                let present = fields.get(key).is_some_and(|t| !field_is_empty(t));
+                if present != *negated {
+                    render_nodes(children, fields, ord, side, out);
+                }
+            }
+        }
+    }
+}
+
+/// Whether a template would show any of the given non-empty fields, which
+/// is how Anki decides whether a note should have a card for it.
+///
+/// This follows Anki's `template_is_empty`: a conditional section counts
+/// if anything inside it does, whatever its own field, so
+/// `{{#Hint}}{{Front}}{{/Hint}}` counts when only `Front` is non-empty. A
+/// negated section is skipped when its field is non-empty, since it
+/// wouldn't be shown; Anki's legacy required-fields cache doesn't skip it,
+/// but card generation does.
+pub(crate) fn renders_with_fields(template: &str, nonempty: &HashSet<&str>) -> bool {
+    fn walk(nodes: &[Node], nonempty: &HashSet<&str>) -> bool {
+        nodes.iter().any(|node| match node {
+            Node::Text(_) => false,
+            Node::Replacement { key, .. } => nonempty.contains(key),
+            Node::Conditional {
+                key, negated: true, ..
+            } if nonempty.contains(key) => false,
+            Node::Conditional { children, .. } => walk(children, nonempty),
+        })
+    }
+    walk(&parse(template), nonempty)
+}
+
+/// Splits a template into pieces of text, each followed by the contents of
+/// a `{{...}}` tag, except at the end.
+fn tokens(template: &str) -> Vec<(&str, Option<&str>)> {
+    let mut tokens = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start + 2..].find("}}").map(|i| i + start + 2) else {
+            break;
+        };
+        tokens.push((&rest[..start], Some(&rest[start + 2..end])));
+        rest = &rest[end + 2..];
+    }
+    tokens.push((rest, None));
+    tokens
+}
+
+fn push_tag(out: &mut String, tag: &str) {
+    out.push_str("{{");
+    out.push_str(tag);
+    out.push_str("}}");
+}
+
+/// Splits a tag into its prefix (a section marker or filters) and the
+/// field it refers to.
+fn split_tag(tag: &str) -> (&str, &str) {
+    let tag = tag.trim();
+    if tag.starts_with(['#', '^', '/']) {
+        (&tag[..1], tag[1..].trim())
+    } else {
+        tag.rfind(':')
+            .map_or(("", tag), |i| (&tag[..=i], tag[i + 1..].trim()))
+    }
+}
+
+/// Points a template's references to a field at its new name.
+pub(crate) fn rename_field(template: &str, old: &str, new: &str) -> String {
+    let mut out = String::with_capacity(template.len());
+    for (text, tag) in tokens(template) {
+        out.push_str(text);
+        match tag.map(|tag| (tag, split_tag(tag))) {
+            Some((_, (prefix, key))) if key == old => push_tag(&mut out, &format!("{prefix}{new}")),
+            Some((tag, _)) => push_tag(&mut out, tag),
+            None => {}
+        }
+    }
+    out
+}
+
+/// Removes a template's references to a field, keeping what it renders
+/// the same now that the field is always empty: `{{#Field}}` sections are
+/// removed with their contents, and `{{^Field}}` sections are unwrapped.
+pub(crate) fn remove_field(template: &str, name: &str) -> String {
+    let mut out = String::with_capacity(template.len());
+    // Open sections being removed, and unwrapped sections whose end tag
+    // still has to go
+    let mut removing = 0;
+    let mut unwrapped = 0;
+    for (text, tag) in tokens(template) {
+        if removing == 0 {
+            out.push_str(text);
+        }
+        let Some(tag) = tag else {
+            continue;
+        };
+        let (prefix, key) = split_tag(tag);
+        if key != name {
+            if removing == 0 {
+                push_tag(&mut out, tag);
+            }
+            continue;
+        }
+        match prefix {
+            "^" if removing == 0 => unwrapped += 1,
+            "#" | "^" => removing += 1,
+            "/" if removing > 0 => removing -= 1,
+            "/" if unwrapped > 0 => unwrapped -= 1,
+            "/" => push_tag(&mut out, tag),
+            _ => {}
+        }
+    }
+    out
+}
+
+/// Renders one side of a template.
+///
+/// `fields` maps field names to their contents, and may include special
+/// fields like `Tags`, `Deck`, and (on the answer side) `FrontSide`. `ord` is
+/// the card's ordinal (`Cards::Ord`), which selects the active cloze for the
+/// `cloze:` filter.
+#[must_use]
+pub fn render<S: std::hash::BuildHasher>(
+    template: &str,
+    fields: &HashMap<&str, &str, S>,
+    ord: u16,
+    side: Side,
+) -> String {
+    let fields = fields.iter().map(|(k, v)| (*k, *v)).collect();
+    let mut out = String::new();
+    render_nodes(&parse(template), &fields, ord, side, &mut out);
+    out
+}
+
+/// Renders both sides of a card, providing the question as `{{FrontSide}}`
+/// to the answer template.
+#[must_use]
+pub fn render_card<S: std::hash::BuildHasher>(
+    qfmt: &str,
+    afmt: &str,
+    fields: &HashMap<&str, &str, S>,
+    ord: u16,
+) -> RenderedCard {
+    let mut fields = fields
+        .iter()
+        .map(|(k, v)| (*k, *v))
+        .collect::<HashMap<_, _>>();
+    let question = render(qfmt, &fields, ord, Side::Question);
+    fields.insert("FrontSide", &question);
+    let answer = render(afmt, &fields, ord, Side::Answer);
+    RenderedCard { question, answer }
+}
+
+impl Database {
+    /// Renders the question and answer HTML of a card, using its notetype's
+    /// templates.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::Database;
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let card = db.render_card(1234.into())?;
+    /// println!("{}", card.question);
+    /// # Ok::<(), rusqlite::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the provided id does not match a card, if its
+    /// notetype or template is missing, or if the database becomes
+    /// unavailable.
+    pub fn render_card(&self, id: CardId) -> Result<RenderedCard> {
+        let mut stmt = self.prepare_raw(
+            "SELECT c.ord, c.flags, n.flds, n.tags, n.mid, nt.name, nt.config, d.name
+             FROM cards c
+             JOIN notes n ON n.id = c.nid
+             JOIN notetypes nt ON nt.id = n.mid
+             LEFT JOIN decks d ON d.id = (CASE WHEN c.odid != 0 THEN c.odid ELSE c.did END)
+             WHERE c.id = ?",
+        )?;
+        let (ord, flags, flds, tags, mid, notetype, config, deck): (
+            u16,
+            i64,
+            String,
+            String,
+            NotetypeId,
+            String,
+            Message,
+            Option<String>,
+        ) = stmt.query_row(params![id], |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+                row.get(5)?,
+                row.get(6)?,
+                row.get(7)?,
+            ))
+        })?;
+
+        // Cloze notetypes have a single template shared by every card
+        let kind = NotetypeKind::from(config.varint(1));
+        let template_ord = if kind == NotetypeKind::Cloze { 0 } else { ord };
+        let mut stmt =
+            self.prepare_raw("SELECT name, config FROM templates WHERE ntid=? AND ord=?")?;
+        let (template, config): (String, Message) = stmt
+            .query_row(params![mid, template_ord], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?;
+
+        let names = self.fields_for_notetype(mid)?;
+        let deck = deck.unwrap_or_default().replace('\x1f', "::");
+        let subdeck = deck.rsplit("::").next().unwrap_or_default().to_string();
+        let flag = flags & 0b111;
+        let flag = if flag == 0 {
+            String::new()
+        } else {
+            format!("flag{flag}")
+        };
+        let card_id = id.to_string();
+        let tags = tags.trim();
+
+        let mut fields = names
+            .iter()
+            .map(String::as_str)
+            .zip(crate::parse_fields(&flds))
+            .collect::<HashMap<_, _>>();
+        fields.insert("Tags", tags);
+        fields.insert("Type", &notetype);
+        fields.insert("Deck", &deck);
+        fields.insert("Subdeck", &subdeck);
+        fields.insert("Card", &template);
+        fields.insert("CardFlag", &flag);
+        fields.insert("CardID", &card_id);
+
+        Ok(render_card(
+            &config.string(1),
+            &config.string(2),
+            &fields,
+            ord,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn card_generation_follows_anki() {
+        let renders = |template, fields: &[&str]| {
+            renders_with_fields(template, &fields.iter().copied().collect())
+        };
+        assert!(renders("{{Front}}", &["Front"]));
+        assert!(!renders("{{Front}}", &["Back"]));
+        assert!(renders("{{#Hint}}{{Front}}{{/Hint}}", &["Front"]));
+        assert!(!renders("{{#Hint}}hint{{/Hint}}", &["Hint"]));
+        assert!(renders("{{^X}}{{Front}}{{/X}}", &["Front"]));
+        assert!(!renders("{{^X}}{{Front}}{{/X}}", &["Front", "X"]));
+        assert!(renders("{{^X}}{{Front}}{{/X}}{{X}}", &["Front", "X"]));
+    }
+}
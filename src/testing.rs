@@ -0,0 +1,431 @@
+//! Building small collections in memory, for testing code that queries them.
+//!
+//! Each [`Fixture`] starts from [`Database::in_memory`], a new collection
+//! with the Default deck and Anki's stock notetypes, and adds decks,
+//! notetypes, notes and review history to it. Decks and notetypes are
+//! referred to by name, and ids are handed out in order from a fixed base,
+//! skipping any that the stock notetypes already use.
+//! Review history is relative to today, and the collection's creation time
+//! and stock notetypes come from the clock, unless the fixture is built with
+//! [`Fixture::at`], which always produces the same database.
+//!
+//! ```rust
+//! use ankidb::{model::Ease, query::{self, AnkiExt}, testing::Fixture};
+//!
+//! let db = Fixture::new()
+//!     .with_deck("Japanese::Vocab")
+//!     .with_note("Japanese::Vocab", "Basic", &["猫", "cat"], &["animal"])
+//!     .with_reviews(&[(10, Ease::Good), (9, Ease::Good), (6, Ease::Again)])
+//!     .with_note("Japanese::Vocab", "Basic", &["犬", "dog"], &[])
+//!     .build()?;
+//!
+//! let (mut stmt, bind) = db.prepare(query::revlog().count_star())?;
+//! let reviews: i64 = stmt.query_row(&*bind.as_params(), |row| row.get(0))?;
+//! assert_eq!(reviews, 3);
+//! # Ok::<(), rusqlite::Error>(())
+//! ```
+
+use crate::{
+    Database, cloze,
+    model::{CardId, Ease, NoteId, NotetypeId, NotetypeKind, parse_fields},
+    proto::Message,
+    stock,
+    text::{field_checksum, strip_html_preserving_media_filenames},
+    timing::now_secs,
+};
+use rusqlite::{Result, params};
+use std::collections::HashSet;
+
+/// Where fixture ids start, in milliseconds like the timestamps Anki uses
+/// for ids: 2020-09-13.
+const ID_BASE: i64 = 1_600_000_000_000;
+
+const DAY_SECS: i64 = 86_400;
+
+/// A collection to build, from [`Fixture::new`].
+#[derive(Debug, Clone, Default)]
+#[must_use]
+pub struct Fixture {
+    /// The time the collection is built at, in seconds, or `None` for now.
+    now: Option<i64>,
+    decks: Vec<String>,
+    notetypes: Vec<NotetypeSpec>,
+    notes: Vec<NoteSpec>,
+}
+
+#[derive(Debug, Clone)]
+struct NotetypeSpec {
+    name: String,
+    fields: Vec<String>,
+    templates: Vec<(String, String, String)>,
+}
+
+#[derive(Debug, Clone)]
+struct NoteSpec {
+    deck: String,
+    notetype: String,
+    fields: Vec<String>,
+    tags: Vec<String>,
+    reviews: Vec<(u32, Ease)>,
+}
+
+impl Fixture {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts a fixture that's built as if the time were `now`, in seconds
+    /// since the epoch, rather than the current time. Review history is
+    /// relative to that day.
+    ///
+    /// ```rust
+    /// use ankidb::{model::Ease, testing::Fixture};
+    ///
+    /// let build = || {
+    ///     Fixture::at(1_700_000_000)
+    ///         .with_note("Default", "Basic", &["front", "back"], &[])
+    ///         .with_reviews(&[(3, Ease::Good)])
+    ///         .build()
+    /// };
+    /// let crt = |db: &ankidb::Database| {
+    ///     db.prepare_raw("SELECT crt FROM col")?
+    ///         .query_row([], |row| row.get::<_, i64>(0))
+    /// };
+    /// assert_eq!(crt(&build()?)?, crt(&build()?)?);
+    /// # Ok::<(), rusqlite::Error>(())
+    /// ```
+    pub fn at(now: i64) -> Self {
+        Self {
+            now: Some(now),
+            ..Self::default()
+        }
+    }
+
+    /// Adds a deck, along with any of its parents that don't exist yet.
+    /// Names use `::` between levels, as in Anki.
+    pub fn with_deck(mut self, name: &str) -> Self {
+        self.decks.push(name.to_string());
+        self
+    }
+
+    /// Adds a normal notetype, with templates given as `(name, question,
+    /// answer)`.
+    pub fn with_notetype(
+        mut self,
+        name: &str,
+        fields: &[&str],
+        templates: &[(&str, &str, &str)],
+    ) -> Self {
+        self.notetypes.push(NotetypeSpec {
+            name: name.to_string(),
+            fields: fields.iter().map(ToString::to_string).collect(),
+            templates: templates
+                .iter()
+                .map(|&(name, q, a)| (name.to_string(), q.to_string(), a.to_string()))
+                .collect(),
+        });
+        self
+    }
+
+    /// Adds a note to a deck, with new cards for each of its notetype's
+    /// templates, or each of its clozes. Missing fields are left empty.
+    pub fn with_note(mut self, deck: &str, notetype: &str, fields: &[&str], tags: &[&str]) -> Self {
+        self.notes.push(NoteSpec {
+            deck: deck.to_string(),
+            notetype: notetype.to_string(),
+            fields: fields.iter().map(ToString::to_string).collect(),
+            tags: tags.iter().map(ToString::to_string).collect(),
+            reviews: Vec::new(),
+        });
+        self
+    }
+
+    /// Gives the cards of the last note added a review history, as
+    /// `(days_ago, ease)` pairs. The cards end up in the review queue.
+    ///
+    /// Intervals grow roughly like SM-2 with Anki's default options, but
+    /// without learning steps or fuzz: a card's first review sets a 1 day
+    /// interval (4 days for Easy), Again resets it to 1 day and counts a
+    /// lapse, and Hard, Good and Easy multiply it by 1.2, 2.5 and 3.25.
+    ///
+    /// # Panics
+    ///
+    /// This panics if no note has been added yet.
+    pub fn with_reviews(mut self, reviews: &[(u32, Ease)]) -> Self {
+        let note = self
+            .notes
+            .last_mut()
+            .expect("with_reviews must come after with_note");
+        note.reviews.extend_from_slice(reviews);
+        note.reviews
+            .sort_by_key(|&(days_ago, _)| std::cmp::Reverse(days_ago));
+        self
+    }
+
+    /// Creates the collection.
+    ///
+    /// # Errors
+    ///
+    /// This can fail if a note refers to a deck or notetype that doesn't
+    /// exist, or if names conflict with existing ones.
+    pub fn build(self) -> Result<Database> {
+        let now = self.now.unwrap_or_else(now_secs);
+        let db = Database::in_memory_at(now)?;
+        db.transact(|db| self.write(db, now))?;
+        Ok(db)
+    }
+
+    fn write(&self, db: &Database, now: i64) -> Result<()> {
+        let mut ids = Ids {
+            last: ID_BASE,
+            taken: db
+                .prepare_raw("SELECT id FROM notetypes UNION SELECT id FROM decks")?
+                .query_map([], |row| row.get(0))?
+                .collect::<Result<_>>()?,
+        };
+
+        // Move the collection's creation back so that every review happened
+        // after it
+        let oldest = self
+            .notes
+            .iter()
+            .flat_map(|n| &n.reviews)
+            .map(|&(days_ago, _)| i64::from(days_ago))
+            .max()
+            .unwrap_or_default();
+        db.prepare_raw("UPDATE col SET crt = crt - ?")?
+            .execute(params![oldest * DAY_SECS])?;
+
+        self.write_decks(db, &mut ids)?;
+        self.write_notetypes(db, &mut ids)?;
+        self.write_notes(db, &mut ids, now)
+    }
+
+    fn write_decks(&self, db: &Database, ids: &mut Ids) -> Result<()> {
+        for name in &self.decks {
+            let mut path = String::new();
+            for component in name.split("::") {
+                if !path.is_empty() {
+                    path.push('\x1f');
+                }
+                path.push_str(component);
+                db.prepare_cached_raw(
+                    "INSERT INTO decks SELECT ?, ?, 0, 0, ?, ?
+                     WHERE NOT EXISTS (SELECT 1 FROM decks WHERE name = ?2)",
+                )?
+                .execute(params![
+                    ids.next(),
+                    path,
+                    Message::new().encode(),
+                    stock::normal_deck_kind(1.into()).encode()
+                ])?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_notetypes(&self, db: &Database, ids: &mut Ids) -> Result<()> {
+        for notetype in &self.notetypes {
+            let ntid = ids.next();
+            db.prepare_cached_raw("INSERT INTO notetypes VALUES (?, ?, 0, 0, ?)")?
+                .execute(params![
+                    ntid,
+                    notetype.name,
+                    stock::notetype_config(0).encode()
+                ])?;
+            for (ord, name) in notetype.fields.iter().enumerate() {
+                db.prepare_cached_raw("INSERT INTO fields VALUES (?, ?, ?, ?)")?
+                    .execute(params![ntid, ord, name, stock::field_config().encode()])?;
+            }
+            for (ord, (name, q, a)) in notetype.templates.iter().enumerate() {
+                db.prepare_cached_raw("INSERT INTO templates VALUES (?, ?, ?, 0, 0, ?)")?
+                    .execute(params![
+                        ntid,
+                        ord,
+                        name,
+                        stock::template_config(q, a).encode()
+                    ])?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_notes(&self, db: &Database, ids: &mut Ids, now: i64) -> Result<()> {
+        let timing = db.timing_at(now)?;
+        for (position, note) in (1..).zip(&self.notes) {
+            let deck = db.id_for_deck(&note.deck.replace("::", "\x1f"))?;
+            let notetype = db.id_for_notetype(&note.notetype)?;
+            let nid = NoteId::from(ids.next());
+            let fields = note_fields(db, notetype, note)?;
+
+            let first = parse_fields(&fields).next().unwrap_or_default();
+            let tags = if note.tags.is_empty() {
+                String::new()
+            } else {
+                format!(" {} ", note.tags.join(" "))
+            };
+            db.prepare_cached_raw("INSERT INTO notes VALUES (?, ?, ?, 0, 0, ?, ?, ?, ?, 0, '')")?
+                .execute(params![
+                    nid,
+                    format!("fixture{}", i64::from(nid) - ID_BASE),
+                    notetype,
+                    tags,
+                    fields,
+                    strip_html_preserving_media_filenames(first),
+                    field_checksum(first),
+                ])?;
+            // Like Anki, register parent tags too
+            for tag in &note.tags {
+                for (end, _) in tag.match_indices("::").chain([(tag.len(), "")]) {
+                    db.prepare_cached_raw("INSERT OR IGNORE INTO tags VALUES (?, 0, 0, NULL)")?
+                        .execute(params![&tag[..end]])?;
+                }
+            }
+
+            let ords: Vec<u16> = match db.notetype_kind(notetype)? {
+                NotetypeKind::Cloze => cloze::card_ords(parse_fields(&fields))
+                    .into_iter()
+                    .collect(),
+                NotetypeKind::Normal => {
+                    let count: u16 = db
+                        .prepare_cached_raw("SELECT COUNT(*) FROM templates WHERE ntid = ?")?
+                        .query_row(params![notetype], |row| row.get(0))?;
+                    (0..count).collect()
+                }
+            };
+            for ord in ords {
+                let cid = CardId::from(ids.next());
+                db.prepare_cached_raw(
+                    "INSERT INTO cards VALUES (?, ?, ?, ?, 0, 0, 0, 0, ?, 0, 0, 0, 0, 0, 0, 0, 0, '')",
+                )?
+                .execute(params![cid, nid, deck, ord, position])?;
+                write_reviews(
+                    db,
+                    ids,
+                    cid,
+                    &note.reviews,
+                    i64::from(timing.days_elapsed),
+                    timing.day_start(),
+                )?;
+            }
+        }
+
+        db.prepare_raw("UPDATE config SET val = ? WHERE key = 'nextPos'")?
+            .execute(params![(self.notes.len() + 1).to_string().into_bytes()])?;
+
+        Ok(())
+    }
+}
+
+/// Hands out ids in order, skipping those already taken.
+struct Ids {
+    last: i64,
+    taken: HashSet<i64>,
+}
+
+impl Ids {
+    fn next(&mut self) -> i64 {
+        self.last += 1;
+        while self.taken.contains(&self.last) {
+            self.last += 1;
+        }
+        self.last
+    }
+}
+
+/// The note's fields joined the way Anki stores them, padded to the
+/// notetype's field count.
+fn note_fields(db: &Database, notetype: NotetypeId, note: &NoteSpec) -> Result<String> {
+    let count = db.fields_for_notetype(notetype)?.len();
+    let mut fields = note.fields.clone();
+    fields.resize(count.max(fields.len()), String::new());
+    Ok(fields.join("\x1f"))
+}
+
+/// Writes a card's review history to the revlog, and moves the card into
+/// the review queue with the interval it ended on.
+fn write_reviews(
+    db: &Database,
+    ids: &mut Ids,
+    card: CardId,
+    reviews: &[(u32, Ease)],
+    today: i64,
+    day_start: i64,
+) -> Result<()> {
+    let Some(&(last_days_ago, _)) = reviews.last() else {
+        return Ok(());
+    };
+
+    let mut ivl = 0;
+    let mut lapses = 0;
+    for (index, &(days_ago, ease)) in (0..).zip(reviews) {
+        let last_ivl = ivl;
+        let first = index == 0;
+        ivl = match ease {
+            Ease::Easy if first => 4,
+            _ if first => 1,
+            Ease::Again => {
+                lapses += 1;
+                1
+            }
+            Ease::Hard => grow(ivl, 1.2),
+            Ease::Good => grow(ivl, 2.5),
+            Ease::Easy => grow(ivl, 3.25),
+        };
+        // Midday, offset by a fresh id so that reviews on the same day
+        // stay unique and in order
+        let at = (day_start - i64::from(days_ago) * DAY_SECS + DAY_SECS / 2) * 1000
+            + (ids.next() - ID_BASE);
+        db.prepare_cached_raw("INSERT INTO revlog VALUES (?, ?, 0, ?, ?, ?, ?, 10000, ?)")?
+            .execute(params![
+                at,
+                card,
+                i64::from(ease),
+                ivl,
+                last_ivl,
+                if first { 0 } else { 2500 },
+                i32::from(!first),
+            ])?;
+    }
+
+    db.prepare_cached_raw(
+        "UPDATE cards SET type = 2, queue = 2, due = ?, ivl = ?, factor = 2500, reps = ?, lapses = ?
+         WHERE id = ?",
+    )?
+    .execute(params![
+        today - i64::from(last_days_ago) + ivl,
+        ivl,
+        reviews.len(),
+        lapses,
+        card
+    ])?;
+    Ok(())
+}
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+fn grow(ivl: i64, factor: f64) -> i64 {
+    ((ivl as f64 * factor).round() as i64).max(ivl + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_skip_the_stock_notetypes() -> Result<()> {
+        // The stock notetypes' ids come from the clock, so here they start
+        // right after ID_BASE
+        let db = Fixture::at(ID_BASE / 1000)
+            .with_notetype("Vocab", &["Word"], &[("Card 1", "{{Word}}", "")])
+            .with_note("Default", "Vocab", &["猫"], &[])
+            .build()?;
+        let notetypes: i64 = db
+            .prepare_raw("SELECT COUNT(*) FROM notetypes")?
+            .query_row([], |row| row.get(0))?;
+        assert_eq!(notetypes, 4);
+        assert!(db.check(false)?.is_ok());
+        Ok(())
+    }
+}
@@ -0,0 +1,308 @@
+//! Helpers for the HTML that Anki stores in note fields.
+
+use sha1::{Digest, Sha1};
+use std::fmt::Write;
+
+/// Escapes text for use inside a double-quoted HTML attribute.
+pub fn encode_attribute(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '"' => out.push_str("&quot;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Decodes the HTML entities that commonly appear in fields.
+pub fn decode_entities(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let decoded = rest.find(';').filter(|end| *end <= 10).and_then(|end| {
+            let entity = &rest[1..end];
+            let c = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some('\u{a0}'),
+                _ => entity
+                    .strip_prefix("#x")
+                    .or_else(|| entity.strip_prefix("#X"))
+                    .map(|hex| u32::from_str_radix(hex, 16))
+                    .or_else(|| entity.strip_prefix('#').map(str::parse))
+                    .and_then(Result::ok)
+                    .and_then(char::from_u32),
+            };
+            c.map(|c| (c, end))
+        });
+        if let Some((c, end)) = decoded {
+            out.push(c);
+            rest = &rest[end + 1..];
+        } else {
+            out.push('&');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Removes a block like `<style>...</style>`, matching case-insensitively.
+fn remove_blocks(text: &str, open: &str, close: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let lower = text.to_ascii_lowercase();
+    let mut pos = 0;
+    while let Some(start) = lower[pos..].find(open).map(|i| i + pos) {
+        out.push_str(&text[pos..start]);
+        pos = lower[start..]
+            .find(close)
+            .map_or(text.len(), |end| start + end + close.len());
+    }
+    out.push_str(&text[pos..]);
+    out
+}
+
+/// Removes tags, comments, scripts and styles without decoding entities.
+fn remove_tags(text: &str) -> String {
+    let text = remove_blocks(text, "<!--", "-->");
+    let text = remove_blocks(&text, "<style", "</style>");
+    let text = remove_blocks(&text, "<script", "</script>");
+
+    let mut out = String::with_capacity(text.len());
+    let mut in_tag = false;
+    for c in text.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            c if !in_tag => out.push(c),
+            _ => {}
+        }
+    }
+    out
+}
+
+/// Removes tags, comments, scripts and styles, leaving just the text
+/// content.
+pub fn strip_html(text: &str) -> String {
+    decode_entities(&remove_tags(text)).trim().to_string()
+}
+
+/// The value of an attribute, given the contents of a tag after its name.
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let lower = tag.to_ascii_lowercase();
+    let mut pos = 0;
+    let start = loop {
+        let found = pos + lower[pos..].find(name)?;
+        pos = found + name.len();
+        let preceded = lower[..found].ends_with(|c: char| c.is_whitespace());
+        if preceded && lower[pos..].starts_with('=') {
+            break pos + 1;
+        }
+    };
+    let value = &tag[start..];
+    let value = match value.chars().next()? {
+        quote @ ('"' | '\'') => value[1..].split(quote).next()?,
+        _ => value
+            .split(|c: char| c.is_whitespace() || c == '>')
+            .next()?,
+    };
+    Some(value.trim_end_matches('/'))
+}
+
+/// Finds the tags that Anki treats as referring to media files, calling
+/// `f` with the text before each tag and the filename it refers to.
+/// Returns the text after the last tag.
+fn media_tags<'a>(text: &'a str, mut f: impl FnMut(&'a str, &'a str)) -> &'a str {
+    let mut rest = text;
+    let mut pos = 0;
+    while let Some(start) = rest[pos..].find('<').map(|i| pos + i) {
+        let Some(end) = rest[start..].find('>').map(|i| start + i) else {
+            break;
+        };
+        let tag = &rest[start + 1..end];
+        let name_end = tag
+            .find(|c: char| c.is_whitespace() || c == '/')
+            .unwrap_or(tag.len());
+        let source = match tag[..name_end].to_ascii_lowercase().as_str() {
+            "img" | "audio" | "video" | "source" => attribute(&tag[name_end..], "src"),
+            "object" => attribute(&tag[name_end..], "data"),
+            _ => None,
+        };
+        if let Some(source) = source {
+            f(&rest[..start], source);
+            rest = &rest[end + 1..];
+            pos = 0;
+        } else {
+            pos = end + 1;
+        }
+    }
+    rest
+}
+
+/// Strips HTML the way Anki does before checksumming a field, keeping the
+/// filenames of images and other media so that notes differing only by
+/// media aren't considered duplicates.
+pub fn strip_html_preserving_media_filenames(text: &str) -> String {
+    let mut with_filenames = String::with_capacity(text.len());
+    let rest = media_tags(text, |before, source| {
+        with_filenames.push_str(before);
+        with_filenames.push(' ');
+        with_filenames.push_str(source);
+        with_filenames.push(' ');
+    });
+    with_filenames.push_str(rest);
+
+    decode_entities(&remove_tags(&with_filenames).replace("&nbsp;", " "))
+}
+
+/// The media files referenced by a field, through media tags like `<img>`
+/// or `[sound:...]`, in the order they appear. Remote URLs are skipped.
+pub fn media_references(text: &str) -> Vec<String> {
+    let mut refs = Vec::new();
+    media_tags(text, |_, source| refs.push(decode_entities(source)));
+
+    let mut rest = text;
+    while let Some(start) = rest.find("[sound:") {
+        rest = &rest[start + 7..];
+        let Some(end) = rest.find(']') else {
+            break;
+        };
+        refs.push(decode_entities(&rest[..end]));
+        rest = &rest[end + 1..];
+    }
+
+    refs.retain(|r| !r.is_empty() && !r.contains("://"));
+    refs
+}
+
+/// The images that Anki generates for the LaTeX in a field, named after
+/// the SHA-1 of the LaTeX: `latex-<sha1>.png`, or `.svg` for notetypes
+/// that render LaTeX as SVG. `[latex]`, `[$]` and `[$$]` are supported.
+pub fn latex_references(text: &str, svg: bool) -> Vec<String> {
+    const KINDS: [(&str, &str); 3] = [("[latex]", "[/latex]"), ("[$]", "[/$]"), ("[$$]", "[/$$]")];
+    let extension = if svg { "svg" } else { "png" };
+    let lower = text.to_ascii_lowercase();
+    let mut refs = Vec::new();
+    let mut pos = 0;
+    while let Some(start) = lower[pos..].find('[').map(|i| pos + i) {
+        pos = start + 1;
+        for (index, (open, close)) in KINDS.into_iter().enumerate() {
+            if !lower[start..].starts_with(open) {
+                continue;
+            }
+            let body = start + open.len();
+            // The LaTeX can't be empty, so look for the end after its first
+            // character
+            let Some(first) = text[body..].chars().next() else {
+                continue;
+            };
+            let Some(end) = lower[body + first.len_utf8()..]
+                .find(close)
+                .map(|i| body + first.len_utf8() + i)
+            else {
+                continue;
+            };
+            let latex = &text[body..end];
+            let latex = match index {
+                0 => latex.to_string(),
+                1 => format!("${latex}$"),
+                _ => format!(r"\begin{{displaymath}}{latex}\end{{displaymath}}"),
+            };
+            let hash = Sha1::digest(strip_html_for_latex(&latex).as_bytes())
+                .iter()
+                .fold(String::new(), |mut hash, b| {
+                    let _ = write!(hash, "{b:02x}");
+                    hash
+                });
+            refs.push(format!("latex-{hash}.{extension}"));
+            pos = end + close.len();
+            break;
+        }
+    }
+    refs
+}
+
+/// Strips HTML from LaTeX as Anki does before rendering it, keeping line
+/// breaks.
+fn strip_html_for_latex(latex: &str) -> String {
+    let mut with_newlines = String::with_capacity(latex.len());
+    let lower = latex.to_ascii_lowercase();
+    let mut pos = 0;
+    while let Some(start) = lower[pos..].find('<').map(|i| pos + i) {
+        let tag = ["<br>", "<br />", "<div>"]
+            .into_iter()
+            .find(|tag| lower[start..].starts_with(tag));
+        with_newlines.push_str(&latex[pos..start]);
+        if let Some(tag) = tag {
+            with_newlines.push('\n');
+            pos = start + tag.len();
+        } else {
+            with_newlines.push('<');
+            pos = start + 1;
+        }
+    }
+    with_newlines.push_str(&latex[pos..]);
+    decode_entities(&remove_tags(&with_newlines))
+}
+
+/// Points the references to a media file at a new filename, as when a file
+/// is renamed to avoid a conflict.
+pub fn rename_media_references(text: &str, old: &str, new: &str) -> String {
+    let mut text = text.to_string();
+    for attribute in ["src", "data"] {
+        for (open, close) in [("\"", "\""), ("'", "'"), ("", " "), ("", ">"), ("", "/")] {
+            text = text.replace(
+                &format!("{attribute}={open}{old}{close}"),
+                &format!("{attribute}={open}{new}{close}"),
+            );
+        }
+    }
+    text.replace(&format!("[sound:{old}]"), &format!("[sound:{new}]"))
+}
+
+/// The checksum Anki stores in `notes.csum`: the first 4 bytes of the
+/// SHA-1 of the note's first field, with HTML stripped.
+pub fn field_checksum(text: &str) -> u32 {
+    let digest = Sha1::digest(strip_html_preserving_media_filenames(text).as_bytes());
+    u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]])
+}
+
+/// Whether a field has no visible content, ignoring whitespace and the
+/// `<br>`/`<div>` tags that editors tend to leave behind.
+pub fn field_is_empty(text: &str) -> bool {
+    let mut rest = text;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == '\u{a0}');
+        if rest.is_empty() {
+            return true;
+        }
+        if let Some(entity) = rest.strip_prefix("&nbsp;") {
+            rest = entity;
+            continue;
+        }
+        if !rest.starts_with('<') {
+            return false;
+        }
+        let Some(end) = rest.find('>') else {
+            return false;
+        };
+        let tag = rest[1..end]
+            .trim_start_matches('/')
+            .trim_end_matches('/')
+            .trim()
+            .to_ascii_lowercase();
+        if !(tag == "br" || tag == "div" || tag.starts_with("div ") || tag.starts_with("br ")) {
+            return false;
+        }
+        rest = &rest[end + 1..];
+    }
+}
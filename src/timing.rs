@@ -0,0 +1,155 @@
+//! When the collection's days start and end, which the scheduler uses for
+//! due dates and the stats screens use for grouping reviews.
+
+use crate::Database;
+use rusqlite::{OptionalExtension, Result, params};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DAY_SECS: i64 = 86_400;
+
+/// The scheduler's idea of "today".
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SchedTiming {
+    /// The number of days since the collection was created, which is the
+    /// unit that review cards' `Cards::Due` is measured in.
+    pub days_elapsed: u32,
+    /// The timestamp (in seconds) at which the next day starts.
+    pub next_day_at: i64,
+}
+
+impl SchedTiming {
+    /// Computes the timing for a collection created at `crt` (from
+    /// `Col::Crt`), with a day that starts at `rollover_hour` local time.
+    ///
+    /// Offsets are in minutes west of UTC, as Anki stores them. Without a
+    /// `creation_offset`, days are counted in 24-hour steps from `crt`,
+    /// which is how older collections behave.
+    #[must_use]
+    pub fn compute(
+        crt: i64,
+        creation_offset: Option<i32>,
+        current_offset: Option<i32>,
+        rollover_hour: i8,
+        now: i64,
+    ) -> Self {
+        let Some(creation_offset) = creation_offset else {
+            let days = (now - crt).div_euclid(DAY_SECS).max(0);
+            return Self {
+                days_elapsed: u32::try_from(days).unwrap_or_default(),
+                next_day_at: crt + (days + 1) * DAY_SECS,
+            };
+        };
+
+        let current_offset = i64::from(current_offset.unwrap_or(creation_offset)) * 60;
+        let rollover = i64::from(rollover_hour.clamp(0, 23)) * 3600;
+
+        let local_now = now - current_offset;
+        let today = local_now.div_euclid(DAY_SECS);
+        let rollover_passed = local_now.rem_euclid(DAY_SECS) >= rollover;
+        let rollover_today = today * DAY_SECS + rollover + current_offset;
+
+        let created_day = (crt - i64::from(creation_offset) * 60).div_euclid(DAY_SECS);
+        let days = today - created_day - i64::from(!rollover_passed);
+
+        Self {
+            days_elapsed: u32::try_from(days.max(0)).unwrap_or_default(),
+            next_day_at: if rollover_passed {
+                rollover_today + DAY_SECS
+            } else {
+                rollover_today
+            },
+        }
+    }
+
+    /// The timestamp (in seconds) at which today started.
+    #[must_use]
+    pub const fn day_start(&self) -> i64 {
+        self.next_day_at - DAY_SECS
+    }
+
+    /// Converts a timestamp in seconds into a day relative to today, where
+    /// `0` is today, `-1` is yesterday, and `1` is tomorrow.
+    #[must_use]
+    pub fn relative_day(&self, secs: i64) -> i32 {
+        let day = (secs - self.next_day_at).div_euclid(DAY_SECS) + 1;
+        i32::try_from(day).unwrap_or(if day < 0 { i32::MIN } else { i32::MAX })
+    }
+}
+
+pub(crate) fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| i64::try_from(d.as_secs()).unwrap_or(i64::MAX))
+}
+
+pub(crate) fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| i64::try_from(d.as_millis()).unwrap_or(i64::MAX))
+}
+
+impl Database {
+    /// Reads a value from the `config` table, which Anki stores as JSON.
+    pub(crate) fn config_json(&self, key: &str) -> Result<Option<String>> {
+        let mut stmt = self.prepare_cached_raw("SELECT val FROM config WHERE key=?")?;
+        let val: Option<Vec<u8>> = stmt.query_row(params![key], |row| row.get(0)).optional()?;
+        Ok(val.map(|v| String::from_utf8_lossy(&v).trim().to_string()))
+    }
+
+    pub(crate) fn config_i64(&self, key: &str) -> Result<Option<i64>> {
+        Ok(self.config_json(key)?.and_then(|v| v.parse().ok()))
+    }
+
+    /// Writes a value to the `config` table, as JSON.
+    pub(crate) fn set_config_json(&self, key: &str, val: &str) -> Result<()> {
+        self.prepare_cached_raw("INSERT OR REPLACE INTO config VALUES (?, -1, ?, ?)")?
+            .execute(params![key, now_secs(), val.as_bytes()])?;
+        Ok(())
+    }
+
+    /// Gets the scheduler's timing as of right now.
+    ///
+    /// ```rust,no_run
+    /// # use ankidb::Database;
+    /// let db = Database::open(&"/path/to/collection.anki2")?;
+    /// let timing = db.timing()?;
+    /// println!("{} days since the collection was created", timing.days_elapsed);
+    /// # Ok::<(), rusqlite::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the database becomes unavailable.
+    pub fn timing(&self) -> Result<SchedTiming> {
+        self.timing_at(now_secs())
+    }
+
+    /// Gets the scheduler's timing as of the given timestamp, in seconds.
+    ///
+    /// The current UTC offset comes from the collection's `localOffset`
+    /// config, which Anki keeps up to date as it's used.
+    ///
+    /// # Errors
+    ///
+    /// This can fail if the database becomes unavailable.
+    pub fn timing_at(&self, now: i64) -> Result<SchedTiming> {
+        let crt: i64 = self
+            .prepare_cached_raw("SELECT crt FROM col")?
+            .query_row([], |row| row.get(0))?;
+        let offset = |key| -> Result<Option<i32>> {
+            Ok(self.config_i64(key)?.and_then(|v| i32::try_from(v).ok()))
+        };
+        let rollover = self
+            .config_i64("rollover")?
+            .and_then(|v| i8::try_from(v).ok())
+            .unwrap_or(4);
+
+        Ok(SchedTiming::compute(
+            crt,
+            offset("creationOffset")?,
+            offset("localOffset")?,
+            rollover,
+            now,
+        ))
+    }
+}